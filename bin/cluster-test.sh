@@ -2,9 +2,9 @@
 
 echo "starting services"
 cargo build
-target/debug/rkv --address 127.0.0.1:8078 &
-target/debug/rkv --address 127.0.0.1:8079 &
-target/debug/rkv --address 127.0.0.1:8080 --seed-nodes "127.0.0.1:8078","127.0.0.1:8079" &
+target/debug/rkv --address 127.0.0.1:8078 --folder target/data/8078 &
+target/debug/rkv --address 127.0.0.1:8079 --folder target/data/8079 &
+target/debug/rkv --address 127.0.0.1:8080 --folder target/data/8080 --seed-nodes "127.0.0.1:8078","127.0.0.1:8079" &
 
 echo "waiting for startup"
 sleep 5
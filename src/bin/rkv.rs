@@ -14,19 +14,23 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let config = Config::parse_from_args();
     let addr = config.address;
-    let server = Arc::new(Server::new(config));
+    let server = Arc::new(Server::new(config)?);
+    server.start();
     let rkv_service = RkvService {
         server: server.clone(),
     };
-    let peer_service = PeerService { server: server };
+    let peer_service = PeerService {
+        server: server.clone(),
+    };
 
     info!("starting rkv server at {}", addr);
 
     tonic::transport::Server::builder()
         .add_service(RkvServiceServer::new(rkv_service))
         .add_service(PeerServiceServer::new(peer_service))
-        .serve(addr)
+        .serve_with_shutdown(addr, async move { server.stopped().await })
         .await?;
 
+    info!("stopped rkv server at {}", addr);
     Ok(())
 }
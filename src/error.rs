@@ -17,7 +17,19 @@ quick_error! {
             from()
             display("{}", err)
         }
+        Corruption(msg: String) {
+            display("corruption: {}", msg)
+        }
         TooFewReplicas {}
+        Timeout {
+            display("deadline exceeded")
+        }
+        VersionConflict {
+            display("version conflict")
+        }
+        ClusterMismatch(msg: String) {
+            display("cluster config mismatch: {}", msg)
+        }
         Other(err: Box<dyn std::error::Error + Send + Sync + 'static>) {
             from()
             source(err.as_ref())
@@ -27,3 +39,15 @@ quick_error! {
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    // The gRPC status code reported for the error
+    pub fn code(&self) -> tonic::Code {
+        match self {
+            Error::VersionConflict => tonic::Code::Aborted,
+            Error::Timeout => tonic::Code::DeadlineExceeded,
+            Error::ClusterMismatch(_) => tonic::Code::FailedPrecondition,
+            _ => tonic::Code::Internal,
+        }
+    }
+}
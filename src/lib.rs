@@ -10,8 +10,10 @@ pub mod proto {
     tonic::include_proto!("rkv");
 }
 
-#[derive(Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct Key(pub Vec<u8>);
 pub type Value = Vec<u8>;
 pub type Version = i64;
 pub type ValueVersion = (Value, Version);
+// Time in ms since the unix epoch at which a value expires
+pub type Expiry = u64;
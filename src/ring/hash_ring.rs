@@ -1,47 +1,96 @@
-use std::collections::hash_map::DefaultHasher;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Display;
 use std::hash::Hash;
-use std::hash::Hasher;
 use std::iter::Iterator;
 use std::ops::Bound;
+use xxhash_rust::xxh64::xxh64;
 
 // A consistent hash ring inspired by Karger et al.'s
 // https://www.akamai.com/us/en/multimedia/documents/technical-publication/consistent-hashing-and-random-trees-distributed-caching-protocols-for-relieving-hot-spots-on-the-world-wide-web-technical-publication.pdf
+//
+// Every node must place keys alike, so positions on the ring are specified
+// rather than left to std's hashers, which may change between Rust versions.
+// An item is at the XXH64 hash of its bytes with seed 0, and the i-th point
+// of a bucket is at the XXH64 hash of the UTF-8 bytes of its Display form
+// with seed i.
+//
+// With a load factor set, the ring also offers consistent hashing with
+// bounded loads, after Mirrokni et al.'s https://arxiv.org/abs/1608.01350.
+// The items assigned to each bucket are counted, and get_bounded skips
+// buckets holding more than the load factor times their share of the
+// assigned items. Since bounded lookups depend on the loads, they only suit
+// assignments that may move, unlike the placement of stored keys. This is
+// support at the ring level only: the server doesn't route anything through
+// bounded lookups yet, and replicas are still placed by plain successors.
+#[derive(Clone)]
 pub struct HashRing<T> {
     entries: BTreeMap<u64, T>,
     replicas: i32,
+    weights: HashMap<T, u32>,
+    load_factor: Option<f64>,
+    loads: HashMap<T, u64>,
 }
 
-// TODO: Replace DefaultHasher. Stability not guaranteed across rust versions.
-impl<T: Hash + Clone + Eq> HashRing<T> {
+// How evenly a set of items spreads over the buckets of a ring
+#[derive(Debug)]
+pub struct Balance<T: Hash + Eq> {
+    // Points of each bucket on the ring
+    pub points: HashMap<T, usize>,
+    // Items placed on each bucket
+    pub items: HashMap<T, usize>,
+    // Items of the most loaded bucket over the mean, both per unit of weight
+    pub max_mean_ratio: f64,
+}
+
+impl<T: Hash + Clone + Eq + Display> HashRing<T> {
     pub fn new(replicas: i32) -> Self {
         assert!(replicas > 0, "replicas must be > 0");
         Self {
             entries: BTreeMap::new(),
             replicas: replicas,
+            weights: HashMap::new(),
+            load_factor: None,
+            loads: HashMap::new(),
         }
     }
 
     pub fn insert(&mut self, bucket: T) {
-        for point in self.points(&bucket) {
+        self.insert_weighted(bucket, 1);
+    }
+
+    // Inserts bucket with weight times the points of a bucket of weight 1,
+    // replacing its points if it's already on the ring
+    pub fn insert_weighted(&mut self, bucket: T, weight: u32) {
+        assert!(weight > 0, "weight must be > 0");
+        self.remove(&bucket);
+        for point in self.points(&bucket, weight) {
             // Overwrites the prior entry on collision
             self.entries.insert(point, bucket.clone());
         }
+        self.weights.insert(bucket, weight);
     }
 
     pub fn remove(&mut self, bucket: &T) {
-        for point in self.points(bucket) {
+        let weight = match self.weights.remove(bucket) {
+            Some(weight) => weight,
+            None => return,
+        };
+        for point in self.points(bucket, weight) {
             // Removes the prior entry on collision
             self.entries.remove(&point);
         }
+        self.loads.remove(bucket);
     }
 
-    pub fn get<V: Hash>(&self, item: &V) -> Option<&T> {
+    pub fn get(&self, item: &[u8]) -> Option<&T> {
         self.successors(item).next()
     }
 
-    pub fn successors<V: Hash>(&self, item: &V) -> impl Iterator<Item = &T> {
-        let point = self.point(item);
+    pub fn successors(&self, item: &[u8]) -> impl Iterator<Item = &T> {
+        self.successors_of_point(self.point(item))
+    }
+
+    fn successors_of_point(&self, point: u64) -> impl Iterator<Item = &T> {
         let r1 = self
             .entries
             .range((Bound::Included(point), Bound::Unbounded));
@@ -52,25 +101,125 @@ impl<T: Hash + Clone + Eq> HashRing<T> {
         r1.chain(r2).map(|(_, v)| v)
     }
 
-    fn point<V: Hash>(&self, v: &V) -> u64 {
-        let mut h = DefaultHasher::new();
-        v.hash(&mut h);
-        h.finish()
+    // Splits the ring into the ranges between consecutive points. Each is
+    // returned as (start, end], wrapping past u64::MAX, along with the first
+    // n distinct buckets at or after end, which are responsible for it. A
+    // ring with a single point has one range with start == end covering the
+    // whole ring.
+    pub fn ranges(&self, n: usize) -> Vec<(u64, u64, Vec<T>)> {
+        let points: Vec<u64> = self.entries.keys().copied().collect();
+        let mut ranges = Vec::with_capacity(points.len());
+        for (i, end) in points.iter().enumerate() {
+            let start = points[(i + points.len() - 1) % points.len()];
+            ranges.push((start, *end, self.owners(*end, n)));
+        }
+        ranges
+    }
+
+    // The first n distinct buckets at or after point
+    pub fn owners(&self, point: u64, n: usize) -> Vec<T> {
+        self.walk(point).take(n).cloned().collect()
+    }
+
+    // The distinct buckets at or after point, in ring order
+    pub fn walk(&self, point: u64) -> impl Iterator<Item = &T> {
+        let mut seen = HashSet::new();
+        self.successors_of_point(point)
+            .filter(move |bucket| seen.insert(*bucket))
+    }
+
+    // Bounds the load of each bucket to load_factor times its share of the
+    // assigned items. Factors closer to 1 balance better but move more
+    // items off their successor.
+    pub fn set_load_factor(&mut self, load_factor: f64) {
+        assert!(load_factor > 1.0, "load factor must be > 1");
+        self.load_factor = Some(load_factor);
+    }
+
+    // The first bucket at or after item with room for another, or the first
+    // one if loads aren't bounded
+    pub fn get_bounded(&self, item: &[u8]) -> Option<&T> {
+        let total: u64 = self.loads.values().sum();
+        self.walk(self.point(item))
+            .find(|bucket| self.load(bucket) < self.capacity(bucket, total + 1))
     }
 
-    fn points(&self, bucket: &T) -> Vec<u64> {
-        let mut res = Vec::new();
-        for replica in 0..self.replicas {
-            let mut h = DefaultHasher::new();
-            bucket.hash(&mut h);
-            replica.hash(&mut h);
-            res.push(h.finish());
+    // Counts an item assigned to bucket
+    pub fn add_load(&mut self, bucket: &T) {
+        if self.weights.contains_key(bucket) {
+            *self.loads.entry(bucket.clone()).or_default() += 1;
         }
-        res
+    }
+
+    // Uncounts an item assigned to bucket
+    pub fn remove_load(&mut self, bucket: &T) {
+        if let Some(load) = self.loads.get_mut(bucket) {
+            *load = load.saturating_sub(1);
+        }
+    }
+
+    pub fn load(&self, bucket: &T) -> u64 {
+        self.loads.get(bucket).copied().unwrap_or(0)
+    }
+
+    // Items bucket may hold out of total
+    fn capacity(&self, bucket: &T, total: u64) -> u64 {
+        let load_factor = match self.load_factor {
+            Some(load_factor) => load_factor,
+            None => return u64::MAX,
+        };
+        let total_weight: u64 = self.weights.values().map(|w| u64::from(*w)).sum();
+        let share = self.weights[bucket] as f64 / total_weight as f64;
+        (load_factor * total as f64 * share).ceil() as u64
+    }
+
+    // Reports how items spread over the buckets, each placed on its
+    // successor
+    pub fn balance<'a>(&self, items: impl IntoIterator<Item = &'a [u8]>) -> Balance<T> {
+        let mut points = HashMap::new();
+        for bucket in self.entries.values() {
+            *points.entry(bucket.clone()).or_default() += 1;
+        }
+        let mut counts: HashMap<T, usize> = self
+            .weights
+            .keys()
+            .map(|bucket| (bucket.clone(), 0))
+            .collect();
+        for item in items {
+            if let Some(bucket) = self.get(item) {
+                *counts.get_mut(bucket).unwrap() += 1;
+            }
+        }
+
+        let total: usize = counts.values().sum();
+        let total_weight: u64 = self.weights.values().map(|w| u64::from(*w)).sum();
+        let max = counts
+            .iter()
+            .map(|(bucket, n)| *n as f64 / self.weights[bucket] as f64)
+            .fold(0.0, f64::max);
+        let mean = total as f64 / total_weight as f64;
+        Balance {
+            points,
+            items: counts,
+            max_mean_ratio: if total == 0 { 0.0 } else { max / mean },
+        }
+    }
+
+    // The position of an item on the ring
+    pub fn point(&self, item: &[u8]) -> u64 {
+        xxh64(item, 0)
+    }
+
+    // The first points of a bucket are the same whatever its weight, so
+    // changing it only moves the keys of the points added or removed
+    fn points(&self, bucket: &T, weight: u32) -> Vec<u64> {
+        let name = bucket.to_string();
+        (0..self.replicas as u64 * u64::from(weight))
+            .map(|replica| xxh64(name.as_bytes(), replica))
+            .collect()
     }
 }
 
-// TODO: Test balance guarantees
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -81,19 +230,19 @@ mod tests {
         let mut r: HashRing<i32> = HashRing::new(replicas);
 
         // empty
-        assert_eq!(r.get(&0), None);
+        assert_eq!(r.get(b"0"), None);
 
         // single bucket
         r.insert(0);
-        assert_eq!(r.get(&42), Some(&0));
+        assert_eq!(r.get(b"42"), Some(&0));
 
-        let successors: Vec<_> = r.successors(&99).map(|v| *v).collect();
+        let successors: Vec<_> = r.successors(b"99").map(|v| *v).collect();
         let expected: Vec<_> = std::iter::repeat(0).take(replicas as usize).collect();
         assert_eq!(successors, expected);
 
         // empty
         r.remove(&0);
-        assert_eq!(r.get(&0), None);
+        assert_eq!(r.get(b"0"), None);
 
         // N buckets
         let N = 4;
@@ -101,8 +250,115 @@ mod tests {
         for bucket in &buckets {
             r.insert(*bucket);
         }
-        assert_eq!(buckets.contains(r.get(&"foo").unwrap()), true);
-        let successors: Vec<_> = r.successors(&"bar").cloned().collect();
+        assert_eq!(buckets.contains(r.get(b"foo").unwrap()), true);
+        let successors: Vec<_> = r.successors(b"bar").cloned().collect();
         assert_eq!(successors.len(), buckets.len() * replicas as usize);
+
+        // Ranges tile the ring and are owned by the successors of their end
+        let ranges = r.ranges(3);
+        assert_eq!(ranges.len(), buckets.len() * replicas as usize);
+        for (i, (start, end, owners)) in ranges.iter().enumerate() {
+            assert_eq!(*start, ranges[(i + ranges.len() - 1) % ranges.len()].1);
+            assert_eq!(owners.len(), 3);
+            assert_eq!(Some(&owners[0]), r.successors_of_point(*end).next());
+            // Including those of the points within them
+            let mid = end.wrapping_sub(end.wrapping_sub(*start) / 2);
+            assert_eq!(*owners, r.owners(mid, 3));
+        }
+    }
+
+    #[test]
+    fn test_stable_points() {
+        // Reference values of XXH64, which placement must never drift from
+        let r: HashRing<i32> = HashRing::new(2);
+        assert_eq!(r.point(b""), 0xef46db3751d8e999);
+        assert_eq!(r.point(b"a"), 0xd24ec4f1a98c6e5b);
+        assert_eq!(r.points(&7, 1), vec![xxh64(b"7", 0), xxh64(b"7", 1)]);
+    }
+
+    fn items(n: u32) -> Vec<Vec<u8>> {
+        (0..n).map(|i| format!("k{}", i).into_bytes()).collect()
+    }
+
+    #[test]
+    fn test_balance() {
+        let items = items(100_000);
+        let ratio = |replicas, buckets: &[(i32, u32)]| {
+            let mut r = HashRing::new(replicas);
+            for (bucket, weight) in buckets {
+                r.insert_weighted(*bucket, *weight);
+            }
+            let balance = r.balance(items.iter().map(|i| i.as_slice()));
+            for (bucket, weight) in buckets {
+                assert_eq!(balance.points[bucket], (replicas as u32 * weight) as usize);
+            }
+            assert_eq!(balance.items.values().sum::<usize>(), items.len());
+            balance.max_mean_ratio
+        };
+        let even: Vec<_> = (0..10).map(|bucket| (bucket, 1)).collect();
+
+        // More points per bucket spread items more evenly. Placement is
+        // deterministic, so the bounds hold for good.
+        assert!(ratio(8, &even) > 1.3);
+        assert!(ratio(256, &even) < 1.15);
+
+        // Weighted buckets take items in proportion to their weight
+        let weighted: Vec<_> = (0..10)
+            .map(|bucket| (bucket, 1 + bucket as u32 % 3))
+            .collect();
+        assert!(ratio(256, &weighted) < 1.15);
+    }
+
+    #[test]
+    fn test_bounded_loads() {
+        let mut r: HashRing<i32> = HashRing::new(8);
+        for bucket in 0..10 {
+            r.insert(bucket);
+        }
+        r.set_load_factor(1.25);
+        let items = items(10_000);
+        for item in &items {
+            let bucket = *r.get_bounded(item).unwrap();
+            r.add_load(&bucket);
+        }
+
+        // No bucket exceeds its bound, unlike with plain successors
+        let max = (0..10).map(|bucket| r.load(&bucket)).max().unwrap();
+        assert!(max <= (1.25 * items.len() as f64 / 10.0).ceil() as u64);
+        assert!(r.balance(items.iter().map(|i| i.as_slice())).max_mean_ratio > 1.25);
+        assert_eq!((0..10).map(|bucket| r.load(&bucket)).sum::<u64>(), 10_000);
+
+        // Unloaded items go back to their successor once there's room
+        let first = *r.get(&items[0]).unwrap();
+        while r.load(&first) > 0 {
+            r.remove_load(&first);
+        }
+        assert_eq!(r.get_bounded(&items[0]), Some(&first));
+        r.remove(&first);
+        assert_eq!(r.load(&first), 0);
+    }
+
+    #[test]
+    fn test_weighted_buckets() {
+        let replicas = 8;
+        let mut r: HashRing<i32> = HashRing::new(replicas);
+        r.insert(0);
+        r.insert_weighted(1, 3);
+        let points =
+            |r: &HashRing<i32>, bucket| r.entries.values().filter(|b| **b == bucket).count();
+        assert_eq!(points(&r, 0), replicas as usize);
+        assert_eq!(points(&r, 1), 3 * replicas as usize);
+
+        // Reweighting keeps the points shared by both weights
+        let before: Vec<_> = r.entries.keys().copied().collect();
+        r.insert_weighted(1, 2);
+        assert_eq!(points(&r, 1), 2 * replicas as usize);
+        assert!(r.entries.keys().all(|point| before.contains(point)));
+
+        r.remove(&1);
+        assert_eq!(r.entries.len(), replicas as usize);
+        // Removing an absent bucket leaves the others' points alone
+        r.remove(&1);
+        assert_eq!(r.entries.len(), replicas as usize);
     }
 }
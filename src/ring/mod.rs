@@ -1,2 +1,2 @@
 mod hash_ring;
-pub use hash_ring::HashRing;
+pub use hash_ring::{Balance, HashRing};
@@ -0,0 +1,479 @@
+use super::membership::MAX_WEIGHT;
+use super::server::Config;
+use crate::error::Error;
+use crate::proto::{ClusterConfig, ConflictResolution, ReplicaPlacement, RingHash};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// Settings loaded from a file, TOML unless its name ends in .json. Every
+// field is optional and those left out keep their value from the command
+// line or its default. The cluster config goes in a [cluster] table.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    folder: Option<PathBuf>,
+    engine: Option<String>,
+    wal_sync: Option<String>,
+    address: Option<SocketAddr>,
+    seed_nodes: Option<Vec<SocketAddr>>,
+    zone: Option<String>,
+    weight: Option<u32>,
+    cluster: Option<ClusterConfigFile>,
+    gossip_interval_ms: Option<u64>,
+    heartbeat_interval_ms: Option<u64>,
+    phi_threshold: Option<f64>,
+    hint_ttl_secs: Option<u64>,
+    max_hint_bytes: Option<u64>,
+    anti_entropy_interval_ms: Option<u64>,
+    tombstone_grace_secs: Option<u64>,
+    request_timeout_ms: Option<u64>,
+    max_clock_skew_ms: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterConfigFile {
+    name: Option<String>,
+    replication_factor: Option<i32>,
+    read_replicas: Option<i32>,
+    write_replicas: Option<i32>,
+    ring_replicas: Option<i32>,
+    read_repair_chance: Option<f32>,
+    // "siblings" or "last_write_wins"
+    conflict_resolution: Option<String>,
+    epoch: Option<u64>,
+    // "zone_aware" or "ring_order"
+    replica_placement: Option<String>,
+    // "xxhash64"
+    ring_hash: Option<String>,
+}
+
+// How the local cluster config compares to one received from a peer
+#[derive(Debug, PartialEq)]
+pub enum Agreement {
+    // The configs match, or the peer's is older and it will adopt ours
+    Agree,
+    // The peer's config has a newer epoch and replaces ours
+    Adopt,
+    // The peer belongs to another cluster or its config can't be reconciled
+    // with ours
+    Mismatch(String),
+}
+
+impl ConfigFile {
+    // Sets the fields of config given in the file, except those for which
+    // explicit holds because they were given on the command line
+    pub fn apply(self, config: &mut Config, explicit: impl Fn(&str) -> bool) -> Result<(), String> {
+        macro_rules! set {
+            ($field:ident) => {
+                set!($field, |value| Ok::<_, String>(value))
+            };
+            ($field:ident, $parse:expr) => {
+                if let Some(value) = self.$field {
+                    if !explicit(stringify!($field)) {
+                        config.$field = $parse(value)?;
+                    }
+                }
+            };
+        }
+        set!(folder);
+        set!(engine, parse_setting);
+        set!(wal_sync, parse_setting);
+        set!(address);
+        set!(seed_nodes);
+        set!(zone);
+        set!(weight, |weight| validate_weight(weight).map(|_| weight));
+        set!(gossip_interval_ms);
+        set!(heartbeat_interval_ms);
+        set!(phi_threshold);
+        set!(hint_ttl_secs);
+        set!(max_hint_bytes);
+        set!(anti_entropy_interval_ms);
+        set!(tombstone_grace_secs);
+        set!(request_timeout_ms);
+        set!(max_clock_skew_ms);
+        if let Some(cluster) = self.cluster {
+            if !explicit("cluster_config") {
+                cluster.apply(&mut config.cluster_config)?;
+                validate_cluster_config(&config.cluster_config)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ClusterConfigFile {
+    pub fn apply(self, config: &mut ClusterConfig) -> Result<(), String> {
+        if let Some(name) = self.name {
+            config.name = name;
+        }
+        if let Some(replication_factor) = self.replication_factor {
+            config.replication_factor = replication_factor;
+        }
+        if let Some(read_replicas) = self.read_replicas {
+            config.read_replicas = read_replicas;
+        }
+        if let Some(write_replicas) = self.write_replicas {
+            config.write_replicas = write_replicas;
+        }
+        if let Some(ring_replicas) = self.ring_replicas {
+            config.ring_replicas = ring_replicas;
+        }
+        if let Some(read_repair_chance) = self.read_repair_chance {
+            config.read_repair_chance = read_repair_chance;
+        }
+        if let Some(conflict_resolution) = self.conflict_resolution {
+            let conflict_resolution = match conflict_resolution.as_str() {
+                "siblings" => ConflictResolution::Siblings,
+                "last_write_wins" => ConflictResolution::LastWriteWins,
+                _ => {
+                    return Err(format!(
+                        "unknown conflict_resolution {:?}, expected \"siblings\" or \"last_write_wins\"",
+                        conflict_resolution
+                    ))
+                }
+            };
+            config.conflict_resolution = conflict_resolution as i32;
+        }
+        if let Some(epoch) = self.epoch {
+            config.epoch = epoch;
+        }
+        if let Some(replica_placement) = self.replica_placement {
+            let replica_placement = match replica_placement.as_str() {
+                "zone_aware" => ReplicaPlacement::ZoneAware,
+                "ring_order" => ReplicaPlacement::RingOrder,
+                _ => {
+                    return Err(format!(
+                        "unknown replica_placement {:?}, expected \"zone_aware\" or \"ring_order\"",
+                        replica_placement
+                    ))
+                }
+            };
+            config.replica_placement = replica_placement as i32;
+        }
+        if let Some(ring_hash) = self.ring_hash {
+            let ring_hash = match ring_hash.as_str() {
+                "xxhash64" => RingHash::Xxhash64,
+                _ => {
+                    return Err(format!(
+                        "unknown ring_hash {:?}, expected \"xxhash64\"",
+                        ring_hash
+                    ))
+                }
+            };
+            config.ring_hash = ring_hash as i32;
+        }
+        Ok(())
+    }
+}
+
+// Reads a cluster config file over the defaults
+pub fn load_cluster_config(path: &Path, defaults: ClusterConfig) -> Result<ClusterConfig, String> {
+    let mut config = defaults;
+    load::<ClusterConfigFile>(path)?.apply(&mut config)?;
+    validate_cluster_config(&config)?;
+    Ok(config)
+}
+
+// Checks that a cluster config describes a cluster that can serve requests
+pub fn validate_cluster_config(config: &ClusterConfig) -> Result<(), String> {
+    let n = config.replication_factor;
+    if config.name.is_empty() {
+        return Err("name must not be empty".to_string());
+    }
+    if n < 1 {
+        return Err(format!("replication_factor (N={}) must be at least 1", n));
+    }
+    if config.read_replicas < 1 || config.read_replicas > n {
+        return Err(format!(
+            "read_replicas (R={}) must be between 1 and replication_factor (N={})",
+            config.read_replicas, n
+        ));
+    }
+    if config.write_replicas < 1 || config.write_replicas > n {
+        return Err(format!(
+            "write_replicas (W={}) must be between 1 and replication_factor (N={})",
+            config.write_replicas, n
+        ));
+    }
+    if config.ring_replicas < 1 {
+        return Err(format!(
+            "ring_replicas ({}) must be at least 1",
+            config.ring_replicas
+        ));
+    }
+    if !(0.0..=1.0).contains(&config.read_repair_chance) {
+        return Err(format!(
+            "read_repair_chance ({}) must be between 0 and 1",
+            config.read_repair_chance
+        ));
+    }
+    if ConflictResolution::from_i32(config.conflict_resolution).is_none() {
+        return Err(format!(
+            "unknown conflict_resolution {}",
+            config.conflict_resolution
+        ));
+    }
+    if ReplicaPlacement::from_i32(config.replica_placement).is_none() {
+        return Err(format!(
+            "unknown replica_placement {}",
+            config.replica_placement
+        ));
+    }
+    // The ring only hashes with XXH64 so far
+    if RingHash::from_i32(config.ring_hash) != Some(RingHash::Xxhash64) {
+        return Err(format!("unsupported ring_hash {}", config.ring_hash));
+    }
+    Ok(())
+}
+
+pub fn validate_weight(weight: u32) -> Result<(), String> {
+    if !(1..=MAX_WEIGHT).contains(&weight) {
+        return Err(format!(
+            "weight ({}) must be between 1 and {}",
+            weight, MAX_WEIGHT
+        ));
+    }
+    Ok(())
+}
+
+fn parse_setting<T: FromStr<Err = Error>>(value: String) -> Result<T, String> {
+    value.parse().map_err(|e: Error| e.to_string())
+}
+
+pub fn compare_cluster_config(local: &ClusterConfig, remote: &ClusterConfig) -> Agreement {
+    if remote.name != local.name {
+        return Agreement::Mismatch(format!(
+            "peer belongs to cluster {:?}, not {:?}",
+            remote.name, local.name
+        ));
+    }
+    if remote.epoch > local.epoch {
+        return match validate_cluster_config(remote) {
+            Ok(()) => Agreement::Adopt,
+            Err(e) => {
+                Agreement::Mismatch(format!("invalid config at epoch {}: {}", remote.epoch, e))
+            }
+        };
+    }
+    if remote.epoch == local.epoch && remote != local {
+        return Agreement::Mismatch(format!(
+            "peer has a different config at epoch {}",
+            local.epoch
+        ));
+    }
+    Agreement::Agree
+}
+
+pub fn load<T: DeserializeOwned>(path: &Path) -> Result<T, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let json = path.extension().is_some_and(|ext| ext == "json");
+    parse(&contents, json).map_err(|e| format!("invalid config {}: {}", path.display(), e))
+}
+
+fn parse<T: DeserializeOwned>(contents: &str, json: bool) -> Result<T, String> {
+    if json {
+        serde_json::from_str(contents).map_err(|e| e.to_string())
+    } else {
+        toml::from_str(contents).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::server::default_cluster_config as defaults;
+    use super::*;
+    use structopt::StructOpt;
+
+    fn cluster(contents: &str, json: bool) -> Result<ClusterConfig, String> {
+        let mut config = defaults();
+        parse::<ClusterConfigFile>(contents, json)?.apply(&mut config)?;
+        validate_cluster_config(&config)?;
+        Ok(config)
+    }
+
+    #[test]
+    fn test_cluster_config() {
+        let toml = r#"
+            name = "prod"
+            replication_factor = 5
+            read_replicas = 3
+            conflict_resolution = "last_write_wins"
+            replica_placement = "ring_order"
+            ring_hash = "xxhash64"
+        "#;
+        let json = r#"{
+            "name": "prod",
+            "replication_factor": 5,
+            "read_replicas": 3,
+            "conflict_resolution": "last_write_wins",
+            "replica_placement": "ring_order"
+        }"#;
+        let expected = ClusterConfig {
+            name: "prod".to_string(),
+            replication_factor: 5,
+            read_replicas: 3,
+            conflict_resolution: ConflictResolution::LastWriteWins as i32,
+            replica_placement: ReplicaPlacement::RingOrder as i32,
+            ..defaults()
+        };
+        assert_eq!(cluster(toml, false).unwrap(), expected);
+        assert_eq!(cluster(json, true).unwrap(), expected);
+        assert_eq!(cluster("", false).unwrap(), defaults());
+    }
+
+    #[test]
+    fn test_invalid_cluster_config() {
+        let err = |contents| cluster(contents, false).unwrap_err();
+        assert_eq!(
+            err("read_replicas = 4"),
+            "read_replicas (R=4) must be between 1 and replication_factor (N=3)"
+        );
+        assert_eq!(
+            err("replication_factor = 1"),
+            "read_replicas (R=2) must be between 1 and replication_factor (N=1)"
+        );
+        assert_eq!(
+            err("write_replicas = 0"),
+            "write_replicas (W=0) must be between 1 and replication_factor (N=3)"
+        );
+        assert_eq!(
+            err("ring_replicas = 0"),
+            "ring_replicas (0) must be at least 1"
+        );
+        assert_eq!(
+            err("read_repair_chance = 1.5"),
+            "read_repair_chance (1.5) must be between 0 and 1"
+        );
+        assert!(err("conflict_resolution = \"newest\"").contains("unknown conflict_resolution"));
+        assert!(err("replica_placement = \"random\"").contains("unknown replica_placement"));
+        assert!(err("ring_hash = \"sha1\"").contains("unknown ring_hash"));
+        assert!(err("replicas = 3").contains("unknown field `replicas`"));
+        assert!(err("read_replicas = \"two\"").contains("invalid type"));
+    }
+
+    #[test]
+    fn test_compare_cluster_config() {
+        let local = defaults();
+        assert_eq!(compare_cluster_config(&local, &local), Agreement::Agree);
+
+        let other = ClusterConfig {
+            name: "other".to_string(),
+            epoch: 1,
+            ..defaults()
+        };
+        assert!(matches!(
+            compare_cluster_config(&local, &other),
+            Agreement::Mismatch(_)
+        ));
+
+        // Changes are only accepted with a newer epoch
+        let changed = ClusterConfig {
+            read_replicas: 3,
+            ..defaults()
+        };
+        assert!(matches!(
+            compare_cluster_config(&local, &changed),
+            Agreement::Mismatch(_)
+        ));
+        let changed = ClusterConfig {
+            epoch: 1,
+            ..changed
+        };
+        assert_eq!(compare_cluster_config(&local, &changed), Agreement::Adopt);
+        assert_eq!(compare_cluster_config(&changed, &local), Agreement::Agree);
+
+        let invalid = ClusterConfig {
+            read_replicas: 4,
+            ..changed
+        };
+        assert!(matches!(
+            compare_cluster_config(&local, &invalid),
+            Agreement::Mismatch(_)
+        ));
+
+        // Nodes placing keys with another hash are refused at any epoch
+        let std_hasher = ClusterConfig {
+            ring_hash: RingHash::StdDefaultHasher as i32,
+            ..defaults()
+        };
+        for remote in &[
+            std_hasher.clone(),
+            ClusterConfig {
+                epoch: 1,
+                ..std_hasher
+            },
+        ] {
+            assert!(matches!(
+                compare_cluster_config(&local, remote),
+                Agreement::Mismatch(_)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_config_file() {
+        let mut config = Config::from_iter(&["rkv", "--address", "127.0.0.1:9000"]);
+        let file: ConfigFile = parse(
+            r#"
+                address = "127.0.0.1:8000"
+                engine = "lsm"
+                seed_nodes = ["127.0.0.1:8001"]
+                zone = "rack-1"
+                weight = 4
+                request_timeout_ms = 100
+
+                [cluster]
+                ring_replicas = 32
+            "#,
+            false,
+        )
+        .unwrap();
+        file.apply(&mut config, |name| name == "address").unwrap();
+        // Flags given on the command line take precedence
+        assert_eq!(config.address, "127.0.0.1:9000".parse().unwrap());
+        assert!(matches!(config.engine, crate::store::Engine::Lsm));
+        assert_eq!(config.seed_nodes, vec!["127.0.0.1:8001".parse().unwrap()]);
+        assert_eq!(config.zone, "rack-1");
+        assert_eq!(config.weight, 4);
+        assert_eq!(config.request_timeout_ms, 100);
+        assert_eq!(config.cluster_config.ring_replicas, 32);
+
+        let file: ConfigFile = parse(&format!("weight = {}", MAX_WEIGHT + 1), false).unwrap();
+        assert!(file
+            .apply(&mut config, |_| false)
+            .unwrap_err()
+            .contains("weight (1001) must be between 1 and 1000"));
+        let file: ConfigFile = parse(&format!("weight = {}", MAX_WEIGHT), false).unwrap();
+        file.apply(&mut config, |_| false).unwrap();
+        assert_eq!(config.weight, MAX_WEIGHT);
+
+        let file: ConfigFile = parse("engine = \"btree\"", false).unwrap();
+        assert!(file
+            .apply(&mut config, |_| false)
+            .unwrap_err()
+            .contains("unknown engine: btree"));
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cluster.json");
+        std::fs::write(&path, r#"{"write_replicas": 3}"#).unwrap();
+        assert_eq!(
+            load_cluster_config(&path, defaults())
+                .unwrap()
+                .write_replicas,
+            3
+        );
+
+        let missing = dir.path().join("missing.toml");
+        assert!(load_cluster_config(&missing, defaults())
+            .unwrap_err()
+            .starts_with("failed to read"));
+    }
+}
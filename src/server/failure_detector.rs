@@ -0,0 +1,165 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// A phi accrual failure detector, from Hayashibara et al.
+// https://citeseerx.ist.psu.edu/viewdoc/download?doi=10.1.1.80.7427&rep=rep1&type=pdf
+//
+// Rather than a binary up/down verdict, the detector outputs a suspicion
+// level phi for each node. Heartbeat inter-arrival times are assumed to be
+// normally distributed, and phi = -log10(P(next heartbeat arrives later than
+// now)). A phi of 1 means a 10% chance the node is still alive and merely
+// slow, 2 means 1%, 3 means 0.1% and so on.
+pub struct FailureDetector {
+    // Heartbeat interval nodes are expected to follow. Seeds the arrival
+    // window of new nodes so they can be suspected before ever responding.
+    expected_interval: Duration,
+    windows: HashMap<SocketAddr, ArrivalWindow>,
+}
+
+// Most recent inter-arrival samples kept per node
+const MAX_SAMPLES: usize = 1000;
+// Floor for the standard deviation. Keeps perfectly regular heartbeats from
+// making the detector hair-triggered.
+const MIN_STD_DEV_MS: f64 = 100.0;
+
+struct ArrivalWindow {
+    last_arrival: Instant,
+    intervals: VecDeque<f64>,
+    sum: f64,
+    sum_squares: f64,
+}
+
+impl FailureDetector {
+    pub fn new(expected_interval: Duration) -> Self {
+        Self {
+            expected_interval,
+            windows: HashMap::new(),
+        }
+    }
+
+    // Starts tracking a node as if it had just heartbeated
+    pub fn watch(&mut self, addr: SocketAddr, now: Instant) {
+        let expected_interval = self.expected_interval;
+        self.windows
+            .entry(addr)
+            .or_insert_with(|| ArrivalWindow::new(now, expected_interval));
+    }
+
+    pub fn unwatch(&mut self, addr: &SocketAddr) {
+        self.windows.remove(addr);
+    }
+
+    pub fn watched(&self) -> Vec<SocketAddr> {
+        self.windows.keys().copied().collect()
+    }
+
+    // Records a heartbeat from a node
+    pub fn report(&mut self, addr: SocketAddr, now: Instant) {
+        match self.windows.get_mut(&addr) {
+            Some(window) => window.add(now),
+            None => self.watch(addr, now),
+        }
+    }
+
+    // Returns the suspicion level of a node. Nodes that aren't watched are
+    // never suspected.
+    pub fn phi(&self, addr: &SocketAddr, now: Instant) -> f64 {
+        self.windows.get(addr).map_or(0.0, |window| window.phi(now))
+    }
+}
+
+impl ArrivalWindow {
+    fn new(now: Instant, expected_interval: Duration) -> Self {
+        let mut window = Self {
+            last_arrival: now,
+            intervals: VecDeque::new(),
+            sum: 0.0,
+            sum_squares: 0.0,
+        };
+        window.push(expected_interval.as_secs_f64() * 1000.0);
+        window
+    }
+
+    fn add(&mut self, now: Instant) {
+        let interval = now.duration_since(self.last_arrival).as_secs_f64() * 1000.0;
+        self.last_arrival = now;
+        self.push(interval);
+    }
+
+    fn push(&mut self, interval: f64) {
+        if self.intervals.len() == MAX_SAMPLES {
+            let oldest = self.intervals.pop_front().unwrap();
+            self.sum -= oldest;
+            self.sum_squares -= oldest * oldest;
+        }
+        self.intervals.push_back(interval);
+        self.sum += interval;
+        self.sum_squares += interval * interval;
+    }
+
+    fn phi(&self, now: Instant) -> f64 {
+        let n = self.intervals.len() as f64;
+        let mean = self.sum / n;
+        let variance = (self.sum_squares / n - mean * mean).max(0.0);
+        let std_dev = variance.sqrt().max(MIN_STD_DEV_MS);
+        let elapsed = now.duration_since(self.last_arrival).as_secs_f64() * 1000.0;
+
+        // Logistic approximation of the normal CDF, as used by Akka
+        let y = (elapsed - mean) / std_dev;
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+        if elapsed > mean {
+            -(e / (1.0 + e)).log10()
+        } else {
+            -(1.0 - 1.0 / (1.0 + e)).log10()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failure_detector() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+        let interval = Duration::from_millis(500);
+        let mut fd = FailureDetector::new(interval);
+        let start = Instant::now();
+
+        assert_eq!(fd.phi(&addr, start), 0.0);
+
+        let mut now = start;
+        fd.watch(addr, now);
+        for _ in 0..10 {
+            now += interval;
+            fd.report(addr, now);
+        }
+        assert!(fd.phi(&addr, now) < 1.0);
+        assert!(fd.phi(&addr, now + interval) < 1.0);
+
+        // Suspicion grows the longer a node stays silent
+        let phis: Vec<_> = (1..10).map(|i| fd.phi(&addr, now + interval * i)).collect();
+        assert!(phis.windows(2).all(|w| w[0] <= w[1]));
+        assert!(fd.phi(&addr, now + interval * 4) > 8.0);
+
+        // A heartbeat clears suspicion
+        now += interval * 4;
+        fd.report(addr, now);
+        assert!(fd.phi(&addr, now) < 1.0);
+
+        fd.unwatch(&addr);
+        assert_eq!(fd.phi(&addr, now + interval * 10), 0.0);
+    }
+
+    #[test]
+    fn test_failure_detector_never_heard_from() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+        let interval = Duration::from_millis(500);
+        let mut fd = FailureDetector::new(interval);
+        let start = Instant::now();
+        fd.watch(addr, start);
+        assert!(fd.phi(&addr, start) < 1.0);
+        assert!(fd.phi(&addr, start + interval * 10) > 8.0);
+    }
+}
@@ -0,0 +1,292 @@
+use crate::error::Result;
+use crate::proto;
+use log::warn;
+use prost::Message;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Durable storage for hinted handoff.
+//
+// A coordinator that can't reach a replica keeps the mutation as a hint and
+// replays it once the replica is back. Hints for each target are appended to
+// their own file, so delivery to a target takes its whole file at once.
+// Hints older than ttl are dropped unreplayed, and new hints are refused once
+// all files together reach max_size.
+//
+// Record layout:
+//   len: u32 | crc: u32 | proto::Hint
+pub struct HintStore {
+    dir: PathBuf,
+    ttl: Duration,
+    max_size: u64,
+    sizes: Mutex<HashMap<SocketAddr, u64>>,
+}
+
+const HINT_FILE_EXT: &str = "hints";
+const HEADER_LEN: usize = 8;
+
+impl HintStore {
+    pub fn open<P: AsRef<Path>>(dir: P, ttl: Duration, max_size: u64) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut sizes = HashMap::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(HINT_FILE_EXT) {
+                continue;
+            }
+            match target(&path) {
+                Some(target) => {
+                    sizes.insert(target, fs::metadata(&path)?.len());
+                }
+                None => warn!("ignoring unknown hint file {}", path.display()),
+            }
+        }
+        Ok(Self {
+            dir,
+            ttl,
+            max_size,
+            sizes: Mutex::new(sizes),
+        })
+    }
+
+    // Stores a hint, returning false if it was refused for lack of space
+    pub fn add(&self, target: SocketAddr, mutation: proto::hint::Mutation) -> Result<bool> {
+        let hint = proto::Hint {
+            target: target.to_string(),
+            created_ms: now_ms(),
+            mutation: Some(mutation),
+        };
+        self.append(target, &hint)
+    }
+
+    // Targets with pending hints
+    pub fn targets(&self) -> Vec<SocketAddr> {
+        self.sizes.lock().unwrap().keys().copied().collect()
+    }
+
+    // Removes and returns every unexpired hint for target, oldest first
+    pub fn take(&self, target: &SocketAddr) -> Result<Vec<proto::Hint>> {
+        let mut sizes = self.sizes.lock().unwrap();
+        if sizes.remove(target).is_none() {
+            return Ok(Vec::new());
+        }
+        let path = self.path(target);
+        let mut buf = Vec::new();
+        File::open(&path)?.read_to_end(&mut buf)?;
+        fs::remove_file(&path)?;
+
+        let expiry = now_ms().saturating_sub(self.ttl.as_millis() as u64);
+        let mut hints = Vec::new();
+        let mut r = &buf[..];
+        while let Some(hint) = decode(&mut r) {
+            if hint.created_ms >= expiry {
+                hints.push(hint);
+            }
+        }
+        if !r.is_empty() {
+            warn!("dropping invalid hints at the end of {}", path.display());
+        }
+        Ok(hints)
+    }
+
+    // Puts back hints that couldn't be delivered ahead of any added since
+    // they were taken, so they're still replayed in order. Ignores the size
+    // limit.
+    pub fn restore(&self, target: SocketAddr, hints: &[proto::Hint]) -> Result<()> {
+        if hints.is_empty() {
+            return Ok(());
+        }
+        let mut buf = Vec::new();
+        for hint in hints {
+            encode(hint, &mut buf);
+        }
+        let mut sizes = self.sizes.lock().unwrap();
+        let path = self.path(&target);
+        match File::open(&path) {
+            Ok(mut file) => {
+                file.read_to_end(&mut buf)?;
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&buf)?;
+        file.sync_data()?;
+        fs::rename(&tmp_path, &path)?;
+        sizes.insert(target, buf.len() as u64);
+        Ok(())
+    }
+
+    // Drops every hint for target
+    pub fn remove(&self, target: &SocketAddr) -> Result<()> {
+        let mut sizes = self.sizes.lock().unwrap();
+        if sizes.remove(target).is_some() {
+            fs::remove_file(self.path(target))?;
+        }
+        Ok(())
+    }
+
+    fn append(&self, target: SocketAddr, hint: &proto::Hint) -> Result<bool> {
+        let mut buf = Vec::new();
+        encode(hint, &mut buf);
+        let mut sizes = self.sizes.lock().unwrap();
+        if sizes.values().sum::<u64>() >= self.max_size {
+            return Ok(false);
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(&target))?;
+        file.write_all(&buf)?;
+        file.sync_data()?;
+        *sizes.entry(target).or_insert(0) += buf.len() as u64;
+        Ok(true)
+    }
+
+    // ':' isn't allowed in file names on every platform
+    fn path(&self, target: &SocketAddr) -> PathBuf {
+        let name = target.to_string().replace(':', "_");
+        self.dir.join(format!("{}.{}", name, HINT_FILE_EXT))
+    }
+}
+
+fn target(path: &Path) -> Option<SocketAddr> {
+    let name = path.file_stem()?.to_str()?;
+    name.replace('_', ":").parse().ok()
+}
+
+fn encode(hint: &proto::Hint, buf: &mut Vec<u8>) {
+    let mut body = Vec::with_capacity(hint.encoded_len());
+    hint.encode(&mut body).expect("vec has capacity");
+    buf.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+    buf.extend_from_slice(&body);
+}
+
+// Reads the next hint, or None if the remaining bytes don't form a complete,
+// valid record
+fn decode(r: &mut &[u8]) -> Option<proto::Hint> {
+    let mut header = [0; HEADER_LEN];
+    if let Err(e) = r.read_exact(&mut header) {
+        debug_assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+        return None;
+    }
+    let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    if r.len() < len || crc32fast::hash(&r[..len]) != crc {
+        return None;
+    }
+    let hint = proto::Hint::decode(&r[..len]).ok()?;
+    *r = &r[len..];
+    Some(hint)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time before unix epoch")
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proto::hint::Mutation;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn put(key: &str) -> Mutation {
+        Mutation::Put(proto::PutRequest {
+            key: key.as_bytes().to_vec(),
+            value: b"v".to_vec(),
+            ..Default::default()
+        })
+    }
+
+    fn keys(hints: &[proto::Hint]) -> Vec<Vec<u8>> {
+        hints
+            .iter()
+            .map(|hint| match hint.mutation.as_ref().unwrap() {
+                Mutation::Put(req) => req.key.clone(),
+                Mutation::Delete(req) => req.key.clone(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_hint_store() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let hints = HintStore::open(dir.path(), Duration::from_secs(60), 1 << 20).unwrap();
+            assert!(hints.add(addr(1), put("k0")).unwrap());
+            assert!(hints.add(addr(1), put("k1")).unwrap());
+            let delete = Mutation::Delete(proto::DeleteRequest {
+                key: b"k2".to_vec(),
+                version: 1,
+            });
+            assert!(hints.add(addr(2), delete).unwrap());
+        }
+
+        let hints = HintStore::open(dir.path(), Duration::from_secs(60), 1 << 20).unwrap();
+        let mut targets = hints.targets();
+        targets.sort();
+        assert_eq!(targets, vec![addr(1), addr(2)]);
+
+        let taken = hints.take(&addr(1)).unwrap();
+        assert_eq!(keys(&taken), vec![b"k0".to_vec(), b"k1".to_vec()]);
+        assert_eq!(hints.take(&addr(1)).unwrap(), Vec::new());
+        assert_eq!(hints.targets(), vec![addr(2)]);
+
+        // Restored hints go ahead of ones added in the meantime
+        assert!(hints.add(addr(1), put("k3")).unwrap());
+        hints.restore(addr(1), &taken[1..]).unwrap();
+        assert_eq!(
+            keys(&hints.take(&addr(1)).unwrap()),
+            vec![b"k1".to_vec(), b"k3".to_vec()]
+        );
+
+        hints.remove(&addr(2)).unwrap();
+        assert!(hints.targets().is_empty());
+    }
+
+    #[test]
+    fn test_hint_store_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let hints = HintStore::open(dir.path(), Duration::from_secs(60), 1).unwrap();
+        assert!(hints.add(addr(1), put("k0")).unwrap());
+        assert!(!hints.add(addr(1), put("k1")).unwrap());
+        assert_eq!(hints.take(&addr(1)).unwrap().len(), 1);
+
+        let hints = HintStore::open(dir.path(), Duration::from_secs(0), 1 << 20).unwrap();
+        let mut expired = proto::Hint {
+            target: addr(1).to_string(),
+            created_ms: now_ms() - 1000,
+            mutation: Some(put("k0")),
+        };
+        hints.restore(addr(1), &[expired.clone()]).unwrap();
+        assert!(hints.take(&addr(1)).unwrap().is_empty());
+
+        // A torn record at the end of a file is dropped
+        expired.created_ms = now_ms() + 1000;
+        hints.restore(addr(1), &[expired.clone(), expired]).unwrap();
+        let path = hints.path(&addr(1));
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+        assert_eq!(hints.take(&addr(1)).unwrap().len(), 1);
+    }
+}
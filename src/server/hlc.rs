@@ -0,0 +1,141 @@
+use crate::{Expiry, Version};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// A hybrid logical clock, stamping writes with versions that follow the
+// physical clock while never going backwards or repeating.
+// https://cse.buffalo.edu/tech-reports/2014-04.pdf
+//
+// A stamp packs the physical time in ms into the high bits and a logical
+// counter, bumped when several stamps share a ms or the physical clock lags a
+// stamp already seen, into the low LOGICAL_BITS. Nodes observe the stamps of
+// writes they receive, so a write coordinated after another was seen always
+// gets a greater stamp regardless of clock skew.
+pub struct Hlc {
+    last: AtomicI64,
+}
+
+const LOGICAL_BITS: u32 = 16;
+
+impl Hlc {
+    pub fn new() -> Self {
+        Self {
+            last: AtomicI64::new(0),
+        }
+    }
+
+    // Returns a stamp greater than every stamp issued or observed so far
+    pub fn now(&self) -> Version {
+        self.tick(physical_ms())
+    }
+
+    // Moves the clock past a stamp received from another node
+    pub fn observe(&self, stamp: Version) {
+        self.last.fetch_max(stamp, Ordering::SeqCst);
+    }
+
+    // Returns a stamp for a write the client may have assigned a version
+    // to, which the clock moves past so later stamps supersede it. Versions
+    // below 1 are unassigned.
+    pub fn now_after(&self, assigned: Version) -> Version {
+        if assigned > 0 {
+            self.observe(assigned);
+        }
+        self.now()
+    }
+
+    fn tick(&self, physical_ms: i64) -> Version {
+        let physical = physical_ms << LOGICAL_BITS;
+        let prev = self
+            .last
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+                last.checked_add(1).map(|next| next.max(physical))
+            })
+            .expect("hlc stamps exhausted");
+        // Can't overflow, as the update succeeded
+        (prev + 1).max(physical)
+    }
+}
+
+// Returns the stamp issued age before stamp, or the least stamp if age
+// reaches past it. Lesser stamps are older.
+pub fn before(stamp: Version, age: Duration) -> Version {
+    let age = (age.as_millis() as i128) << LOGICAL_BITS;
+    (stamp as i128 - age).max(i64::MIN as i128) as Version
+}
+
+// Returns the greatest stamp issued up to skew after the current physical
+// time, or the greatest stamp if skew reaches past it
+pub fn ahead(skew: Duration) -> Version {
+    let millis = physical_ms() as i128 + skew.as_millis() as i128;
+    (((millis + 1) << LOGICAL_BITS) - 1).min(i64::MAX as i128) as Version
+}
+
+// The physical time of a stamp in ms since the unix epoch
+pub fn millis(stamp: Version) -> Expiry {
+    (stamp >> LOGICAL_BITS) as Expiry
+}
+
+// The least stamp issued at a physical time
+pub fn at_millis(millis: Expiry) -> Version {
+    (millis as i64) << LOGICAL_BITS
+}
+
+fn physical_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time before unix epoch")
+        .as_millis() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hlc() {
+        let hlc = Hlc::new();
+        let a = hlc.tick(1000);
+        assert_eq!(a, 1000 << LOGICAL_BITS);
+        // Stamps within the same ms, or while the physical clock is behind,
+        // advance the logical counter
+        let b = hlc.tick(1000);
+        assert_eq!(b, a + 1);
+        assert_eq!(hlc.tick(999), b + 1);
+        // The physical clock takes over once it catches up
+        assert_eq!(hlc.tick(1001), 1001 << LOGICAL_BITS);
+
+        // Stamps from nodes whose clocks run ahead are never undercut
+        let remote = 5000 << LOGICAL_BITS;
+        hlc.observe(remote);
+        assert_eq!(hlc.tick(1002), remote + 1);
+        hlc.observe(remote - 10);
+        assert_eq!(hlc.tick(1003), remote + 2);
+
+        // Ticking reaches the greatest stamp without wrapping
+        let exhausted = Hlc::new();
+        exhausted.observe(i64::MAX - 1);
+        assert_eq!(exhausted.tick(1000), i64::MAX);
+
+        assert!(hlc.now() > remote);
+
+        // As are versions clients assign to writes
+        let assigned = (physical_ms() + 60_000) << LOGICAL_BITS;
+        assert!(hlc.now_after(assigned) > assigned);
+        assert!(hlc.now() > assigned);
+        let now = hlc.now();
+        assert_eq!(hlc.now_after(-1), now + 1);
+
+        assert_eq!(
+            before(remote + 1, Duration::from_secs(1)),
+            (4000 << LOGICAL_BITS) + 1
+        );
+        assert_eq!(before(remote, Duration::from_secs(u64::MAX)), i64::MIN);
+        let now = Hlc::new().now();
+        assert!(now < ahead(Duration::from_secs(1)));
+        assert!(ahead(Duration::from_secs(1)) < at_millis(physical_ms() as Expiry + 1001));
+        assert_eq!(ahead(Duration::from_secs(u64::MAX)), i64::MAX);
+        assert_eq!(millis(remote + 2), 5000);
+        assert_eq!(at_millis(5000), remote);
+    }
+}
@@ -0,0 +1,593 @@
+use super::placement::{self, Placement, Replicas, Zones};
+use crate::proto::{self, ClusterConfig, GossipType, NodeInfo, NodeStatus, ReplicaPlacement};
+use crate::ring::HashRing;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+// The local node's view of cluster membership.
+//
+// Each node owns its own entry and bumps its version on every gossip round
+// and status change. Entries received through gossip replace local ones only
+// when they carry a newer (generation, version), so the latest state a node
+// announced about itself eventually reaches every peer.
+//
+// UNAVAILABLE is a local opinion of the failure detector. It's set without
+// bumping the version and gossiped as the ONLINE it stands in for, so it's
+// never spread, and it survives newer gossip about the node until the
+// detector clears it.
+//
+// The write ring places writes and the read ring places reads. They differ
+// while ranges move between nodes: joining nodes are kept off the read ring
+// until they've received the ranges they take over, and leaving nodes stay
+// on it until they've handed theirs over, so reads go to the nodes holding
+// them. Nodes that have left are on neither.
+//
+// Replicas are chosen on either ring by the placement the cluster config
+// names, from the zones nodes announce. Nodes take points on the rings in
+// proportion to the weights they announce.
+pub struct Membership {
+    local: SocketAddr,
+    nodes: HashMap<SocketAddr, NodeState>,
+    zones: Zones,
+    weights: HashMap<SocketAddr, u32>,
+    ring: HashRing<SocketAddr>,
+    write_ring: HashRing<SocketAddr>,
+    placement: Box<dyn Placement>,
+}
+
+// The greatest weight a node may announce, bounding the points it takes on
+// the rings to this many times ring_replicas
+pub const MAX_WEIGHT: u32 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct NodeState {
+    status: NodeStatus,
+    generation: u64,
+    version: u64,
+}
+
+impl NodeState {
+    fn on_ring(&self) -> bool {
+        self.status != NodeStatus::LeftNetwork
+    }
+
+    fn is_readable(&self) -> bool {
+        self.on_ring() && self.status != NodeStatus::Joining
+    }
+
+    fn is_writable(&self) -> bool {
+        self.on_ring() && self.status != NodeStatus::Leaving
+    }
+}
+
+impl Membership {
+    // Starts out ONLINE, or JOINING if the local node has ranges to take
+    // over
+    pub fn new(
+        local: SocketAddr,
+        generation: u64,
+        status: NodeStatus,
+        zone: String,
+        weight: u32,
+        cluster_config: &ClusterConfig,
+    ) -> Self {
+        let mut membership = Self {
+            local,
+            nodes: HashMap::new(),
+            zones: Zones::new(),
+            weights: HashMap::new(),
+            ring: HashRing::new(cluster_config.ring_replicas),
+            write_ring: HashRing::new(cluster_config.ring_replicas),
+            placement: to_placement(cluster_config),
+        };
+        let state = NodeState {
+            status,
+            generation,
+            version: 0,
+        };
+        membership.nodes.insert(local, state);
+        membership.zones.insert(local, zone);
+        membership.weights.insert(local, weight);
+        membership.update_rings(local, None, state);
+        membership
+    }
+
+    // The ring placing reads
+    pub fn ring(&self) -> &HashRing<SocketAddr> {
+        &self.ring
+    }
+
+    // The ring placing writes, which has the joining nodes but not the
+    // leaving ones
+    pub fn write_ring(&self) -> &HashRing<SocketAddr> {
+        &self.write_ring
+    }
+
+    // The replicas reads go to
+    pub fn replicas(&self) -> Replicas<'_> {
+        self.replicas_on(&self.ring)
+    }
+
+    // The replicas writes go to
+    pub fn write_replicas(&self) -> Replicas<'_> {
+        self.replicas_on(&self.write_ring)
+    }
+
+    // The replicas on a ring taken from the membership at some point
+    pub fn replicas_on<'a>(&'a self, ring: &'a HashRing<SocketAddr>) -> Replicas<'a> {
+        Replicas {
+            ring,
+            placement: self.placement.as_ref(),
+            zones: &self.zones,
+        }
+    }
+
+    pub fn local_status(&self) -> NodeStatus {
+        self.nodes[&self.local].status
+    }
+
+    // Whether a node is on the ring and not suspected by the failure detector
+    pub fn is_available(&self, addr: &SocketAddr) -> bool {
+        self.nodes
+            .get(addr)
+            .is_some_and(|state| match state.status {
+                NodeStatus::Online | NodeStatus::Joining | NodeStatus::Leaving => true,
+                NodeStatus::Unavailable | NodeStatus::LeftNetwork => false,
+            })
+    }
+
+    pub fn has_left(&self, addr: &SocketAddr) -> bool {
+        self.nodes
+            .get(addr)
+            .is_some_and(|state| state.status == NodeStatus::LeftNetwork)
+    }
+
+    pub fn is_leaving(&self, addr: &SocketAddr) -> bool {
+        self.nodes
+            .get(addr)
+            .is_some_and(|state| state.status == NodeStatus::Leaving)
+    }
+
+    // Peers that haven't left the network
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.nodes
+            .iter()
+            .filter(|(addr, state)| **addr != self.local && state.on_ring())
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    // Bumps the local version so peers see the local node is still alive
+    pub fn tick(&mut self) {
+        self.nodes.get_mut(&self.local).unwrap().version += 1;
+    }
+
+    pub fn local_info(&self) -> NodeInfo {
+        self.to_node_info(&self.local, &self.nodes[&self.local])
+    }
+
+    pub fn gossip(&self, gossip_type: GossipType) -> proto::Gossip {
+        proto::Gossip {
+            r#type: gossip_type as i32,
+            nodes: self
+                .nodes
+                .iter()
+                .map(|(addr, state)| self.to_node_info(addr, state))
+                .collect(),
+        }
+    }
+
+    // Applies every entry newer than the local one
+    pub fn merge(&mut self, gossip: &proto::Gossip) {
+        for info in &gossip.nodes {
+            let addr = match info.address.parse::<SocketAddr>() {
+                Ok(addr) => addr,
+                Err(_) => {
+                    warn!("ignoring gossip for invalid address: {}", info.address);
+                    continue;
+                }
+            };
+            let status = match NodeStatus::from_i32(info.status) {
+                Some(status) => status,
+                None => {
+                    warn!("ignoring gossip with invalid status: {}", info.status);
+                    continue;
+                }
+            };
+            let state = NodeState {
+                status,
+                generation: info.generation,
+                version: info.version,
+            };
+            // A node's zone and weight only change with a restart, so
+            // they're taken along with the entry's other news. They're set
+            // before the rings change to place replicas by them right away.
+            if addr != self.local && self.is_newer(&addr, &state) {
+                self.zones.insert(addr, info.zone.clone());
+                self.set_weight(addr, info.weight.clamp(1, MAX_WEIGHT));
+            }
+            self.apply(addr, state);
+        }
+    }
+
+    // Rebuilds the rings and the placement after the cluster config changed
+    pub fn set_cluster_config(&mut self, cluster_config: &ClusterConfig) {
+        self.placement = to_placement(cluster_config);
+        self.ring = HashRing::new(cluster_config.ring_replicas);
+        self.write_ring = HashRing::new(cluster_config.ring_replicas);
+        for (addr, state) in &self.nodes {
+            let weight = self.weights[addr];
+            if state.is_readable() {
+                self.ring.insert_weighted(*addr, weight);
+            }
+            if state.is_writable() {
+                self.write_ring.insert_weighted(*addr, weight);
+            }
+        }
+    }
+
+    // Changes the status of the local node, bumping its version so the
+    // change spreads
+    pub fn set_local_status(&mut self, status: NodeStatus) {
+        let old = self.nodes[&self.local];
+        let new = NodeState {
+            status,
+            version: old.version + 1,
+            ..old
+        };
+        self.nodes.insert(self.local, new);
+        self.update_rings(self.local, Some(old), new);
+    }
+
+    // Marks a node as unavailable or available again, without spreading the
+    // change
+    pub fn set_available(&mut self, addr: &SocketAddr, available: bool) {
+        if *addr == self.local {
+            return;
+        }
+        if let Some(state) = self.nodes.get_mut(addr) {
+            match (state.status, available) {
+                (NodeStatus::Online, false) => {
+                    info!("node {} is unavailable", addr);
+                    state.status = NodeStatus::Unavailable;
+                }
+                (NodeStatus::Unavailable, true) => {
+                    info!("node {} is available", addr);
+                    state.status = NodeStatus::Online;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Records that a node left the network. Bumps the node's version on its
+    // behalf so the change spreads.
+    pub fn leave(&mut self, addr: SocketAddr) {
+        let state = match self.nodes.get(&addr) {
+            Some(state) => NodeState {
+                status: NodeStatus::LeftNetwork,
+                version: state.version + 1,
+                ..*state
+            },
+            None => return,
+        };
+        self.apply(addr, state);
+    }
+
+    fn apply(&mut self, addr: SocketAddr, new: NodeState) {
+        if addr == self.local {
+            // Refute stale or mistaken news about the local node by
+            // announcing a newer version of its actual state
+            let local = self.nodes.get_mut(&addr).unwrap();
+            if new.generation == local.generation
+                && new.version >= local.version
+                && new.status != local.status
+            {
+                local.version = new.version + 1;
+            }
+            return;
+        }
+
+        if !self.is_newer(&addr, &new) {
+            return;
+        }
+        let old = self.nodes.get(&addr).copied();
+        let new = match old {
+            Some(old)
+                if old.status == NodeStatus::Unavailable && new.status == NodeStatus::Online =>
+            {
+                NodeState {
+                    status: NodeStatus::Unavailable,
+                    ..new
+                }
+            }
+            _ => new,
+        };
+        self.nodes.insert(addr, new);
+        self.update_rings(addr, old, new);
+    }
+
+    // Rescales the points of a node on the rings it's on
+    fn set_weight(&mut self, addr: SocketAddr, weight: u32) {
+        let old = self.weights.insert(addr, weight);
+        let state = match (old, self.nodes.get(&addr)) {
+            (Some(old), Some(state)) if old != weight => *state,
+            _ => return,
+        };
+        info!(
+            "node {} changed weight from {} to {}",
+            addr,
+            old.unwrap(),
+            weight
+        );
+        if state.is_readable() {
+            self.ring.insert_weighted(addr, weight);
+        }
+        if state.is_writable() {
+            self.write_ring.insert_weighted(addr, weight);
+        }
+    }
+
+    fn is_newer(&self, addr: &SocketAddr, state: &NodeState) -> bool {
+        self.nodes
+            .get(addr)
+            .is_none_or(|old| (state.generation, state.version) > (old.generation, old.version))
+    }
+
+    fn to_node_info(&self, addr: &SocketAddr, state: &NodeState) -> NodeInfo {
+        // Only ONLINE nodes are marked unavailable
+        let status = match state.status {
+            NodeStatus::Unavailable => NodeStatus::Online,
+            status => status,
+        };
+        NodeInfo {
+            address: addr.to_string(),
+            status: status as i32,
+            generation: state.generation,
+            version: state.version,
+            zone: self.zones.get(addr).cloned().unwrap_or_default(),
+            weight: self.weights[addr],
+        }
+    }
+
+    fn update_rings(&mut self, addr: SocketAddr, old: Option<NodeState>, new: NodeState) {
+        let was_writable = old.is_some_and(|old| old.is_writable());
+        let weight = self.weights[&addr];
+        if new.is_writable() && !was_writable {
+            self.write_ring.insert_weighted(addr, weight);
+        } else if !new.is_writable() && was_writable {
+            self.write_ring.remove(&addr);
+        }
+        let was_readable = old.is_some_and(|old| old.is_readable());
+        if new.is_readable() && !was_readable {
+            self.ring.insert_weighted(addr, weight);
+        } else if !new.is_readable() && was_readable {
+            self.ring.remove(&addr);
+        }
+
+        let was_on_ring = old.is_some_and(|old| old.on_ring());
+        match (was_on_ring, new.on_ring()) {
+            (false, true) => info!("node {} joined the ring", addr),
+            (true, false) => info!("node {} left the ring", addr),
+            (true, true) if new.is_readable() && !was_readable => {
+                info!("node {} finished joining", addr)
+            }
+            (true, true) if !new.is_writable() && was_writable => {
+                info!("node {} is leaving the ring", addr)
+            }
+            _ => {}
+        }
+    }
+}
+
+fn to_placement(cluster_config: &ClusterConfig) -> Box<dyn Placement> {
+    // The config is validated before it's used
+    placement::placement(
+        ReplicaPlacement::from_i32(cluster_config.replica_placement)
+            .unwrap_or(ReplicaPlacement::ZoneAware),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::server::default_cluster_config;
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn info(port: u16, status: NodeStatus, generation: u64, version: u64) -> NodeInfo {
+        NodeInfo {
+            address: addr(port).to_string(),
+            status: status as i32,
+            generation,
+            version,
+            zone: format!("zone{}", port),
+            weight: 1,
+        }
+    }
+
+    fn membership(status: NodeStatus) -> Membership {
+        Membership::new(
+            addr(1),
+            1,
+            status,
+            "zone1".to_string(),
+            1,
+            &default_cluster_config(),
+        )
+    }
+
+    fn gossip(nodes: Vec<NodeInfo>) -> proto::Gossip {
+        proto::Gossip {
+            r#type: GossipType::ShareFullNetwork as i32,
+            nodes,
+        }
+    }
+
+    fn status(m: &Membership, port: u16) -> NodeStatus {
+        m.nodes[&addr(port)].status
+    }
+
+    fn ring_buckets(m: &Membership) -> Vec<SocketAddr> {
+        let mut buckets: Vec<_> = m.ring().successors(b"k").cloned().collect();
+        buckets.sort();
+        buckets.dedup();
+        buckets
+    }
+
+    #[test]
+    fn test_membership_merge() {
+        let mut m = membership(NodeStatus::Online);
+        assert_eq!(ring_buckets(&m), vec![addr(1)]);
+
+        m.merge(&gossip(vec![
+            info(2, NodeStatus::Online, 1, 3),
+            info(3, NodeStatus::Online, 1, 0),
+        ]));
+        assert_eq!(ring_buckets(&m), vec![addr(1), addr(2), addr(3)]);
+        assert_eq!(m.zones[&addr(2)], "zone2");
+        let mut peers = m.peers();
+        peers.sort();
+        assert_eq!(peers, vec![addr(2), addr(3)]);
+
+        // Stale news is ignored
+        m.merge(&gossip(vec![info(2, NodeStatus::LeftNetwork, 1, 2)]));
+        assert_eq!(status(&m, 2), NodeStatus::Online);
+
+        // Newer news is applied
+        m.merge(&gossip(vec![info(2, NodeStatus::LeftNetwork, 1, 4)]));
+        assert_eq!(status(&m, 2), NodeStatus::LeftNetwork);
+        assert_eq!(ring_buckets(&m), vec![addr(1), addr(3)]);
+        assert_eq!(m.peers(), vec![addr(3)]);
+
+        // A restart rejoins regardless of version
+        m.merge(&gossip(vec![info(2, NodeStatus::Online, 2, 0)]));
+        assert_eq!(ring_buckets(&m), vec![addr(1), addr(2), addr(3)]);
+    }
+
+    #[test]
+    fn test_membership_ring_replicas() {
+        let mut m = membership(NodeStatus::Online);
+        m.merge(&gossip(vec![
+            info(2, NodeStatus::Online, 1, 1),
+            info(3, NodeStatus::LeftNetwork, 1, 1),
+        ]));
+        assert_eq!(m.ring().ranges(1).len(), 16);
+
+        m.set_cluster_config(&ClusterConfig {
+            ring_replicas: 32,
+            ..default_cluster_config()
+        });
+        assert_eq!(m.ring().ranges(1).len(), 64);
+        assert_eq!(ring_buckets(&m), vec![addr(1), addr(2)]);
+    }
+
+    #[test]
+    fn test_membership_weights() {
+        let mut m = membership(NodeStatus::Online);
+        m.merge(&gossip(vec![NodeInfo {
+            weight: 3,
+            ..info(2, NodeStatus::Online, 1, 1)
+        }]));
+        assert_eq!(m.ring().ranges(1).len(), 32);
+        assert_eq!(m.write_ring().ranges(1).len(), 32);
+
+        // A restart with a different weight rescales the node's points
+        m.merge(&gossip(vec![info(2, NodeStatus::Online, 2, 0)]));
+        assert_eq!(m.ring().ranges(1).len(), 16);
+        assert_eq!(m.write_ring().ranges(1).len(), 16);
+        m.merge(&gossip(vec![NodeInfo {
+            weight: 0,
+            ..info(2, NodeStatus::Online, 3, 0)
+        }]));
+        assert_eq!(m.ring().ranges(1).len(), 16);
+
+        // Weights past the cap are taken as the cap
+        m.merge(&gossip(vec![NodeInfo {
+            weight: MAX_WEIGHT,
+            ..info(2, NodeStatus::Online, 4, 0)
+        }]));
+        let ranges = m.ring().ranges(1).len();
+        assert_eq!(ranges, 8 * (MAX_WEIGHT as usize + 1));
+        m.merge(&gossip(vec![NodeInfo {
+            weight: u32::MAX,
+            ..info(2, NodeStatus::Online, 5, 0)
+        }]));
+        assert_eq!(m.ring().ranges(1).len(), ranges);
+        assert_eq!(m.weights[&addr(2)], MAX_WEIGHT);
+    }
+
+    #[test]
+    fn test_membership_availability() {
+        let mut m = membership(NodeStatus::Online);
+        m.merge(&gossip(vec![info(2, NodeStatus::Online, 1, 1)]));
+
+        m.set_available(&addr(2), false);
+        assert_eq!(status(&m, 2), NodeStatus::Unavailable);
+        assert!(!m.is_available(&addr(2)));
+        assert_eq!(ring_buckets(&m), vec![addr(1), addr(2)]);
+        // Peers aren't told
+        let nodes = m.gossip(GossipType::ShareFullNetwork).nodes;
+        let node = nodes
+            .iter()
+            .find(|node| node.address == addr(2).to_string());
+        assert_eq!(node.unwrap().status, NodeStatus::Online as i32);
+
+        // Only the failure detector makes the node available again
+        m.merge(&gossip(vec![info(2, NodeStatus::Online, 1, 2)]));
+        assert_eq!(status(&m, 2), NodeStatus::Unavailable);
+        m.set_available(&addr(2), true);
+        assert!(m.is_available(&addr(2)));
+
+        m.leave(addr(2));
+        assert_eq!(status(&m, 2), NodeStatus::LeftNetwork);
+        assert!(m.has_left(&addr(2)));
+        assert_eq!(m.gossip(GossipType::NotifyNodeLeft).nodes.len(), 2);
+    }
+
+    #[test]
+    fn test_membership_joining_and_leaving() {
+        let write_buckets = |m: &Membership| {
+            let mut buckets: Vec<_> = m.write_ring().successors(b"k").cloned().collect();
+            buckets.sort();
+            buckets.dedup();
+            buckets
+        };
+        let mut m = membership(NodeStatus::Joining);
+        m.merge(&gossip(vec![
+            info(2, NodeStatus::Online, 1, 1),
+            info(3, NodeStatus::Joining, 1, 1),
+        ]));
+        assert_eq!(ring_buckets(&m), vec![addr(2)]);
+        assert_eq!(write_buckets(&m), vec![addr(1), addr(2), addr(3)]);
+        assert!(m.is_available(&addr(3)));
+
+        // Joining nodes are read from once they're online
+        m.set_local_status(NodeStatus::Online);
+        assert_eq!(m.local_info().version, 1);
+        m.merge(&gossip(vec![info(3, NodeStatus::Online, 1, 2)]));
+        assert_eq!(ring_buckets(&m), vec![addr(1), addr(2), addr(3)]);
+        assert_eq!(write_buckets(&m), vec![addr(1), addr(2), addr(3)]);
+
+        // Leaving nodes are read from until they've left
+        m.merge(&gossip(vec![info(2, NodeStatus::Leaving, 1, 2)]));
+        assert!(m.is_leaving(&addr(2)));
+        assert_eq!(ring_buckets(&m), vec![addr(1), addr(2), addr(3)]);
+        assert_eq!(write_buckets(&m), vec![addr(1), addr(3)]);
+        m.merge(&gossip(vec![info(2, NodeStatus::LeftNetwork, 1, 3)]));
+        assert_eq!(ring_buckets(&m), vec![addr(1), addr(3)]);
+        assert_eq!(write_buckets(&m), vec![addr(1), addr(3)]);
+    }
+
+    #[test]
+    fn test_membership_refutes_local_news() {
+        let mut m = membership(NodeStatus::Online);
+        m.merge(&gossip(vec![info(1, NodeStatus::LeftNetwork, 1, 5)]));
+        let local = m.local_info();
+        assert_eq!(local.status, NodeStatus::Online as i32);
+        assert_eq!(local.version, 6);
+    }
+}
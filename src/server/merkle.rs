@@ -0,0 +1,325 @@
+use crate::proto::KeyDigest;
+use crate::{Value, Version};
+use std::collections::{BTreeMap, HashSet};
+use xxhash_rust::xxh64::xxh64;
+
+// A Merkle tree over every key a node stores, used to find the keys that
+// differ between replicas without comparing them one by one.
+//
+// The tree has a fixed shape: 2^DEPTH leaves, each covering an equal slice of
+// the hash ring, so co-replicas can compare any node by id. A node's hash is
+// the XOR of the hashes of every (key, digest) pair beneath it, which lets
+// the tree be updated in place on every mutation. Ranges owned according to
+// the HashRing rarely align with leaves, so nodes that straddle a range's
+// boundary are compared key by key.
+//
+// Only the hashes are kept, 1MiB whatever the number of keys, so the keys
+// stored need not fit in memory. The keys under differing leaves are found
+// by scanning the store instead.
+pub struct MerkleTree {
+    // Node i has children 2i and 2i+1. The root is node 1 and leaves are
+    // nodes LEAVES..2 * LEAVES.
+    hashes: Vec<u64>,
+}
+
+const DEPTH: u32 = 16;
+const LEAVES: u32 = 1 << DEPTH;
+pub const ROOT: u32 = 1;
+
+impl MerkleTree {
+    pub fn new() -> Self {
+        Self {
+            hashes: vec![0; 2 * LEAVES as usize],
+        }
+    }
+
+    // Adds a key found at point on the ring. A key's digest must be removed
+    // before another one is added.
+    pub fn insert(&mut self, point: u64, key: &[u8], digest: u64) {
+        self.update(leaf_of(point), item_hash(key, digest));
+    }
+
+    // Removes a key added with digest
+    pub fn remove(&mut self, point: u64, key: &[u8], digest: u64) {
+        self.update(leaf_of(point), item_hash(key, digest));
+    }
+
+    // Returns the hash of a node, or None if there's no such node
+    pub fn hash(&self, node: u32) -> Option<u64> {
+        if (ROOT..2 * LEAVES).contains(&node) {
+            Some(self.hashes[node as usize])
+        } else {
+            None
+        }
+    }
+
+    // Given the remote hashes of nodes lying within a range, returns the
+    // children of the nodes that differ and adds differing leaves to leaves
+    pub fn descend(&self, nodes: &[u32], remote: &[u64], leaves: &mut Vec<u32>) -> Vec<u32> {
+        let mut next = Vec::new();
+        for (node, hash) in nodes.iter().zip(remote) {
+            if self.hash(*node) == Some(*hash) {
+                continue;
+            }
+            if is_leaf(*node) {
+                leaves.push(*node);
+            } else {
+                next.push(2 * node);
+                next.push(2 * node + 1);
+            }
+        }
+        next
+    }
+
+    fn update(&mut self, leaf: u32, delta: u64) {
+        let mut node = leaf;
+        while node >= ROOT {
+            self.hashes[node as usize] ^= delta;
+            node /= 2;
+        }
+    }
+}
+
+// Returns whether a point lies under one of the given leaves and within
+// [start, end]. Ids that aren't leaves are ignored.
+pub fn in_leaves(leaves: &[u32], start: u64, end: u64) -> impl Fn(u64) -> bool {
+    let leaves: HashSet<u32> = leaves.iter().copied().collect();
+    move |point| point >= start && point <= end && leaves.contains(&leaf_of(point))
+}
+
+// Splits the nodes below node that overlap [start, end] into those lying
+// entirely within it, whose hashes can be compared, and leaves that only
+// partly overlap it, which must be compared key by key
+pub fn split(node: u32, start: u64, end: u64, nodes: &mut Vec<u32>, leaves: &mut Vec<u32>) {
+    let (lo, hi) = span(node);
+    if hi < start || lo > end {
+        return;
+    }
+    if lo >= start && hi <= end {
+        nodes.push(node);
+    } else if is_leaf(node) {
+        leaves.push(node);
+    } else {
+        split(2 * node, start, end, nodes, leaves);
+        split(2 * node + 1, start, end, nodes, leaves);
+    }
+}
+
+// Returns the keys whose digests differ between two sets, including keys
+// present in only one of them
+pub fn diff(local: &[KeyDigest], remote: &[KeyDigest]) -> Vec<Vec<u8>> {
+    let local: BTreeMap<_, _> = local.iter().map(|k| (&k.key, k.digest)).collect();
+    let remote: BTreeMap<_, _> = remote.iter().map(|k| (&k.key, k.digest)).collect();
+    let mut keys: Vec<Vec<u8>> = local
+        .iter()
+        .filter(|(key, digest)| remote.get(*key) != Some(*digest))
+        .map(|(key, _)| (*key).clone())
+        .collect();
+    keys.extend(
+        remote
+            .keys()
+            .filter(|key| !local.contains_key(*key))
+            .map(|key| (*key).clone()),
+    );
+    keys
+}
+
+// Converts a ring range (start, end], as returned by HashRing::ranges, into
+// inclusive spans of points
+pub fn spans(start: u64, end: u64) -> Vec<(u64, u64)> {
+    if start < end {
+        return vec![(start + 1, end)];
+    }
+    let mut spans = vec![(0, end)];
+    if start < u64::MAX {
+        spans.push((start + 1, u64::MAX));
+    }
+    spans
+}
+
+// Hash identifying a version of a key's value, or of its tombstone if value
+// is None. Digests and tree hashes are compared across nodes, so they're
+// XXH64 hashes of a fixed encoding rather than of the Hash impls.
+pub fn digest(value: Option<&Value>, version: Version) -> u64 {
+    let mut bytes = Vec::with_capacity(value.map_or(0, |value| value.len()) + 17);
+    match value {
+        Some(value) => {
+            bytes.push(1);
+            bytes.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(value);
+        }
+        None => bytes.push(0),
+    }
+    bytes.extend_from_slice(&version.to_le_bytes());
+    xxh64(&bytes, 0)
+}
+
+fn item_hash(key: &[u8], digest: u64) -> u64 {
+    let mut bytes = Vec::with_capacity(key.len() + 16);
+    bytes.extend_from_slice(&(key.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(key);
+    bytes.extend_from_slice(&digest.to_le_bytes());
+    xxh64(&bytes, 0)
+}
+
+fn is_leaf(node: u32) -> bool {
+    (LEAVES..2 * LEAVES).contains(&node)
+}
+
+fn leaf_of(point: u64) -> u32 {
+    LEAVES + (point >> (64 - DEPTH)) as u32
+}
+
+// The inclusive range of ring points covered by a node
+fn span(node: u32) -> (u64, u64) {
+    let level = 31 - node.leading_zeros();
+    if level == 0 {
+        return (0, u64::MAX);
+    }
+    let width = 64 - level;
+    let lo = ((node - (1 << level)) as u64) << width;
+    (lo, lo | ((1 << width) - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A tree along with the keys under it, standing in for a store
+    struct Replica {
+        tree: MerkleTree,
+        keys: BTreeMap<Vec<u8>, (u64, u64)>,
+    }
+
+    impl Replica {
+        fn new() -> Self {
+            Self {
+                tree: MerkleTree::new(),
+                keys: BTreeMap::new(),
+            }
+        }
+
+        fn insert(&mut self, point: u64, key: Vec<u8>, digest: u64) {
+            self.remove(point, &key);
+            self.tree.insert(point, &key, digest);
+            self.keys.insert(key, (point, digest));
+        }
+
+        fn remove(&mut self, point: u64, key: &[u8]) {
+            if let Some((_, old)) = self.keys.remove(key) {
+                self.tree.remove(point, key, old);
+            }
+        }
+
+        fn digests(&self, leaves: &[u32], start: u64, end: u64) -> Vec<KeyDigest> {
+            let within = in_leaves(leaves, start, end);
+            self.keys
+                .iter()
+                .filter(|(_, (point, _))| within(*point))
+                .map(|(key, (_, digest))| KeyDigest {
+                    key: key.clone(),
+                    digest: *digest,
+                })
+                .collect()
+        }
+    }
+
+    // Compares two replicas over [start, end] the way they do, returning
+    // the differing keys
+    fn compare(a: &Replica, b: &Replica, start: u64, end: u64) -> Vec<Vec<u8>> {
+        let mut nodes = Vec::new();
+        let mut leaves = Vec::new();
+        split(ROOT, start, end, &mut nodes, &mut leaves);
+        while !nodes.is_empty() {
+            let remote: Vec<_> = nodes.iter().map(|n| b.tree.hash(*n).unwrap()).collect();
+            nodes = a.tree.descend(&nodes, &remote, &mut leaves);
+        }
+        let mut keys = diff(
+            &a.digests(&leaves, start, end),
+            &b.digests(&leaves, start, end),
+        );
+        keys.sort();
+        keys
+    }
+
+    fn point(i: u64) -> u64 {
+        i.wrapping_mul(0x9e37_79b9_7f4a_7c15)
+    }
+
+    fn key(i: u64) -> Vec<u8> {
+        format!("k{}", i).into_bytes()
+    }
+
+    #[test]
+    fn test_merkle_tree() {
+        let mut a = Replica::new();
+        let mut b = Replica::new();
+        for i in 0..1000 {
+            a.insert(point(i), key(i), i);
+            b.insert(point(i), key(i), i);
+        }
+        assert_eq!(a.tree.hash(ROOT), b.tree.hash(ROOT));
+        assert!(compare(&a, &b, 0, u64::MAX).is_empty());
+
+        b.insert(point(1), key(1), 42);
+        b.remove(point(2), &key(2));
+        b.insert(point(1000), key(1000), 1000);
+        assert_ne!(a.tree.hash(ROOT), b.tree.hash(ROOT));
+        let mut expected = vec![key(1), key(2), key(1000)];
+        expected.sort();
+        assert_eq!(compare(&a, &b, 0, u64::MAX), expected);
+
+        // Only keys within the compared range are reported
+        let (start, end) = (point(1) - 10, point(1) + 10);
+        assert_eq!(compare(&a, &b, start, end), vec![key(1)]);
+
+        // Updates are reversible
+        b.insert(point(1), key(1), 1);
+        b.insert(point(2), key(2), 2);
+        b.remove(point(1000), &key(1000));
+        assert_eq!(a.tree.hash(ROOT), b.tree.hash(ROOT));
+    }
+
+    #[test]
+    fn test_in_leaves() {
+        let within = in_leaves(&[leaf_of(1 << 48), ROOT], 1 << 48, u64::MAX);
+        assert!(within(1 << 48));
+        assert!(within((1 << 48) + 1));
+        assert!(!within((1 << 48) - 1));
+        assert!(!within(u64::MAX));
+    }
+
+    #[test]
+    fn test_merkle_digests() {
+        // Digests are exchanged between nodes, so they must never change
+        let value = b"v0".to_vec();
+        assert_eq!(digest(Some(&value), 42), 0xa4c9_f6d1_88c7_88c7);
+        assert_eq!(digest(None, 42), 0x91eb_5b75_a9a3_86c9);
+        let mut tree = MerkleTree::new();
+        tree.insert(0, b"k0", digest(Some(&value), 42));
+        assert_eq!(tree.hash(ROOT), Some(0x2b6d_faaf_eb1a_da67));
+        // A tombstone differs from an empty value
+        assert_ne!(digest(Some(&Vec::new()), 42), digest(None, 42));
+    }
+
+    #[test]
+    fn test_merkle_spans() {
+        assert_eq!(spans(1, 5), vec![(2, 5)]);
+        assert_eq!(spans(5, 1), vec![(0, 1), (6, u64::MAX)]);
+        assert_eq!(spans(u64::MAX, 1), vec![(0, 1)]);
+        assert_eq!(spans(3, 3), vec![(0, 3), (4, u64::MAX)]);
+    }
+
+    #[test]
+    fn test_merkle_span() {
+        assert_eq!(span(ROOT), (0, u64::MAX));
+        assert_eq!(span(2), (0, u64::MAX / 2));
+        assert_eq!(span(3), (u64::MAX / 2 + 1, u64::MAX));
+        assert_eq!(span(LEAVES).0, 0);
+        assert_eq!(span(2 * LEAVES - 1).1, u64::MAX);
+        for p in &[0, 1, 1 << 48, u64::MAX] {
+            let (lo, hi) = span(leaf_of(*p));
+            assert!(lo <= *p && *p <= hi);
+        }
+    }
+}
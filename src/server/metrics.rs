@@ -0,0 +1,20 @@
+use crate::proto;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Counters of a node's activity since it started
+#[derive(Default)]
+pub struct Metrics {
+    read_repairs: AtomicU64,
+}
+
+impl Metrics {
+    pub fn add_read_repairs(&self, n: u64) {
+        self.read_repairs.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> proto::GetMetricsResponse {
+        proto::GetMetricsResponse {
+            read_repairs: self.read_repairs.load(Ordering::Relaxed),
+        }
+    }
+}
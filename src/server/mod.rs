@@ -1,5 +1,16 @@
+mod config;
+mod failure_detector;
+mod hints;
+mod hlc;
+mod membership;
+mod merkle;
+mod metrics;
+mod peers;
+mod placement;
+mod rebalance;
 mod server;
 mod service;
+mod vector_clock;
 
 pub use server::{Config, Server};
 pub use service::{PeerService, RkvService};
@@ -0,0 +1,192 @@
+use crate::error::{Error, Result};
+use crate::proto::peer_service_client::PeerServiceClient;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tonic::transport::{Channel, Endpoint};
+
+// Long-lived connections to peers, shared by every request to the same node.
+//
+// A tonic channel multiplexes concurrent requests over one HTTP/2
+// connection, so a single channel is kept per peer and cloned for each
+// request. A failed connection attempt, or a request failing for want of a
+// connection, drops the peer's channel and backs off before reconnecting,
+// doubling with each consecutive failure. Until then requests to the peer
+// fail immediately instead of paying a connection attempt each.
+pub struct PeerConnections {
+    peers: Mutex<HashMap<SocketAddr, Peer>>,
+}
+
+#[derive(Default)]
+struct Peer {
+    channel: Option<Channel>,
+    // Consecutive failures to connect or to reach the peer
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+// Whether requests are getting through to a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+    Connected,
+    // Never connected to, or evicted
+    Unknown,
+    // Backing off after consecutive failures
+    Unreachable(u32),
+}
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+impl PeerConnections {
+    pub fn new() -> Self {
+        Self {
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Returns a client sharing the peer's channel, connecting first if
+    // there's none
+    pub async fn client(&self, addr: SocketAddr) -> Result<PeerServiceClient<Channel>> {
+        {
+            let peers = self.peers.lock().unwrap();
+            if let Some(peer) = peers.get(&addr) {
+                if let Some(channel) = &peer.channel {
+                    return Ok(PeerServiceClient::new(channel.clone()));
+                }
+                if peer.retry_at.is_some_and(|at| Instant::now() < at) {
+                    return Err(Error::Rpc(tonic::Status::unavailable(format!(
+                        "backing off from {}",
+                        addr
+                    ))));
+                }
+            }
+        }
+
+        let endpoint = to_endpoint(&addr);
+        let result = match tokio::time::timeout(CONNECT_TIMEOUT, endpoint.connect()).await {
+            Ok(result) => result.map_err(Error::from),
+            Err(_) => Err(Error::Timeout),
+        };
+        let mut peers = self.peers.lock().unwrap();
+        let peer = peers.entry(addr).or_default();
+        match result {
+            Ok(channel) => {
+                peer.failures = 0;
+                peer.retry_at = None;
+                let channel = peer.channel.get_or_insert(channel);
+                Ok(PeerServiceClient::new(channel.clone()))
+            }
+            Err(e) => {
+                peer.fail(Instant::now());
+                Err(e)
+            }
+        }
+    }
+
+    // Passes on the result of a request to a peer, dropping its channel if
+    // the request failed to reach it
+    pub fn check<T>(
+        &self,
+        addr: SocketAddr,
+        result: std::result::Result<T, tonic::Status>,
+    ) -> Result<T> {
+        if let Err(status) = &result {
+            if is_connection_error(status) {
+                let mut peers = self.peers.lock().unwrap();
+                if let Some(peer) = peers.get_mut(&addr) {
+                    peer.channel = None;
+                    peer.fail(Instant::now());
+                }
+            }
+        }
+        result.map_err(Error::from)
+    }
+
+    // Drops the connections to nodes other than peers, such as those that
+    // left the network
+    pub fn retain(&self, peers: &[SocketAddr]) {
+        self.peers
+            .lock()
+            .unwrap()
+            .retain(|addr, _| peers.contains(addr));
+    }
+
+    pub fn health(&self, addr: &SocketAddr) -> Health {
+        match self.peers.lock().unwrap().get(addr) {
+            Some(peer) if peer.channel.is_some() => Health::Connected,
+            Some(peer) if peer.failures > 0 => Health::Unreachable(peer.failures),
+            _ => Health::Unknown,
+        }
+    }
+}
+
+impl Peer {
+    fn fail(&mut self, now: Instant) {
+        self.failures += 1;
+        self.retry_at = Some(now + backoff(self.failures));
+    }
+}
+
+// Time to wait before reconnecting after consecutive failures
+fn backoff(failures: u32) -> Duration {
+    let exp = failures.saturating_sub(1).min(16);
+    (MIN_BACKOFF * 2u32.pow(exp)).min(MAX_BACKOFF)
+}
+
+// Whether a request failed because the peer couldn't be reached, as
+// opposed to the peer failing it. Transport failures surface as UNKNOWN.
+fn is_connection_error(status: &tonic::Status) -> bool {
+    matches!(
+        status.code(),
+        tonic::Code::Unavailable | tonic::Code::Unknown
+    )
+}
+
+fn to_endpoint(addr: &SocketAddr) -> Endpoint {
+    Endpoint::from_shared(format!("http://{}", addr)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), MIN_BACKOFF);
+        assert_eq!(backoff(2), MIN_BACKOFF * 2);
+        assert_eq!(backoff(3), MIN_BACKOFF * 4);
+        assert_eq!(backoff(10), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_peer() {
+        // Nothing listens on the port of a just-closed listener
+        let addr = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        let peers = PeerConnections::new();
+        assert_eq!(peers.health(&addr), Health::Unknown);
+
+        assert!(peers.client(addr).await.is_err());
+        assert_eq!(peers.health(&addr), Health::Unreachable(1));
+
+        // Requests during the backoff fail without connecting
+        match peers.client(addr).await {
+            Err(Error::Rpc(status)) => assert_eq!(status.code(), tonic::Code::Unavailable),
+            _ => panic!("expected backoff"),
+        }
+        assert_eq!(peers.health(&addr), Health::Unreachable(1));
+
+        tokio::time::delay_for(backoff(1)).await;
+        assert!(peers.client(addr).await.is_err());
+        assert_eq!(peers.health(&addr), Health::Unreachable(2));
+
+        peers.retain(&[]);
+        assert_eq!(peers.health(&addr), Health::Unknown);
+    }
+}
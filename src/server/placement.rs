@@ -0,0 +1,194 @@
+use crate::proto::ReplicaPlacement;
+use crate::ring::HashRing;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+
+// The zone of every node known to the membership
+pub type Zones = HashMap<SocketAddr, String>;
+
+// Chooses the replicas of a key from the nodes on the ring.
+//
+// Every node must choose the same replicas for the same ring, so a
+// placement may only depend on the order of the nodes after the key and on
+// their zones.
+pub trait Placement: Send + Sync {
+    // Chooses up to n of the distinct nodes met walking the ring from a key
+    fn choose(
+        &self,
+        walk: &mut dyn Iterator<Item = SocketAddr>,
+        zones: &Zones,
+        n: usize,
+    ) -> Vec<SocketAddr>;
+}
+
+pub fn placement(replica_placement: ReplicaPlacement) -> Box<dyn Placement> {
+    match replica_placement {
+        ReplicaPlacement::ZoneAware => Box::new(ZoneAware),
+        ReplicaPlacement::RingOrder => Box::new(RingOrder),
+    }
+}
+
+// The first n nodes after the key
+pub struct RingOrder;
+
+impl Placement for RingOrder {
+    fn choose(
+        &self,
+        walk: &mut dyn Iterator<Item = SocketAddr>,
+        _zones: &Zones,
+        n: usize,
+    ) -> Vec<SocketAddr> {
+        walk.take(n).collect()
+    }
+}
+
+// The first nodes after the key in zones that don't hold a replica yet.
+// Once every zone holds one, or too few nodes remain, the nodes skipped are
+// taken in ring order. The walk stops as soon as every zone has been met
+// and enough nodes have been, so it only crosses the whole ring when a zone
+// is missing from it.
+pub struct ZoneAware;
+
+impl Placement for ZoneAware {
+    fn choose(
+        &self,
+        walk: &mut dyn Iterator<Item = SocketAddr>,
+        zones: &Zones,
+        n: usize,
+    ) -> Vec<SocketAddr> {
+        let zone = |addr: &SocketAddr| zones.get(addr).map_or("", |zone| zone.as_str());
+        let mut unmet: HashSet<&str> = zones.values().map(|zone| zone.as_str()).collect();
+        let mut chosen: Vec<SocketAddr> = Vec::with_capacity(n);
+        let mut skipped = Vec::new();
+        while chosen.len() < n && (!unmet.is_empty() || chosen.len() + skipped.len() < n) {
+            let addr = match walk.next() {
+                Some(addr) => addr,
+                None => break,
+            };
+            unmet.remove(zone(&addr));
+            if chosen.iter().any(|c| zone(c) == zone(&addr)) {
+                skipped.push(addr);
+            } else {
+                chosen.push(addr);
+            }
+        }
+        let missing = n - chosen.len();
+        chosen.extend(skipped.into_iter().take(missing));
+        chosen
+    }
+}
+
+// A ring along with the placement choosing replicas on it
+pub struct Replicas<'a> {
+    pub ring: &'a HashRing<SocketAddr>,
+    pub placement: &'a dyn Placement,
+    pub zones: &'a Zones,
+}
+
+impl Replicas<'_> {
+    // The n replicas of the keys at point
+    pub fn of_point(&self, point: u64, n: usize) -> Vec<SocketAddr> {
+        self.placement
+            .choose(&mut self.ring.walk(point).copied(), self.zones, n)
+    }
+
+    pub fn of_key(&self, key: &[u8], n: usize) -> Vec<SocketAddr> {
+        self.of_point(self.ring.point(key), n)
+    }
+
+    // Splits the ring into ranges like HashRing::ranges, along with the n
+    // replicas of each
+    pub fn ranges(&self, n: usize) -> Vec<(u64, u64, Vec<SocketAddr>)> {
+        self.ring
+            .ranges(1)
+            .into_iter()
+            .map(|(start, end, _)| (start, end, self.of_point(end, n)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn choose(placement: &dyn Placement, walk: &[u16], zones: &Zones, n: usize) -> Vec<u16> {
+        let mut walk = walk.iter().map(|port| addr(*port));
+        placement
+            .choose(&mut walk, zones, n)
+            .into_iter()
+            .map(|addr| addr.port())
+            .collect()
+    }
+
+    #[test]
+    fn test_zone_aware() {
+        let zones: Zones = vec![(1, "a"), (2, "a"), (3, "b"), (4, "b"), (5, "c")]
+            .into_iter()
+            .map(|(port, zone)| (addr(port), zone.to_string()))
+            .collect();
+        assert_eq!(
+            choose(&ZoneAware, &[1, 2, 3, 4, 5], &zones, 3),
+            vec![1, 3, 5]
+        );
+        assert_eq!(choose(&ZoneAware, &[2, 4, 1, 3, 5], &zones, 2), vec![2, 4]);
+
+        // Falls back to ring order with fewer zones than replicas
+        assert_eq!(choose(&ZoneAware, &[1, 2, 3, 4], &zones, 3), vec![1, 3, 2]);
+        assert_eq!(choose(&ZoneAware, &[1, 2], &zones, 3), vec![1, 2]);
+
+        // Nodes without a zone share the empty one
+        assert_eq!(choose(&ZoneAware, &[6, 7, 8], &Zones::new(), 2), vec![6, 7]);
+        assert_eq!(
+            choose(&RingOrder, &[1, 2, 3, 4, 5], &zones, 3),
+            vec![1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn test_zone_aware_stops_walk() {
+        // With no node labelled, the walk stops after n nodes
+        let unlabelled: Zones = (1..=6).map(|port| (addr(port), String::new())).collect();
+        let mut walk = (1..=6).map(addr);
+        let chosen = ZoneAware.choose(&mut walk, &unlabelled, 3);
+        assert_eq!(chosen, vec![addr(1), addr(2), addr(3)]);
+        assert_eq!(walk.next(), Some(addr(4)));
+
+        // With fewer zones than replicas, once every zone has been met
+        let zones: Zones = (1..=6)
+            .map(|port| (addr(port), format!("zone{}", port % 2)))
+            .collect();
+        let mut walk = (1..=6).map(addr);
+        let chosen = ZoneAware.choose(&mut walk, &zones, 3);
+        assert_eq!(chosen, vec![addr(1), addr(2), addr(3)]);
+        assert_eq!(walk.next(), Some(addr(4)));
+    }
+
+    #[test]
+    fn test_replicas_ranges() {
+        let mut ring = HashRing::new(8);
+        for port in 1..=6 {
+            ring.insert(addr(port));
+        }
+        let zones: Zones = (1..=6)
+            .map(|port| (addr(port), format!("zone{}", port % 3)))
+            .collect();
+        let replicas = Replicas {
+            ring: &ring,
+            placement: &ZoneAware,
+            zones: &zones,
+        };
+        for (start, end, owners) in replicas.ranges(3) {
+            let mut owner_zones: Vec<_> = owners.iter().map(|addr| &zones[addr]).collect();
+            owner_zones.sort();
+            owner_zones.dedup();
+            assert_eq!(owner_zones.len(), 3);
+            // Every point of a range has its replicas
+            let mid = end.wrapping_sub(end.wrapping_sub(start) / 2);
+            assert_eq!(replicas.of_point(mid, 3), owners);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use super::placement::Replicas;
+use std::net::SocketAddr;
+
+// A range of the ring a node takes over after a ring change, to be streamed
+// from the nodes that replicated it before
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transfer {
+    // The range (start, end], wrapping past u64::MAX like HashRing::ranges
+    pub start: u64,
+    pub end: u64,
+    pub sources: Vec<SocketAddr>,
+}
+
+// Returns the ranges node replicates on the new ring but not on the old
+// one, each with its replicas on the old ring. Both rings' points split the
+// ring into segments with a single set of replicas on each, and adjacent
+// segments moving from the same sources are merged.
+pub fn gained_ranges(old: &Replicas, new: &Replicas, node: &SocketAddr, n: usize) -> Vec<Transfer> {
+    let mut points: Vec<u64> = old
+        .ring
+        .ranges(1)
+        .into_iter()
+        .chain(new.ring.ranges(1))
+        .map(|(_, end, _)| end)
+        .collect();
+    points.sort_unstable();
+    points.dedup();
+
+    let mut transfers: Vec<Transfer> = Vec::new();
+    for (i, end) in points.iter().enumerate() {
+        let start = points[(i + points.len() - 1) % points.len()];
+        if !new.of_point(*end, n).contains(node) {
+            continue;
+        }
+        let sources = old.of_point(*end, n);
+        if sources.is_empty() || sources.contains(node) {
+            continue;
+        }
+        match transfers.last_mut() {
+            Some(last) if last.end == start && last.sources == sources => last.end = *end,
+            _ => transfers.push(Transfer {
+                start,
+                end: *end,
+                sources,
+            }),
+        }
+    }
+    // The last segment may continue the first one past the end of the ring
+    if transfers.len() > 1 {
+        let last = &transfers[transfers.len() - 1];
+        if last.end == transfers[0].start && last.sources == transfers[0].sources {
+            let last = transfers.pop().unwrap();
+            transfers[0].start = last.start;
+        }
+    }
+    transfers
+}
+
+// Returns the ranges node replicates on the old ring that other nodes take
+// over on the new one, grouped by the node taking them over
+pub fn handed_off_ranges(
+    old: &Replicas,
+    new: &Replicas,
+    node: &SocketAddr,
+    n: usize,
+) -> Vec<(SocketAddr, Vec<Transfer>)> {
+    let mut targets = new.ring.owners(0, usize::MAX);
+    targets.sort();
+    targets
+        .into_iter()
+        .filter(|target| target != node)
+        .map(|target| {
+            let transfers: Vec<_> = gained_ranges(old, new, &target, n)
+                .into_iter()
+                .filter(|t| t.sources.contains(node))
+                .collect();
+            (target, transfers)
+        })
+        .filter(|(_, transfers)| !transfers.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::placement::{RingOrder, Zones};
+    use super::*;
+    use crate::ring::HashRing;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn ring(ports: &[u16]) -> HashRing<SocketAddr> {
+        let mut ring = HashRing::new(8);
+        for port in ports {
+            ring.insert(addr(*port));
+        }
+        ring
+    }
+
+    fn replicas<'a>(ring: &'a HashRing<SocketAddr>, zones: &'a Zones) -> Replicas<'a> {
+        Replicas {
+            ring,
+            placement: &RingOrder,
+            zones,
+        }
+    }
+
+    // Whether point lies within the range (start, end]
+    fn contains(t: &Transfer, point: u64) -> bool {
+        if t.start < t.end {
+            point > t.start && point <= t.end
+        } else {
+            point > t.start || point <= t.end
+        }
+    }
+
+    #[test]
+    fn test_gained_ranges() {
+        let zones = Zones::new();
+        let (old_ring, new_ring) = (ring(&[1, 2, 3, 4]), ring(&[1, 2, 3, 4, 5]));
+        let (old, new) = (replicas(&old_ring, &zones), replicas(&new_ring, &zones));
+        let transfers = gained_ranges(&old, &new, &addr(5), 2);
+        assert!(!transfers.is_empty());
+
+        // Every key the new node replicates is covered by exactly one
+        // transfer from its old replicas
+        for i in 0..1000u32 {
+            let point = new_ring.point(&i.to_be_bytes());
+            let covering: Vec<_> = transfers.iter().filter(|t| contains(t, point)).collect();
+            if new.of_point(point, 2).contains(&addr(5)) {
+                assert_eq!(covering.len(), 1);
+                assert_eq!(covering[0].sources, old.of_point(point, 2));
+            } else {
+                assert!(covering.is_empty());
+            }
+        }
+
+        // Nodes keeping their ranges gain nothing from an insertion
+        assert!(gained_ranges(&old, &new, &addr(1), 2).is_empty());
+        assert!(gained_ranges(&old, &old, &addr(5), 2).is_empty());
+    }
+
+    #[test]
+    fn test_handed_off_ranges() {
+        let zones = Zones::new();
+        let (old_ring, new_ring) = (ring(&[1, 2, 3, 4]), ring(&[1, 2, 3]));
+        let (old, new) = (replicas(&old_ring, &zones), replicas(&new_ring, &zones));
+        let handoffs = handed_off_ranges(&old, &new, &addr(4), 2);
+        assert!(!handoffs.is_empty());
+
+        // Every replica a key of the leaving node gains is handed it
+        for i in 0..1000u32 {
+            let point = new_ring.point(&i.to_be_bytes());
+            let old_owners = old.of_point(point, 2);
+            for owner in new.of_point(point, 2) {
+                let handed: Vec<_> = handoffs
+                    .iter()
+                    .filter(|(target, _)| *target == owner)
+                    .flat_map(|(_, transfers)| transfers)
+                    .filter(|t| contains(t, point))
+                    .collect();
+                if old_owners.contains(&addr(4)) && !old_owners.contains(&owner) {
+                    assert_eq!(handed.len(), 1);
+                } else {
+                    assert!(handed.is_empty());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_gained_ranges_on_removal() {
+        let zones = Zones::new();
+        let (old_ring, new_ring) = (ring(&[1, 2, 3]), ring(&[1, 2]));
+        let (old, new) = (replicas(&old_ring, &zones), replicas(&new_ring, &zones));
+        for node in &[addr(1), addr(2)] {
+            for t in gained_ranges(&old, &new, node, 2) {
+                assert!(!t.sources.contains(node));
+                assert!(t.sources.contains(&addr(3)));
+            }
+        }
+
+        // A node replicating everything gains nothing
+        assert!(gained_ranges(&old, &new, &addr(1), 3).is_empty());
+    }
+}
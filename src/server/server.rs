@@ -1,34 +1,127 @@
+use super::config::{self, Agreement, ConfigFile};
+use super::failure_detector::FailureDetector;
+use super::hints::HintStore;
+use super::hlc::{self, Hlc};
+use super::membership::Membership;
+use super::merkle::{self, MerkleTree};
+use super::metrics::Metrics;
+use super::peers::{Health, PeerConnections};
+use super::rebalance::{self, Transfer};
+use super::vector_clock;
 use crate::error::{Error, Result};
 use crate::proto;
-use crate::proto::peer_service_client::PeerServiceClient;
-use crate::proto::ClusterConfig;
+use crate::proto::{
+    ClusterConfig, ConflictResolution, GossipType, NodeStatus, ReplicaPlacement, RingHash,
+};
 use crate::ring::HashRing;
 use crate::store;
-use crate::{Key, Version};
-use log::trace;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use crate::{Expiry, Key, Version};
+use log::{info, trace, warn};
+use rand::seq::SliceRandom;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::path::{Path, PathBuf};
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use structopt::StructOpt;
+use tokio::sync::{mpsc, Notify};
 use tonic;
 
 #[derive(Debug, Clone, StructOpt)]
 pub struct Config {
-    #[structopt(short, long, default_value = "")]
+    #[structopt(short, long, default_value = "data")]
     pub folder: PathBuf,
 
+    // One of "mem", "bitcask" or "lsm"
+    #[structopt(short, long, default_value = "bitcask")]
+    pub engine: store::Engine,
+
+    // One of "always", "never" or "batched:<interval in ms>"
+    #[structopt(long, default_value = "always")]
+    pub wal_sync: store::SyncPolicy,
+
     #[structopt(short, long, default_value = "127.0.0.1:8080")]
     pub address: SocketAddr,
 
     #[structopt(short, long, use_delimiter = true)]
     pub seed_nodes: Vec<SocketAddr>,
 
+    // Failure domain of the node, such as its rack or availability zone
+    #[structopt(long, default_value = "")]
+    pub zone: String,
+
+    // Share of the keys the node takes relative to other nodes, such as 4
+    // for a node with four times the disk of a node of weight 1. At most
+    // membership::MAX_WEIGHT.
+    #[structopt(long, default_value = "1")]
+    pub weight: u32,
+
+    // TOML or JSON file of the cluster config. Overrides the [cluster] table
+    // of the config file.
     #[structopt(short, long, parse(try_from_str = parse_cluster_config), default_value = "")]
     pub cluster_config: ClusterConfig,
+
+    #[structopt(long, default_value = "1000")]
+    pub gossip_interval_ms: u64,
+
+    #[structopt(long, default_value = "500")]
+    pub heartbeat_interval_ms: u64,
+
+    // Failure detector suspicion level above which a node is unavailable
+    #[structopt(long, default_value = "8")]
+    pub phi_threshold: f64,
+
+    // Hints older than this are dropped without being delivered
+    #[structopt(long, default_value = "10800")]
+    pub hint_ttl_secs: u64,
+
+    // Total size of stored hints above which new hints are dropped
+    #[structopt(long, default_value = "67108864")]
+    pub max_hint_bytes: u64,
+
+    // Interval between anti-entropy repairs with a random co-replica
+    #[structopt(long, default_value = "60000")]
+    pub anti_entropy_interval_ms: u64,
+
+    // Age after which tombstones are purged. Must exceed the hint TTL and
+    // anti-entropy interval so every replica sees a delete before its
+    // tombstone is gone.
+    #[structopt(long, default_value = "864000")]
+    pub tombstone_grace_secs: u64,
+
+    // Time a coordinator waits for replicas before failing a request, unless
+    // the client's deadline is sooner
+    #[structopt(long, default_value = "5000")]
+    pub request_timeout_ms: u64,
+
+    // How far ahead of the local clock versions assigned by clients, or
+    // stamped by peers whose clocks run ahead, may be. Later versions are
+    // rejected, as the clock would carry them into every later stamp.
+    #[structopt(long, default_value = "60000")]
+    pub max_clock_skew_ms: u64,
+
+    // TOML or JSON file of settings. Flags given on the command line
+    // override it.
+    #[structopt(long)]
+    pub config: Option<PathBuf>,
 }
 impl Config {
     pub fn parse_from_args() -> Self {
-        Self::from_args()
+        let matches = Self::clap().get_matches();
+        let mut config = Self::from_clap(&matches);
+        if let Some(path) = config.config.clone() {
+            let result = config::load::<ConfigFile>(&path)
+                .and_then(|file| file.apply(&mut config, |name| matches.occurrences_of(name) > 0));
+            if let Err(e) = result {
+                structopt::clap::Error::with_description(
+                    &e,
+                    structopt::clap::ErrorKind::InvalidValue,
+                )
+                .exit();
+            }
+        }
+        config
     }
 }
 
@@ -36,44 +129,151 @@ fn parse_cluster_config(src: &str) -> std::result::Result<ClusterConfig, String>
     if src.is_empty() {
         return Ok(default_cluster_config());
     }
-    // TODO: Load from file
-    unimplemented!();
+    config::load_cluster_config(Path::new(src), default_cluster_config())
 }
 
-fn default_cluster_config() -> ClusterConfig {
+pub(super) fn default_cluster_config() -> ClusterConfig {
     ClusterConfig {
         name: "default".to_string(),
         replication_factor: 3,
         read_replicas: 2,
         write_replicas: 2,
         ring_replicas: 8,
+        read_repair_chance: 0.1,
+        conflict_resolution: ConflictResolution::Siblings as i32,
+        epoch: 0,
+        replica_placement: ReplicaPlacement::ZoneAware as i32,
+        ring_hash: RingHash::Xxhash64 as i32,
     }
 }
 
 pub struct Server {
     config: Config,
-    ring: HashRing<SocketAddr>,
+    // Starts as config.cluster_config and is replaced when a peer has one
+    // with a newer epoch
+    cluster_config: RwLock<Arc<ClusterConfig>>,
+    membership: RwLock<Membership>,
+    failure_detector: Mutex<FailureDetector>,
+    peers: PeerConnections,
     store: Box<dyn store::Store>,
+    hints: HintStore,
+    // Covers every key in store. Locked across each mutation of store so
+    // the two are updated in the same order.
+    merkle: Mutex<MerkleTree>,
+    metrics: Metrics,
+    // Stamps coordinated writes. Its stamps also serve as the local node's
+    // vector clock counters, so counters aren't reused across restarts.
+    hlc: Hlc,
+    // Notified once the local node has left the network
+    shutdown: Notify,
 }
 
-// TODO: Parallelize put/get/delete
+// A key's siblings and the version of its latest write. A tombstone has no
+// siblings and a missing key has version -1 too.
+type Versioned = (Vec<proto::Sibling>, Version);
+
+// Entries scanned from the store, along with the key the next page starts
+// at or None once the end of the store is reached
+type Page<T> = (Vec<T>, Option<Key>);
+
+// Responses of the replicas a request was fanned out to, in the order they
+// arrive
+type Responses<T> = mpsc::UnboundedReceiver<(SocketAddr, Result<T>)>;
+
+const HINT_DELIVERY_INTERVAL: Duration = Duration::from_secs(1);
+const TOMBSTONE_GC_INTERVAL: Duration = Duration::from_secs(60);
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(5);
+// Store entries read per page while scanning the whole store
+const SCAN_PAGE_SIZE: usize = 1000;
+// Entries returned per scan page unless the request sets a limit, and the
+// most it may set
+const DEFAULT_SCAN_LIMIT: usize = 100;
+const MAX_SCAN_LIMIT: usize = 1000;
+// Consecutive failures to reach a peer after which it's unavailable
+// regardless of its suspicion level
+const UNREACHABLE_FAILURES: u32 = 3;
+// Merkle tree leaves whose keys are requested at once during repair
+const MERKLE_KEYS_BATCH_SIZE: usize = 256;
+const REBALANCE_INTERVAL: Duration = Duration::from_secs(1);
+// Entries of a streamed range buffered ahead of the receiver
+const STREAM_BUFFER_SIZE: usize = 256;
+
 impl Server {
-    pub fn new(config: Config) -> Self {
-        Self {
-            ring: Self::make_ring(&config),
-            config: config,
-            store: Box::new(store::MemStore::new()),
+    pub fn new(config: Config) -> Result<Self> {
+        config::validate_cluster_config(&config.cluster_config).map_err(Error::InvalidArgument)?;
+        config::validate_weight(config.weight).map_err(Error::InvalidArgument)?;
+        if config.tombstone_grace_secs <= config.hint_ttl_secs
+            || config.tombstone_grace_secs.saturating_mul(1000) <= config.anti_entropy_interval_ms
+        {
+            return Err(Error::InvalidArgument(
+                "tombstone grace period must exceed the hint TTL and anti-entropy interval"
+                    .to_string(),
+            ));
         }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system time before unix epoch");
+        let generation = now.as_millis() as u64;
+        let store = store::open(config.engine, &config.folder, config.wal_sync)?;
+        // A new node joining through seeds has ranges to take over
+        let joining = config.seed_nodes.iter().any(|seed| *seed != config.address)
+            && store.scan(&Key(Vec::new()), 1)?.is_empty();
+        let status = if joining {
+            NodeStatus::Joining
+        } else {
+            NodeStatus::Online
+        };
+        let membership = Membership::new(
+            config.address,
+            generation,
+            status,
+            config.zone.clone(),
+            config.weight,
+            &config.cluster_config,
+        );
+        let merkle = build_merkle_tree(&*store, &membership)?;
+        Ok(Self {
+            cluster_config: RwLock::new(Arc::new(config.cluster_config.clone())),
+            membership: RwLock::new(membership),
+            failure_detector: Mutex::new(FailureDetector::new(Duration::from_millis(
+                config.heartbeat_interval_ms,
+            ))),
+            peers: PeerConnections::new(),
+            store,
+            merkle: Mutex::new(merkle),
+            metrics: Metrics::default(),
+            hlc: Hlc::new(),
+            hints: HintStore::open(
+                config.folder.join("hints"),
+                Duration::from_secs(config.hint_ttl_secs),
+                config.max_hint_bytes,
+            )?,
+            shutdown: Notify::new(),
+            config: config,
+        })
     }
 
-    // TODO: Fetch peers from network
-    fn make_ring(config: &Config) -> HashRing<SocketAddr> {
-        let mut ring = HashRing::new(config.cluster_config.ring_replicas);
-        ring.insert(config.address.clone());
-        for peer in &config.seed_nodes {
-            ring.insert(peer.clone());
-        }
-        ring
+    // Resolves once the server should stop serving
+    pub async fn stopped(&self) {
+        self.shutdown.notified().await
+    }
+
+    // Starts background tasks. Must be called from within a tokio runtime.
+    pub fn start(self: &Arc<Self>) {
+        let server = self.clone();
+        tokio::spawn(async move { server.run_gossip().await });
+        let server = self.clone();
+        tokio::spawn(async move { server.run_heartbeats().await });
+        let server = self.clone();
+        tokio::spawn(async move { server.run_hint_delivery().await });
+        let server = self.clone();
+        tokio::spawn(async move { server.run_anti_entropy().await });
+        let server = self.clone();
+        tokio::spawn(async move { server.run_tombstone_gc().await });
+        let server = self.clone();
+        tokio::spawn(async move { server.run_expiry().await });
+        let server = self.clone();
+        tokio::spawn(async move { server.run_rebalance().await });
     }
 
     pub async fn describe_cluster(
@@ -81,130 +281,760 @@ impl Server {
         req: proto::DescribeClusterRequest,
     ) -> Result<proto::DescribeClusterResponse> {
         Ok(proto::DescribeClusterResponse {
-            cluster_config: Some(self.config.cluster_config.clone()),
+            cluster_config: Some((*self.cluster_config()).clone()),
         })
     }
 
-    // TODO: Assign version if not given
-    // TODO: Retry failed puts/hinted handoff
-    pub async fn put(&self, req: proto::PutRequest) -> Result<proto::PutResponse> {
-        self.check_key(&req.key)?;
+    fn cluster_config(&self) -> Arc<ClusterConfig> {
+        self.cluster_config.read().unwrap().clone()
+    }
 
-        let replicas = self.find_replicas(&req.key)?;
+    // Checks a peer's cluster config against the local one, adopting it if
+    // it has a newer epoch
+    fn agree_cluster_config(&self, remote: &ClusterConfig) -> Result<()> {
+        let old = {
+            let mut local = self.cluster_config.write().unwrap();
+            match config::compare_cluster_config(&local, remote) {
+                Agreement::Agree => return Ok(()),
+                Agreement::Mismatch(msg) => return Err(Error::ClusterMismatch(msg)),
+                Agreement::Adopt => {}
+            }
+            info!(
+                "adopting cluster config epoch {} (was {})",
+                remote.epoch, local.epoch
+            );
+            std::mem::replace(&mut *local, Arc::new(remote.clone()))
+        };
+        if old.ring_replicas != remote.ring_replicas
+            || old.replica_placement != remote.replica_placement
+        {
+            self.membership.write().unwrap().set_cluster_config(remote);
+        }
+        Ok(())
+    }
+
+    pub async fn put(
+        self: &Arc<Self>,
+        req: proto::PutRequest,
+        timeout: Option<Duration>,
+    ) -> Result<proto::PutResponse> {
+        let req = self.replica_put(req)?;
+        let (replicas, suspected, needed) = self.find_write_replicas(&req.key)?;
+
+        let put = req.clone();
+        let mut responses = self.fan_out(&replicas, self.deadline(timeout), move |server, addr| {
+            let put = put.clone();
+            async move { server.remote_put(addr, put).await }
+        });
+        let results = gather(&mut responses, |results| {
+            quorate(results, replicas.len(), needed)
+        })
+        .await;
 
-        let mut results = Vec::new();
-        for addr in replicas {
-            results.push(self.remote_put(addr, req.clone()).await);
+        let resp = self.finish_put(req.clone(), results, suspected, needed);
+        if let Some(hint) = put_hint(&req, resp.is_ok()) {
+            self.hint_stragglers(responses, hint);
         }
+        resp
+    }
 
-        let (successes, failures): (Vec<_>, Vec<_>) =
-            results.iter().partition(|result| result.is_ok());
+    // Stamps a client put, returning the request its replicas apply
+    fn replica_put(&self, req: proto::PutRequest) -> Result<proto::PutRequest> {
+        self.check_key(&req.key)?;
 
-        for result in failures {
-            trace!("put error: {:?}", result);
+        self.check_version(req.version)?;
+        // A conditional put must supersede the version it expects
+        if let Some(expected) = expected_version(&req.condition) {
+            self.check_version(expected)?;
+            self.hlc.observe(expected);
         }
+        // Later puts must supersede a version the client assigned
+        let stamp = self.hlc.now_after(req.version);
+        let version = if req.version > 0 { req.version } else { stamp };
+        let clock = match self.cluster_config().conflict_resolution() {
+            // Stamp the value with the client's causal context advanced at
+            // the local node, superseding every sibling the client has seen
+            ConflictResolution::Siblings => {
+                let context = vector_clock::decode_clock(&req.context)?;
+                let node = self.config.address.to_string();
+                Some(vector_clock::advance(&context, &node, stamp as u64))
+            }
+            ConflictResolution::LastWriteWins => None,
+        };
+        let expires_at = match req.ttl_secs {
+            0 => 0,
+            ttl_secs => ttl_secs
+                .checked_mul(1000)
+                .and_then(|ttl| hlc::millis(stamp).checked_add(ttl))
+                .filter(|expires_at| *expires_at <= hlc::millis(Version::MAX))
+                .ok_or_else(|| Error::InvalidArgument(format!("ttl too long: {}s", ttl_secs)))?,
+        };
+        let sibling = proto::Sibling {
+            value: req.value,
+            clock,
+            expires_at,
+        };
+        Ok(proto::PutRequest {
+            key: req.key,
+            version,
+            siblings: vec![sibling],
+            condition: req.condition,
+            ..Default::default()
+        })
+    }
 
-        let write_replicas = self.config.cluster_config.write_replicas as usize;
-        if successes.len() < write_replicas {
+    // Decides the outcome of a put from the results of its replicas, needing
+    // some number of them to succeed, and hints those that missed it
+    fn finish_put(
+        &self,
+        req: proto::PutRequest,
+        results: Vec<(SocketAddr, Result<proto::PutResponse>)>,
+        mut missed: Vec<SocketAddr>,
+        needed: usize,
+    ) -> Result<proto::PutResponse> {
+        let mut successes = 0;
+        let mut conflicts = 0;
+        for (addr, result) in results {
+            match result {
+                Ok(_) => successes += 1,
+                Err(e) if is_version_conflict(&e) => conflicts += 1,
+                Err(e) => {
+                    trace!("put error: {:?}", e);
+                    missed.push(addr);
+                }
+            }
+        }
+
+        let version = req.version;
+        let succeeded = successes >= needed;
+        if let Some(hint) = put_hint(&req, succeeded) {
+            for addr in missed {
+                self.add_hint(addr, hint.clone());
+            }
+        }
+
+        if !succeeded {
+            if conflicts > 0 {
+                return Err(Error::VersionConflict);
+            }
             return Err(Error::TooFewReplicas);
         }
 
-        Ok(proto::PutResponse { version: -1 })
+        Ok(proto::PutResponse { version })
+    }
+
+    pub async fn get(
+        self: &Arc<Self>,
+        req: proto::GetRequest,
+        timeout: Option<Duration>,
+    ) -> Result<proto::GetResponse> {
+        self.check_key(&req.key)?;
+
+        let (successes, resolved) = self.quorum_get(&req.key, self.deadline(timeout)).await?;
+        Ok(self.finish_get(req.key, &successes, resolved))
+    }
+
+    // Builds the response to a get from the copy its replicas resolved to,
+    // repairing those that are stale by chance
+    fn finish_get(
+        self: &Arc<Self>,
+        key: Vec<u8>,
+        successes: &[(SocketAddr, proto::GetResponse)],
+        resolved: Versioned,
+    ) -> proto::GetResponse {
+        if resolved.1 >= 0 && rand::random::<f32>() < self.cluster_config().read_repair_chance {
+            let stale: Vec<_> = successes
+                .iter()
+                .filter(|(_, r)| r.siblings != resolved.0 || r.version != resolved.1)
+                .map(|(addr, _)| *addr)
+                .collect();
+            self.read_repair(key, &resolved, stale);
+        }
+
+        self.client_response(resolved)
     }
 
-    pub async fn get(&self, req: proto::GetRequest) -> Result<proto::GetResponse> {
+    pub async fn delete(
+        self: &Arc<Self>,
+        req: proto::DeleteRequest,
+        timeout: Option<Duration>,
+    ) -> Result<proto::DeleteResponse> {
         self.check_key(&req.key)?;
 
-        let replicas = self.find_replicas(&req.key)?;
+        let req = proto::DeleteRequest {
+            version: self.hlc.now(),
+            ..req
+        };
+        self.replicate_delete(req, self.deadline(timeout)).await?;
 
-        let mut results = Vec::new();
-        for addr in replicas {
-            results.push(self.remote_get(addr, req.clone()).await);
+        // TODO: return val
+        Ok(proto::DeleteResponse { value: Vec::new() })
+    }
+
+    // Gets each key like get, sending one request per replica for all the
+    // keys it replicates
+    pub async fn batch_get(
+        self: &Arc<Self>,
+        req: proto::BatchGetRequest,
+        timeout: Option<Duration>,
+    ) -> Result<proto::BatchGetResponse> {
+        let deadline = self.deadline(timeout);
+        let keys = req.keys;
+        let mut reads: Vec<Result<Vec<_>>> = Vec::with_capacity(keys.len());
+        let mut totals = Vec::with_capacity(keys.len());
+        let mut batches: HashMap<SocketAddr, Vec<usize>> = HashMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            match self.check_key(key).and_then(|_| self.find_replicas(key)) {
+                Ok((replicas, _)) => {
+                    totals.push(replicas.len());
+                    for addr in replicas {
+                        batches.entry(addr).or_default().push(i);
+                    }
+                    reads.push(Ok(Vec::new()));
+                }
+                Err(e) => {
+                    totals.push(0);
+                    reads.push(Err(e));
+                }
+            }
         }
 
-        let (successes, failures): (Vec<_>, Vec<_>) =
-            results.iter().partition(|result| result.is_ok());
+        let requests: HashMap<_, _> = batches
+            .iter()
+            .map(|(addr, indices)| {
+                let keys = indices.iter().map(|i| keys[*i].clone()).collect();
+                (*addr, proto::BatchGetRequest { keys })
+            })
+            .collect();
+        let replicas: Vec<_> = batches.keys().copied().collect();
+        let mut responses = self.fan_out(&replicas, deadline, move |server, addr| {
+            let batch = requests[&addr].clone();
+            async move { Ok(server.remote_batch_get(addr, batch).await) }
+        });
+        let needed = vec![self.cluster_config().read_replicas as usize; keys.len()];
+        let results = gather(&mut responses, |results| {
+            batch_quorate(results, &batches, &totals, &needed)
+        })
+        .await;
 
-        for result in failures {
-            trace!("get error: {:?}", result);
+        for (addr, result) in results {
+            let indices = &batches[&addr];
+            let results = result.unwrap_or_else(|e| batch_failure(e, indices.len()));
+            for (i, result) in indices.iter().zip(results) {
+                if let Ok(read) = &mut reads[*i] {
+                    read.push((addr, result));
+                }
+            }
         }
 
-        // Find the most frequent version
-        let mut version_counts: HashMap<Version, i32> = HashMap::new();
-        for result in &successes {
-            let count = version_counts
-                .entry(result.as_ref().unwrap().version)
-                .or_insert(0);
-            *count += 1;
+        let results = keys
+            .into_iter()
+            .zip(reads)
+            .map(|(key, read)| {
+                let result = read
+                    .and_then(|read| self.resolve_reads(read))
+                    .map(|(successes, resolved)| self.finish_get(key, &successes, resolved));
+                get_result(result)
+            })
+            .collect();
+        Ok(proto::BatchGetResponse { results })
+    }
+
+    // Puts each key like put, sending one request per replica for all the
+    // keys it replicates
+    pub async fn batch_put(
+        self: &Arc<Self>,
+        req: proto::BatchPutRequest,
+        timeout: Option<Duration>,
+    ) -> Result<proto::BatchPutResponse> {
+        let deadline = self.deadline(timeout);
+        let mut writes = Vec::with_capacity(req.puts.len());
+        let mut totals = Vec::with_capacity(req.puts.len());
+        let mut needed = Vec::with_capacity(req.puts.len());
+        let mut batches: HashMap<SocketAddr, Vec<usize>> = HashMap::new();
+        for (i, put) in req.puts.into_iter().enumerate() {
+            let write = self.replica_put(put).and_then(|put| {
+                let (replicas, suspected, needed) = self.find_write_replicas(&put.key)?;
+                Ok((put, replicas, suspected, needed))
+            });
+            match write {
+                Ok((put, replicas, suspected, n)) => {
+                    totals.push(replicas.len());
+                    needed.push(n);
+                    for addr in replicas {
+                        batches.entry(addr).or_default().push(i);
+                    }
+                    writes.push(Ok((put, Vec::new(), suspected)));
+                }
+                Err(e) => {
+                    totals.push(0);
+                    needed.push(0);
+                    writes.push(Err(e));
+                }
+            }
         }
-        let (version, count) = version_counts
+
+        let requests: HashMap<_, _> = batches
             .iter()
-            .max_by_key(|(version, count)| (*count, *version))
-            .map(|(version, count)| (*version, *count))
-            .unwrap_or((-1, -1));
+            .map(|(addr, indices)| {
+                let puts = indices
+                    .iter()
+                    .filter_map(|i| writes[*i].as_ref().ok())
+                    .map(|(put, _, _)| put.clone())
+                    .collect();
+                (*addr, proto::BatchPutRequest { puts })
+            })
+            .collect();
+        let replicas: Vec<_> = batches.keys().copied().collect();
+        let mut responses = self.fan_out(&replicas, deadline, move |server, addr| {
+            let batch = requests[&addr].clone();
+            async move { Ok(server.remote_batch_put(addr, batch).await) }
+        });
+        let results = gather(&mut responses, |results| {
+            batch_quorate(results, &batches, &totals, &needed)
+        })
+        .await;
+
+        for (addr, result) in results {
+            let indices = &batches[&addr];
+            let results = result.unwrap_or_else(|e| batch_failure(e, indices.len()));
+            for (i, result) in indices.iter().zip(results) {
+                if let Ok((_, results, _)) = &mut writes[*i] {
+                    results.push((addr, result));
+                }
+            }
+        }
+
+        let mut hints = Vec::with_capacity(writes.len());
+        let results = writes
+            .into_iter()
+            .zip(needed)
+            .map(|(write, needed)| match write {
+                Ok((put, results, suspected)) => {
+                    let result = self.finish_put(put.clone(), results, suspected, needed);
+                    hints.push(put_hint(&put, result.is_ok()));
+                    put_result(result)
+                }
+                Err(e) => {
+                    hints.push(None);
+                    put_result(Err(e))
+                }
+            })
+            .collect();
+        self.hint_batch_stragglers(responses, batches, hints);
+        Ok(proto::BatchPutResponse { results })
+    }
+
+    // Reads key from its replicas, returning the responses and the copy
+    // they resolve to. Fails unless enough replicas respond.
+    async fn quorum_get(
+        self: &Arc<Self>,
+        key: &[u8],
+        deadline: Instant,
+    ) -> Result<(Vec<(SocketAddr, proto::GetResponse)>, Versioned)> {
+        let (replicas, _) = self.find_replicas(key)?;
+
+        let read_replicas = self.cluster_config().read_replicas as usize;
+        let req = proto::GetRequest { key: key.to_vec() };
+        let mut responses = self.fan_out(&replicas, deadline, move |server, addr| {
+            let req = req.clone();
+            async move { server.remote_get(addr, req).await }
+        });
+        let results = gather(&mut responses, |results| {
+            quorate(results, replicas.len(), read_replicas)
+        })
+        .await;
+        self.resolve_reads(results)
+    }
+
+    // Resolves the copies of a key read from its replicas. Fails unless
+    // enough replicas responded.
+    fn resolve_reads(
+        &self,
+        results: Vec<(SocketAddr, Result<proto::GetResponse>)>,
+    ) -> Result<(Vec<(SocketAddr, proto::GetResponse)>, Versioned)> {
+        let mut successes = Vec::new();
+        for (addr, result) in results {
+            match result {
+                Ok(response) => successes.push((addr, response)),
+                Err(e) => trace!("get error: {:?}", e),
+            }
+        }
 
-        let read_replicas = self.config.cluster_config.write_replicas;
-        if count < read_replicas {
+        let read_replicas = self.cluster_config().read_replicas as usize;
+        if successes.len() < read_replicas {
             return Err(Error::TooFewReplicas);
         }
 
-        let response = successes
+        let resolved = successes
             .iter()
-            .map(|result| result.as_ref().unwrap())
-            .find(|response| response.version == version)
-            .expect("no matching response");
-
-        Ok(response.clone())
+            .fold((Vec::new(), -1), |resolved, (_, response)| {
+                self.resolve(resolved, (response.siblings.clone(), response.version))
+            });
+        Ok((successes, resolved))
     }
 
-    // TODO: Retry failed deletes
-    pub async fn delete(&self, req: proto::DeleteRequest) -> Result<proto::DeleteResponse> {
-        self.check_key(&req.key)?;
+    // Sends a stamped delete to the replicas of its key, hinting those that
+    // miss it
+    async fn replicate_delete(
+        self: &Arc<Self>,
+        req: proto::DeleteRequest,
+        deadline: Instant,
+    ) -> Result<()> {
+        let (replicas, suspected, needed) = self.find_write_replicas(&req.key)?;
 
-        let replicas = self.find_replicas(&req.key)?;
+        let delete = req.clone();
+        let mut responses = self.fan_out(&replicas, deadline, move |server, addr| {
+            let delete = delete.clone();
+            async move { server.remote_delete(addr, delete).await }
+        });
+        let results = gather(&mut responses, |results| {
+            quorate(results, replicas.len(), needed)
+        })
+        .await;
 
-        let mut results = Vec::new();
-        for addr in replicas {
-            results.push(self.remote_delete(addr, req.clone()).await);
+        let hint = proto::hint::Mutation::Delete(req);
+        let mut successes = 0;
+        for (addr, result) in results {
+            match result {
+                Ok(_) => successes += 1,
+                Err(e) => {
+                    trace!("delete error: {:?}", e);
+                    self.add_hint(addr, hint.clone());
+                }
+            }
         }
+        for addr in suspected {
+            self.add_hint(addr, hint.clone());
+        }
+        self.hint_stragglers(responses, hint);
 
-        let (successes, failures): (Vec<_>, Vec<_>) =
-            results.iter().partition(|result| result.is_ok());
+        if successes < needed {
+            return Err(Error::TooFewReplicas);
+        }
+        Ok(())
+    }
 
-        for result in failures {
-            trace!("delete error: {:?}", result);
+    // The time by which a request must finish, its client's deadline if
+    // sooner than the request timeout
+    fn deadline(&self, timeout: Option<Duration>) -> Instant {
+        let request_timeout = Duration::from_millis(self.config.request_timeout_ms);
+        Instant::now() + timeout.map_or(request_timeout, |t| t.min(request_timeout))
+    }
+
+    // Calls each replica concurrently, giving up on those yet to respond by
+    // the deadline
+    fn fan_out<T, F, Fut>(
+        self: &Arc<Self>,
+        replicas: &[SocketAddr],
+        deadline: Instant,
+        call: F,
+    ) -> Responses<T>
+    where
+        F: Fn(Arc<Self>, SocketAddr) -> Fut,
+        Fut: Future<Output = Result<T>> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = mpsc::unbounded_channel();
+        for addr in replicas.iter().copied() {
+            let tx = tx.clone();
+            let deadline = tokio::time::Instant::from_std(deadline);
+            let call = tokio::time::timeout_at(deadline, call(self.clone(), addr));
+            tokio::spawn(async move {
+                let result = call.await.unwrap_or(Err(Error::Timeout));
+                // Ignored once the request no longer waits on stragglers
+                let _ = tx.send((addr, result));
+            });
         }
+        rx
+    }
 
-        let write_replicas = self.config.cluster_config.write_replicas as usize;
-        if successes.len() < write_replicas {
+    // Hints the replicas still to respond to a write if they miss it
+    fn hint_stragglers<T: Send + 'static>(
+        self: &Arc<Self>,
+        mut responses: Responses<T>,
+        hint: proto::hint::Mutation,
+    ) {
+        let server = self.clone();
+        tokio::spawn(async move {
+            while let Some((addr, result)) = responses.recv().await {
+                match result {
+                    Err(e) if !is_version_conflict(&e) => {
+                        trace!("write error: {:?}", e);
+                        server.add_hint(addr, hint.clone());
+                    }
+                    _ => (),
+                }
+            }
+        });
+    }
+
+    // Hints the replicas still to respond to a batch put if they miss any
+    // of its hinted puts
+    fn hint_batch_stragglers(
+        self: &Arc<Self>,
+        mut responses: Responses<Vec<Result<proto::PutResponse>>>,
+        batches: HashMap<SocketAddr, Vec<usize>>,
+        hints: Vec<Option<proto::hint::Mutation>>,
+    ) {
+        let server = self.clone();
+        tokio::spawn(async move {
+            while let Some((addr, result)) = responses.recv().await {
+                let indices = &batches[&addr];
+                let results = result.unwrap_or_else(|e| batch_failure(e, indices.len()));
+                for (i, result) in indices.iter().zip(results) {
+                    match (result, &hints[*i]) {
+                        (Err(e), Some(hint)) if !is_version_conflict(&e) => {
+                            trace!("write error: {:?}", e);
+                            server.add_hint(addr, hint.clone());
+                        }
+                        _ => (),
+                    }
+                }
+            }
+        });
+    }
+
+    // Scans every node for the keys in range, resolving each key's copies
+    // from the replicas responsible for it. Nodes stopping at the limit
+    // have only been scanned up to their last key, so the page ends there.
+    pub async fn scan(
+        self: &Arc<Self>,
+        req: proto::ScanRequest,
+        timeout: Option<Duration>,
+    ) -> Result<proto::ScanResponse> {
+        let (start, end) = scan_bounds(&req)?;
+        let limit = scan_limit(&req);
+        if !end.is_empty() && start >= end {
+            return Ok(proto::ScanResponse::default());
+        }
+
+        let deadline = self.deadline(timeout);
+        let replication_factor = self.cluster_config().replication_factor as usize;
+        let (ranges, available, suspected) = {
+            let membership = self.membership.read().unwrap();
+            let ranges = membership.replicas().ranges(replication_factor);
+            let mut nodes: Vec<_> = ranges
+                .iter()
+                .flat_map(|(_, _, owners)| owners.iter().copied())
+                .collect();
+            nodes.sort();
+            nodes.dedup();
+            let (available, suspected): (Vec<_>, Vec<_>) = nodes
+                .into_iter()
+                .partition(|addr| membership.is_available(addr));
+            (ranges, available, suspected)
+        };
+        if ranges
+            .iter()
+            .any(|(_, _, owners)| owners.len() < replication_factor)
+        {
             return Err(Error::TooFewReplicas);
         }
 
-        // TODO: return val
-        Ok(proto::DeleteResponse { value: Vec::new() })
+        let direct = proto::ScanRequest {
+            start,
+            end,
+            limit: limit as u32,
+            ..Default::default()
+        };
+        // Every portion of the ring must be covered by enough replicas
+        let read_replicas = self.cluster_config().read_replicas as usize;
+        let responded = |owners: &[SocketAddr], results: &[(SocketAddr, Result<_>)]| {
+            owners
+                .iter()
+                .filter(|owner| {
+                    results
+                        .iter()
+                        .any(|(addr, result)| addr == *owner && result.is_ok())
+                })
+                .count()
+        };
+        let covered = |results: &[(SocketAddr, Result<_>)]| {
+            ranges
+                .iter()
+                .all(|(_, _, owners)| responded(owners, results) >= read_replicas)
+        };
+
+        // Reads the available nodes concurrently, then falls back to the
+        // suspected replicas of the portions they leave uncovered
+        let scan = |nodes: &[SocketAddr]| {
+            let direct = direct.clone();
+            self.fan_out(nodes, deadline, move |server, addr| {
+                let req = direct.clone();
+                async move { server.remote_scan(addr, req).await }
+            })
+        };
+        let mut results = gather(&mut scan(&available), |results| {
+            results.len() == available.len() || covered(results)
+        })
+        .await;
+        if !covered(&results) {
+            let fallback: Vec<_> = suspected
+                .into_iter()
+                .filter(|addr| {
+                    ranges.iter().any(|(_, _, owners)| {
+                        owners.contains(addr) && responded(owners, &results) < read_replicas
+                    })
+                })
+                .collect();
+            let fallback_results = gather(&mut scan(&fallback), |results| {
+                results.len() == fallback.len()
+            })
+            .await;
+            results.extend(fallback_results);
+        }
+        if !covered(&results) {
+            return Err(Error::TooFewReplicas);
+        }
+        let responses: Vec<_> = results
+            .into_iter()
+            .filter_map(|(addr, result)| match result {
+                Ok(response) => Some((addr, response)),
+                Err(e) => {
+                    trace!("scan error: {:?}", e);
+                    None
+                }
+            })
+            .collect();
+
+        let scanned_to = responses
+            .iter()
+            .map(|(_, response)| &response.continuation)
+            .filter(|last| !last.is_empty())
+            .min()
+            .cloned();
+
+        let mut merged: BTreeMap<Vec<u8>, Versioned> = BTreeMap::new();
+        {
+            let membership = self.membership.read().unwrap();
+            for (addr, response) in responses {
+                for entry in response.entries {
+                    if scanned_to.as_ref().is_some_and(|to| entry.key > *to) {
+                        continue;
+                    }
+                    // Skip copies left on nodes no longer responsible for
+                    // the key
+                    let owners = membership.replicas().of_key(&entry.key, replication_factor);
+                    if !owners.contains(&addr) {
+                        continue;
+                    }
+                    let copy = merged.remove(&entry.key).unwrap_or((Vec::new(), -1));
+                    let resolved = self.resolve(copy, (entry.siblings, entry.version));
+                    merged.insert(entry.key, resolved);
+                }
+            }
+        }
+
+        // Deleted and expired keys are skipped
+        let mut entries = Vec::new();
+        for (key, resolved) in merged {
+            let resp = self.client_response(resolved);
+            if resp.siblings.is_empty() {
+                continue;
+            }
+            entries.push(scan_entry(key, resp));
+            if entries.len() == limit {
+                let continuation = entries.last().map(|e| e.key.clone()).unwrap_or_default();
+                return Ok(proto::ScanResponse {
+                    entries,
+                    continuation,
+                });
+            }
+        }
+        Ok(proto::ScanResponse {
+            entries,
+            continuation: scanned_to.unwrap_or_default(),
+        })
     }
 
+    pub async fn get_metrics(
+        &self,
+        _req: proto::GetMetricsRequest,
+    ) -> Result<proto::GetMetricsResponse> {
+        Ok(self.metrics.snapshot())
+    }
+
+    // Resolves the given siblings and version against those stored for key.
+    // Conditional puts replace them instead, if their version matches.
     pub async fn direct_put(&self, req: proto::PutRequest) -> Result<proto::PutResponse> {
         self.check_key(&req.key)?;
-        self.store
-            .put(Key(req.key), req.value)
-            .map(|version| proto::PutResponse { version })
+        if req.siblings.is_empty() {
+            return Err(Error::InvalidArgument("missing siblings".to_string()));
+        }
+        self.check_version(req.version)?;
+        self.hlc.observe(req.version);
+        let expected = expected_version(&req.condition);
+        let incoming = (req.siblings, req.version);
+        let (_, (_, version)) = self.apply(Key(req.key), incoming, expected)?;
+        Ok(proto::PutResponse { version })
     }
 
+    // Returns the tombstone's version with no siblings if key was deleted
     pub async fn direct_get(&self, req: proto::GetRequest) -> Result<proto::GetResponse> {
         self.check_key(&req.key)?;
-        self.store
-            .get(&Key(req.key))
-            .map(|result| result.unwrap_or((Vec::new(), -1)))
-            .map(|(value, version)| proto::GetResponse { value, version })
+        let (siblings, version) = self.stored(&Key(req.key))?;
+        Ok(self.get_response(siblings, version))
     }
 
+    // Tombstones key unless it holds a write stamped after the delete
     pub async fn direct_delete(&self, req: proto::DeleteRequest) -> Result<proto::DeleteResponse> {
         self.check_key(&req.key)?;
-        self.store
-            .delete(&Key(req.key))
-            .map(|result| result.unwrap_or((Vec::new(), -1)))
-            .map(|(value, version)| proto::DeleteResponse { value })
+        self.check_version(req.version)?;
+        self.hlc.observe(req.version);
+        let ((prev, _), _) = self.apply(Key(req.key), (Vec::new(), req.version), None)?;
+        Ok(proto::DeleteResponse {
+            value: prev.first().map_or(Vec::new(), |s| s.value.clone()),
+        })
+    }
+
+    pub async fn direct_batch_get(
+        &self,
+        req: proto::BatchGetRequest,
+    ) -> Result<proto::BatchGetResponse> {
+        let mut results = Vec::with_capacity(req.keys.len());
+        for key in req.keys {
+            results.push(get_result(self.direct_get(proto::GetRequest { key }).await));
+        }
+        Ok(proto::BatchGetResponse { results })
+    }
+
+    pub async fn direct_batch_put(
+        &self,
+        req: proto::BatchPutRequest,
+    ) -> Result<proto::BatchPutResponse> {
+        let mut results = Vec::with_capacity(req.puts.len());
+        for put in req.puts {
+            results.push(put_result(self.direct_put(put).await));
+        }
+        Ok(proto::BatchPutResponse { results })
+    }
+
+    // Returns the local copies of the keys in range, tombstones included.
+    // The continuation is the last key returned if the limit was reached.
+    pub async fn direct_scan(&self, req: proto::ScanRequest) -> Result<proto::ScanResponse> {
+        let limit = scan_limit(&req);
+        let end = if req.end.is_empty() {
+            None
+        } else {
+            Some(Key(req.end))
+        };
+        let stored = self
+            .store
+            .scan_range(&Key(req.start), end.as_ref(), limit)?;
+        let continuation = match stored.last() {
+            Some((key, _)) if stored.len() == limit => key.0.clone(),
+            _ => Vec::new(),
+        };
+        let mut entries = Vec::with_capacity(stored.len());
+        for (key, entry) in stored {
+            let (siblings, version) = versioned(Some(entry))?;
+            entries.push(scan_entry(key.0, self.get_response(siblings, version)));
+        }
+        Ok(proto::ScanResponse {
+            entries,
+            continuation,
+        })
     }
 
     pub async fn heartbeat(
@@ -214,6 +1044,782 @@ impl Server {
         Ok(proto::HeartbeatResponse {})
     }
 
+    pub async fn merkle_nodes(
+        &self,
+        req: proto::MerkleNodesRequest,
+    ) -> Result<proto::MerkleNodesResponse> {
+        let merkle = self.merkle.lock().unwrap();
+        let hashes = req
+            .nodes
+            .iter()
+            .map(|node| {
+                merkle
+                    .hash(*node)
+                    .ok_or_else(|| Error::InvalidArgument(format!("invalid node: {}", node)))
+            })
+            .collect::<Result<_>>()?;
+        Ok(proto::MerkleNodesResponse { hashes })
+    }
+
+    pub async fn merkle_keys(
+        &self,
+        req: proto::MerkleKeysRequest,
+    ) -> Result<proto::MerkleKeysResponse> {
+        let keys = self.leaf_digests(&req.leaves, req.start, req.end)?;
+        Ok(proto::MerkleKeysResponse { keys })
+    }
+
+    // Streams every entry stored within a range to a node taking it over
+    pub fn stream_range(
+        self: &Arc<Self>,
+        req: proto::StreamRangeRequest,
+    ) -> mpsc::Receiver<Result<proto::RangeEntry>> {
+        let (mut tx, rx) = mpsc::channel(STREAM_BUFFER_SIZE);
+        let server = self.clone();
+        tokio::spawn(async move {
+            let spans = merkle::spans(req.start, req.end);
+            let mut next = Some(Key(Vec::new()));
+            while let Some(start) = next {
+                let entries = match server.range_entries(&start, &spans) {
+                    Ok((entries, after)) => {
+                        next = after;
+                        entries
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(e)).await;
+                        return;
+                    }
+                };
+                for entry in entries {
+                    // Stops once the receiver hangs up
+                    if tx.send(Ok(entry)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        rx
+    }
+
+    // Stores the entries of the ranges a leaving node hands off
+    pub async fn hand_off_range(
+        &self,
+        mut entries: tonic::Streaming<proto::RangeEntry>,
+    ) -> Result<proto::HandOffRangeResponse> {
+        let mut received = 0;
+        while let Some(entry) = entries.message().await? {
+            self.apply(Key(entry.key), (entry.siblings, entry.version), None)?;
+            received += 1;
+        }
+        Ok(proto::HandOffRangeResponse { received })
+    }
+
+    pub async fn join_network(
+        &self,
+        req: proto::JoinNetworkRequest,
+    ) -> Result<proto::JoinNetworkResponse> {
+        let node = req
+            .node
+            .ok_or_else(|| Error::InvalidArgument("missing node".to_string()))?;
+        if let Some(cluster_config) = &req.cluster_config {
+            if let Err(e) = self.agree_cluster_config(cluster_config) {
+                warn!("refusing join from {}: {}", node.address, e);
+                return Err(e);
+            }
+        }
+        let cluster_config = self.cluster_config();
+        let mut membership = self.membership.write().unwrap();
+        membership.merge(&proto::Gossip {
+            r#type: GossipType::NotifyNodeJoined as i32,
+            nodes: vec![node],
+        });
+        Ok(proto::JoinNetworkResponse {
+            cluster_config: Some((*cluster_config).clone()),
+            gossip: Some(membership.gossip(GossipType::ShareFullNetwork)),
+        })
+    }
+
+    // Removes a node that's gone from the network, or decommissions the
+    // local node
+    pub async fn leave_network(
+        self: &Arc<Self>,
+        req: proto::LeaveNetworkRequest,
+    ) -> Result<proto::LeaveNetworkResponse> {
+        let addr = req.node_address.parse::<SocketAddr>().map_err(|_| {
+            Error::InvalidArgument(format!("invalid node address: {}", req.node_address))
+        })?;
+        if addr == self.config.address {
+            self.decommission().await?;
+        } else {
+            self.membership.write().unwrap().leave(addr);
+        }
+        Ok(proto::LeaveNetworkResponse {})
+    }
+
+    // Hands every range the local node replicates off to the nodes taking
+    // it over, then leaves the network and stops the server. Nodes keep
+    // reading from the local node until it has left, while their writes go
+    // to the nodes taking over too. Stays in the network if a handoff fails.
+    async fn decommission(self: &Arc<Self>) -> Result<()> {
+        let replication_factor = self.cluster_config().replication_factor as usize;
+        {
+            let mut membership = self.membership.write().unwrap();
+            if membership.local_status() != NodeStatus::Online {
+                return Err(Error::InvalidArgument(
+                    "only an online node can leave".to_string(),
+                ));
+            }
+            let remaining = membership.write_ring().owners(0, usize::MAX).len() - 1;
+            if remaining < replication_factor {
+                return Err(Error::InvalidArgument(format!(
+                    "leaving would leave {} nodes, fewer than the replication factor ({})",
+                    remaining, replication_factor
+                )));
+            }
+            membership.set_local_status(NodeStatus::Leaving);
+        }
+        info!("leaving the network");
+        self.announce().await;
+
+        let handoffs = {
+            let membership = self.membership.read().unwrap();
+            rebalance::handed_off_ranges(
+                &membership.replicas(),
+                &membership.write_replicas(),
+                &self.config.address,
+                replication_factor,
+            )
+        };
+        for (target, transfers) in handoffs {
+            match self.remote_hand_off_range(target, &transfers).await {
+                Ok(sent) => info!(
+                    "handed {} keys in {} ranges off to {}",
+                    sent,
+                    transfers.len(),
+                    target
+                ),
+                Err(e) => {
+                    warn!("failed to hand ranges off to {}: {:?}", target, e);
+                    self.membership
+                        .write()
+                        .unwrap()
+                        .set_local_status(NodeStatus::Online);
+                    self.announce().await;
+                    return Err(e);
+                }
+            }
+        }
+        self.deliver_hints().await;
+
+        self.membership
+            .write()
+            .unwrap()
+            .set_local_status(NodeStatus::LeftNetwork);
+        self.announce().await;
+        info!("left the network");
+        self.shutdown.notify();
+        Ok(())
+    }
+
+    // Sends the local node's view of the network to every peer at once
+    async fn announce(self: &Arc<Self>) {
+        let (peers, gossip) = {
+            let membership = self.membership.read().unwrap();
+            (
+                membership.peers(),
+                membership.gossip(GossipType::ShareFullNetwork),
+            )
+        };
+        let req = proto::GossipRequest {
+            gossip: Some(gossip),
+            cluster_config: Some((*self.cluster_config()).clone()),
+        };
+        let mut responses = self.fan_out(&peers, self.deadline(None), move |server, addr| {
+            let req = req.clone();
+            async move { server.remote_gossip(addr, req).await }
+        });
+        while let Some((addr, result)) = responses.recv().await {
+            if let Err(e) = result {
+                warn!("failed to announce to {}: {:?}", addr, e);
+            }
+        }
+    }
+
+    pub async fn gossip(&self, req: proto::GossipRequest) -> Result<proto::GossipResponse> {
+        if let Some(cluster_config) = &req.cluster_config {
+            if let Err(e) = self.agree_cluster_config(cluster_config) {
+                warn!("refusing gossip: {}", e);
+                return Err(e);
+            }
+        }
+        let cluster_config = self.cluster_config();
+        let mut membership = self.membership.write().unwrap();
+        if let Some(gossip) = &req.gossip {
+            membership.merge(gossip);
+        }
+        Ok(proto::GossipResponse {
+            gossip: Some(membership.gossip(GossipType::ShareFullNetwork)),
+            cluster_config: Some((*cluster_config).clone()),
+        })
+    }
+
+    async fn run_gossip(&self) {
+        let mut interval =
+            tokio::time::interval(Duration::from_millis(self.config.gossip_interval_ms));
+        loop {
+            interval.tick().await;
+            self.gossip_round().await;
+        }
+    }
+
+    // Exchanges the full node table and cluster config with a random peer.
+    // Falls back to joining through the seed nodes until a peer is known.
+    async fn gossip_round(&self) {
+        let (peer, gossip) = {
+            let mut membership = self.membership.write().unwrap();
+            membership.tick();
+            let peer = membership.peers().choose(&mut rand::thread_rng()).copied();
+            (peer, membership.gossip(GossipType::ShareFullNetwork))
+        };
+        let peer = match peer {
+            Some(peer) => peer,
+            None => return self.join().await,
+        };
+
+        let req = proto::GossipRequest {
+            gossip: Some(gossip),
+            cluster_config: Some((*self.cluster_config()).clone()),
+        };
+        match self.remote_gossip(peer, req).await {
+            Ok(resp) => {
+                if let Some(cluster_config) = &resp.cluster_config {
+                    if let Err(e) = self.agree_cluster_config(cluster_config) {
+                        warn!("ignoring gossip from {}: {}", peer, e);
+                        return;
+                    }
+                }
+                if let Some(gossip) = &resp.gossip {
+                    self.membership.write().unwrap().merge(gossip);
+                }
+            }
+            Err(e) => trace!("gossip to {} failed: {:?}", peer, e),
+        }
+    }
+
+    // Heartbeats every peer each interval, feeding responses to the failure
+    // detector
+    async fn run_heartbeats(self: Arc<Self>) {
+        let interval = Duration::from_millis(self.config.heartbeat_interval_ms);
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let peers = self.membership.read().unwrap().peers();
+            self.peers.retain(&peers);
+            self.update_availability(&peers);
+            for peer in peers {
+                let server = self.clone();
+                tokio::spawn(async move {
+                    let heartbeat = server.remote_heartbeat(peer, proto::HeartbeatRequest {});
+                    match tokio::time::timeout(interval * 2, heartbeat).await {
+                        Ok(Ok(_)) => server
+                            .failure_detector
+                            .lock()
+                            .unwrap()
+                            .report(peer, Instant::now()),
+                        Ok(Err(e)) => trace!("heartbeat to {} failed: {:?}", peer, e),
+                        Err(_) => trace!("heartbeat to {} timed out", peer),
+                    }
+                });
+            }
+        }
+    }
+
+    // Marks peers unavailable while their suspicion level exceeds the
+    // threshold or repeated attempts to reach them fail
+    fn update_availability(&self, peers: &[SocketAddr]) {
+        let now = Instant::now();
+        let available: Vec<_> = {
+            let mut failure_detector = self.failure_detector.lock().unwrap();
+            for addr in failure_detector.watched() {
+                if !peers.contains(&addr) {
+                    failure_detector.unwatch(&addr);
+                }
+            }
+            peers
+                .iter()
+                .map(|peer| {
+                    failure_detector.watch(*peer, now);
+                    let reachable = match self.peers.health(peer) {
+                        Health::Unreachable(failures) => failures < UNREACHABLE_FAILURES,
+                        _ => true,
+                    };
+                    reachable && failure_detector.phi(peer, now) < self.config.phi_threshold
+                })
+                .collect()
+        };
+        let mut membership = self.membership.write().unwrap();
+        for (peer, available) in peers.iter().zip(available) {
+            membership.set_available(peer, available);
+        }
+    }
+
+    // Pushes the value or tombstone resolved by a get to replicas that
+    // returned stale or missing data, without waiting for them
+    fn read_repair(self: &Arc<Self>, key: Vec<u8>, resolved: &Versioned, stale: Vec<SocketAddr>) {
+        if stale.is_empty() {
+            return;
+        }
+        self.metrics.add_read_repairs(stale.len() as u64);
+        for addr in stale {
+            let server = self.clone();
+            let key = key.clone();
+            let resolved = resolved.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.remote_apply(addr, key, resolved).await {
+                    trace!("read repair of {} failed: {:?}", addr, e);
+                }
+            });
+        }
+    }
+
+    // Stores a mutation for a replica that missed it. Hints never count
+    // towards write_replicas.
+    fn add_hint(&self, addr: SocketAddr, mutation: proto::hint::Mutation) {
+        match self.hints.add(addr, mutation) {
+            Ok(true) => trace!("stored hint for {}", addr),
+            Ok(false) => warn!("hint storage is full, dropping hint for {}", addr),
+            Err(e) => warn!("failed to store hint for {}: {:?}", addr, e),
+        }
+    }
+
+    async fn run_hint_delivery(&self) {
+        let mut interval = tokio::time::interval(HINT_DELIVERY_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.deliver_hints().await;
+        }
+    }
+
+    // Replays hints to targets that are available again. Hints for nodes
+    // that left the network are dropped.
+    async fn deliver_hints(&self) {
+        for target in self.hints.targets() {
+            let (left, available) = {
+                let membership = self.membership.read().unwrap();
+                (
+                    membership.has_left(&target),
+                    membership.is_available(&target),
+                )
+            };
+            if left {
+                info!("dropping hints for {}, which left the network", target);
+                if let Err(e) = self.hints.remove(&target) {
+                    warn!("failed to remove hints for {}: {:?}", target, e);
+                }
+                continue;
+            }
+            if !available {
+                continue;
+            }
+
+            let hints = match self.hints.take(&target) {
+                Ok(hints) => hints,
+                Err(e) => {
+                    warn!("failed to read hints for {}: {:?}", target, e);
+                    continue;
+                }
+            };
+            let mut delivered = 0;
+            for hint in &hints {
+                let result = match hint.mutation.clone() {
+                    Some(proto::hint::Mutation::Put(req)) => {
+                        self.remote_put(target, req).await.map(|_| ())
+                    }
+                    Some(proto::hint::Mutation::Delete(req)) => {
+                        self.remote_delete(target, req).await.map(|_| ())
+                    }
+                    None => Ok(()),
+                };
+                if let Err(e) = result {
+                    trace!("hint delivery to {} failed: {:?}", target, e);
+                    break;
+                }
+                delivered += 1;
+            }
+            if delivered > 0 {
+                info!("delivered {} hints to {}", delivered, target);
+            }
+            if let Err(e) = self.hints.restore(target, &hints[delivered..]) {
+                warn!("failed to restore hints for {}: {:?}", target, e);
+            }
+        }
+    }
+
+    async fn run_anti_entropy(&self) {
+        let mut interval =
+            tokio::time::interval(Duration::from_millis(self.config.anti_entropy_interval_ms));
+        loop {
+            interval.tick().await;
+            self.anti_entropy_round().await;
+        }
+    }
+
+    // Repairs every range the local node replicates together with a random
+    // available peer
+    async fn anti_entropy_round(&self) {
+        let (peer, spans) = {
+            let membership = self.membership.read().unwrap();
+            let peers: Vec<_> = membership
+                .peers()
+                .into_iter()
+                .filter(|peer| membership.is_available(peer))
+                .collect();
+            let peer = match peers.choose(&mut rand::thread_rng()) {
+                Some(peer) => *peer,
+                None => return,
+            };
+            let replication_factor = self.cluster_config().replication_factor as usize;
+            let spans: Vec<_> = membership
+                .replicas()
+                .ranges(replication_factor)
+                .into_iter()
+                .filter(|(_, _, owners)| {
+                    owners.contains(&self.config.address) && owners.contains(&peer)
+                })
+                .flat_map(|(start, end, _)| merkle::spans(start, end))
+                .collect();
+            (peer, spans)
+        };
+
+        let mut repaired = 0;
+        for (start, end) in spans {
+            match self.repair_span(peer, start, end).await {
+                Ok(n) => repaired += n,
+                Err(e) => {
+                    trace!("anti-entropy with {} failed: {:?}", peer, e);
+                    return;
+                }
+            }
+        }
+        if repaired > 0 {
+            info!("repaired {} keys with {}", repaired, peer);
+        }
+    }
+
+    // Finds the keys in [start, end] that differ from peer by descending
+    // both Merkle trees from the largest nodes within the span, then
+    // reconciles each of them. Returns the number of keys repaired.
+    async fn repair_span(&self, peer: SocketAddr, start: u64, end: u64) -> Result<usize> {
+        let mut nodes = Vec::new();
+        let mut leaves = Vec::new();
+        merkle::split(merkle::ROOT, start, end, &mut nodes, &mut leaves);
+        while !nodes.is_empty() {
+            let req = proto::MerkleNodesRequest {
+                nodes: nodes.clone(),
+            };
+            let remote = self.remote_merkle_nodes(peer, req).await?.hashes;
+            if remote.len() != nodes.len() {
+                return Err(Error::InvalidArgument(
+                    "mismatched merkle node hashes".to_string(),
+                ));
+            }
+            nodes = self
+                .merkle
+                .lock()
+                .unwrap()
+                .descend(&nodes, &remote, &mut leaves);
+        }
+
+        let mut repaired = 0;
+        for batch in leaves.chunks(MERKLE_KEYS_BATCH_SIZE) {
+            let req = proto::MerkleKeysRequest {
+                start,
+                end,
+                leaves: batch.to_vec(),
+            };
+            let remote = self.remote_merkle_keys(peer, req).await?.keys;
+            let local = self.leaf_digests(batch, start, end)?;
+            for key in merkle::diff(&local, &remote) {
+                self.repair_key(peer, key).await?;
+                repaired += 1;
+            }
+        }
+        Ok(repaired)
+    }
+
+    // Resolves the local and peer copies of key, values and tombstones
+    // alike, and stores the result on whichever side lacks it
+    async fn repair_key(&self, peer: SocketAddr, key: Vec<u8>) -> Result<()> {
+        let local = self.stored(&Key(key.clone()))?;
+        let remote = self
+            .remote_get(peer, proto::GetRequest { key: key.clone() })
+            .await?;
+        let remote = (remote.siblings, remote.version);
+        let resolved = self.resolve(local.clone(), remote.clone());
+        if resolved.1 < 0 {
+            return Ok(());
+        }
+        // Tombstones are applied to both sides, so that once past the grace
+        // period both purge them rather than copying them back and forth
+        let tombstone = resolved.0.is_empty();
+        if resolved != remote || tombstone {
+            self.remote_apply(peer, key.clone(), resolved.clone())
+                .await?;
+        }
+        if resolved != local || tombstone {
+            self.apply(Key(key), resolved, None)?;
+        }
+        Ok(())
+    }
+
+    // Streams in the ranges the local node takes over whenever the ring
+    // changes. A joining node goes online once it has every range it
+    // replicates, which moves reads of them to it as the news spreads.
+    async fn run_rebalance(&self) {
+        let mut interval = tokio::time::interval(REBALANCE_INTERVAL);
+        // The write ring as of the last completed rebalance
+        let mut settled: Option<HashRing<SocketAddr>> = None;
+        loop {
+            interval.tick().await;
+            let replication_factor = self.cluster_config().replication_factor as usize;
+            let (status, ring, transfers) = {
+                let membership = self.membership.read().unwrap();
+                let status = membership.local_status();
+                let ring = membership.write_ring().clone();
+                let old = match (status, &settled) {
+                    // Waits to hear of the ring it's joining
+                    (NodeStatus::Joining, _) if membership.peers().is_empty() => continue,
+                    (NodeStatus::Joining, _) => membership.ring(),
+                    (_, Some(old)) => old,
+                    (_, None) => {
+                        settled = Some(ring);
+                        continue;
+                    }
+                };
+                let transfers = rebalance::gained_ranges(
+                    &membership.replicas_on(old),
+                    &membership.replicas_on(&ring),
+                    &self.config.address,
+                    replication_factor,
+                );
+                (status, ring, transfers)
+            };
+
+            let mut received = 0;
+            let mut complete = true;
+            for transfer in &transfers {
+                match self.receive_range(transfer).await {
+                    Ok(n) => received += n,
+                    Err(e) => {
+                        warn!("failed to receive range: {:?}", e);
+                        complete = false;
+                        break;
+                    }
+                }
+            }
+            if !complete {
+                continue;
+            }
+            if !transfers.is_empty() {
+                info!("received {} keys in {} ranges", received, transfers.len());
+            }
+            if status == NodeStatus::Joining {
+                self.membership
+                    .write()
+                    .unwrap()
+                    .set_local_status(NodeStatus::Online);
+            }
+            settled = Some(ring);
+        }
+    }
+
+    // Streams a range from the first of its sources that hasn't left the
+    // network and can send it. Returns the number of keys received. Ranges
+    // taken over from a leaving node are handed off by it instead.
+    async fn receive_range(&self, transfer: &Transfer) -> Result<usize> {
+        let mut result = Ok(0);
+        for source in &transfer.sources {
+            let (leaving, left) = {
+                let membership = self.membership.read().unwrap();
+                (membership.is_leaving(source), membership.has_left(source))
+            };
+            if leaving {
+                return Ok(0);
+            }
+            if left {
+                continue;
+            }
+            result = self.remote_stream_range(*source, transfer).await;
+            match &result {
+                Ok(_) => break,
+                Err(e) => trace!("streaming range from {} failed: {:?}", source, e),
+            }
+        }
+        result
+    }
+
+    async fn run_tombstone_gc(&self) {
+        let mut interval = tokio::time::interval(TOMBSTONE_GC_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.collect_tombstones() {
+                warn!("failed to collect tombstones: {:?}", e);
+            }
+        }
+    }
+
+    // Purges every tombstone older than the grace period, by which time
+    // every replica has either received its delete or been repaired
+    fn collect_tombstones(&self) -> Result<()> {
+        let cutoff = self.tombstone_cutoff();
+        let mut purged = 0;
+        let mut start = Key(Vec::new());
+        loop {
+            let entries = self.store.scan(&start, SCAN_PAGE_SIZE)?;
+            let last = match entries.last() {
+                Some((key, _)) => key.clone(),
+                None => break,
+            };
+            for (key, entry) in entries {
+                let tombstone = store::Entry::Tombstone(match entry {
+                    store::Entry::Tombstone(version) if version < cutoff => version,
+                    _ => continue,
+                });
+                let point = self.membership.read().unwrap().ring().point(&key.0);
+                let mut merkle = self.merkle.lock().unwrap();
+                // Skip keys written since the scan
+                if self.store.entry(&key)?.as_ref() == Some(&tombstone) {
+                    self.store.purge(&key)?;
+                    merkle.remove(point, &key.0, entry_digest(&tombstone));
+                    purged += 1;
+                }
+            }
+            // The smallest key after last
+            start = last;
+            start.0.push(0);
+        }
+        if purged > 0 {
+            info!("purged {} tombstones", purged);
+        }
+        Ok(())
+    }
+
+    async fn run_expiry(self: &Arc<Self>) {
+        let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.expire_keys().await {
+                warn!("failed to expire keys: {:?}", e);
+            }
+        }
+    }
+
+    // Deletes the expired keys the local node is the first available
+    // replica of. Their tombstones replicate like those of any delete.
+    async fn expire_keys(self: &Arc<Self>) -> Result<()> {
+        let mut expired = 0;
+        let mut start = Key(Vec::new());
+        loop {
+            let entries = self.store.scan(&start, SCAN_PAGE_SIZE)?;
+            let last = match entries.last() {
+                Some((key, _)) => key.clone(),
+                None => break,
+            };
+            for (key, entry) in entries {
+                match entry {
+                    store::Entry::Value(_, _, expires) if store::is_expired(expires) => {}
+                    _ => continue,
+                }
+                let (replicas, _) = self.find_replicas(&key.0)?;
+                if replicas.first() != Some(&self.config.address) {
+                    continue;
+                }
+                match self.expire(key.0).await {
+                    Ok(true) => expired += 1,
+                    Ok(false) => {}
+                    Err(e) => trace!("expire error: {:?}", e),
+                }
+            }
+            // The smallest key after last
+            start = last;
+            start.0.push(0);
+        }
+        if expired > 0 {
+            info!("expired {} keys", expired);
+        }
+        Ok(())
+    }
+
+    // Deletes key if the copy its replicas resolve to has expired. The
+    // tombstone is stamped at the expiry, so it supersedes the expired
+    // value but not writes made since. Returns whether key was deleted.
+    async fn expire(self: &Arc<Self>, key: Vec<u8>) -> Result<bool> {
+        let deadline = self.deadline(None);
+        let (_, (siblings, version)) = self.quorum_get(&key, deadline).await?;
+        let expires = match expiry(&siblings) {
+            Some(expires) if !siblings.is_empty() && store::is_expired(Some(expires)) => expires,
+            _ => return Ok(false),
+        };
+        let version = hlc::at_millis(expires).max(version + 1);
+        self.replicate_delete(proto::DeleteRequest { key, version }, deadline)
+            .await?;
+        Ok(true)
+    }
+
+    // Announces the local node to every reachable seed node of the same
+    // cluster
+    async fn join(&self) {
+        let node = self.membership.read().unwrap().local_info();
+        for seed in &self.config.seed_nodes {
+            if *seed == self.config.address {
+                continue;
+            }
+            let req = proto::JoinNetworkRequest {
+                node: Some(node.clone()),
+                cluster_config: Some((*self.cluster_config()).clone()),
+            };
+            match self.remote_join_network(*seed, req).await {
+                Ok(resp) => {
+                    if let Some(cluster_config) = &resp.cluster_config {
+                        if let Err(e) = self.agree_cluster_config(cluster_config) {
+                            warn!("refusing to join network through {}: {}", seed, e);
+                            continue;
+                        }
+                    }
+                    if let Some(gossip) = &resp.gossip {
+                        self.membership.write().unwrap().merge(gossip);
+                    }
+                    info!("joined network through {}", seed);
+                }
+                Err(e) => warn!("failed to join network through {}: {:?}", seed, e),
+            }
+        }
+    }
+
+    // Stores a resolved copy of key on a replica, deleting it there if it's
+    // a tombstone
+    async fn remote_apply(
+        &self,
+        addr: SocketAddr,
+        key: Vec<u8>,
+        resolved: Versioned,
+    ) -> Result<()> {
+        let (siblings, version) = resolved;
+        if siblings.is_empty() {
+            self.remote_delete(addr, proto::DeleteRequest { key, version })
+                .await?;
+        } else {
+            let req = proto::PutRequest {
+                key,
+                version,
+                siblings,
+                ..Default::default()
+            };
+            self.remote_put(addr, req).await?;
+        }
+        Ok(())
+    }
+
     async fn remote_put(
         &self,
         addr: SocketAddr,
@@ -223,8 +1829,8 @@ impl Server {
             return self.direct_put(req).await;
         }
 
-        let mut client = PeerServiceClient::connect(to_endpoint(&addr)).await?;
-        let resp = client.direct_put(req).await?;
+        let mut client = self.peers.client(addr).await?;
+        let resp = self.peers.check(addr, client.direct_put(req).await)?;
         Ok(resp.into_inner())
     }
 
@@ -237,8 +1843,8 @@ impl Server {
             return self.direct_get(req).await;
         }
 
-        let mut client = PeerServiceClient::connect(to_endpoint(&addr)).await?;
-        let resp = client.direct_get(req).await?;
+        let mut client = self.peers.client(addr).await?;
+        let resp = self.peers.check(addr, client.direct_get(req).await)?;
         Ok(resp.into_inner())
     }
 
@@ -251,28 +1857,225 @@ impl Server {
             return self.direct_delete(req).await;
         }
 
-        let mut client = PeerServiceClient::connect(to_endpoint(&addr)).await?;
-        let resp = client.direct_delete(req).await?;
+        let mut client = self.peers.client(addr).await?;
+        let resp = self.peers.check(addr, client.direct_delete(req).await)?;
         Ok(resp.into_inner())
     }
 
-    fn find_replicas(&self, key: &Vec<u8>) -> Result<Vec<SocketAddr>> {
-        let replication_factor = self.config.cluster_config.replication_factor as usize;
-        let mut replicas = Vec::new();
-        let mut iter = self.ring.successors(key);
-        while let Some(addr) = iter.next() {
-            if replicas.contains(addr) {
-                continue;
+    // Returns a result per key. Every key fails if the request does.
+    async fn remote_batch_get(
+        &self,
+        addr: SocketAddr,
+        req: proto::BatchGetRequest,
+    ) -> Vec<Result<proto::GetResponse>> {
+        let len = req.keys.len();
+        let resp = if addr == self.config.address {
+            self.direct_batch_get(req).await
+        } else {
+            match self.peers.client(addr).await {
+                Ok(mut client) => {
+                    let resp = client.direct_batch_get(req).await;
+                    self.peers.check(addr, resp).map(|resp| resp.into_inner())
+                }
+                Err(e) => Err(e),
             }
-            replicas.push(addr.clone());
-            if replicas.len() == replication_factor {
-                break;
+        };
+        match resp {
+            Ok(resp) => resp.results.into_iter().map(from_get_result).collect(),
+            Err(e) => batch_failure(e, len),
+        }
+    }
+
+    // Returns a result per put. Every put fails if the request does.
+    async fn remote_batch_put(
+        &self,
+        addr: SocketAddr,
+        req: proto::BatchPutRequest,
+    ) -> Vec<Result<proto::PutResponse>> {
+        let len = req.puts.len();
+        let resp = if addr == self.config.address {
+            self.direct_batch_put(req).await
+        } else {
+            match self.peers.client(addr).await {
+                Ok(mut client) => {
+                    let resp = client.direct_batch_put(req).await;
+                    self.peers.check(addr, resp).map(|resp| resp.into_inner())
+                }
+                Err(e) => Err(e),
             }
+        };
+        match resp {
+            Ok(resp) => resp.results.into_iter().map(from_put_result).collect(),
+            Err(e) => batch_failure(e, len),
+        }
+    }
+
+    async fn remote_scan(
+        &self,
+        addr: SocketAddr,
+        req: proto::ScanRequest,
+    ) -> Result<proto::ScanResponse> {
+        if addr == self.config.address {
+            return self.direct_scan(req).await;
         }
+
+        let mut client = self.peers.client(addr).await?;
+        let resp = self.peers.check(addr, client.direct_scan(req).await)?;
+        Ok(resp.into_inner())
+    }
+
+    async fn remote_heartbeat(
+        &self,
+        addr: SocketAddr,
+        req: proto::HeartbeatRequest,
+    ) -> Result<proto::HeartbeatResponse> {
+        let mut client = self.peers.client(addr).await?;
+        let resp = self.peers.check(addr, client.heartbeat(req).await)?;
+        Ok(resp.into_inner())
+    }
+
+    async fn remote_join_network(
+        &self,
+        addr: SocketAddr,
+        req: proto::JoinNetworkRequest,
+    ) -> Result<proto::JoinNetworkResponse> {
+        let mut client = self.peers.client(addr).await?;
+        let resp = self.peers.check(addr, client.join_network(req).await)?;
+        Ok(resp.into_inner())
+    }
+
+    async fn remote_gossip(
+        &self,
+        addr: SocketAddr,
+        req: proto::GossipRequest,
+    ) -> Result<proto::GossipResponse> {
+        let mut client = self.peers.client(addr).await?;
+        let resp = self.peers.check(addr, client.gossip(req).await)?;
+        Ok(resp.into_inner())
+    }
+
+    async fn remote_merkle_nodes(
+        &self,
+        addr: SocketAddr,
+        req: proto::MerkleNodesRequest,
+    ) -> Result<proto::MerkleNodesResponse> {
+        let mut client = self.peers.client(addr).await?;
+        let resp = self.peers.check(addr, client.merkle_nodes(req).await)?;
+        Ok(resp.into_inner())
+    }
+
+    async fn remote_merkle_keys(
+        &self,
+        addr: SocketAddr,
+        req: proto::MerkleKeysRequest,
+    ) -> Result<proto::MerkleKeysResponse> {
+        let mut client = self.peers.client(addr).await?;
+        let resp = self.peers.check(addr, client.merkle_keys(req).await)?;
+        Ok(resp.into_inner())
+    }
+
+    // Hands the entries within ranges off to addr, returning the number sent
+    // once it has stored all of them
+    async fn remote_hand_off_range(
+        self: &Arc<Self>,
+        addr: SocketAddr,
+        transfers: &[Transfer],
+    ) -> Result<u64> {
+        let spans: Vec<_> = transfers
+            .iter()
+            .flat_map(|t| merkle::spans(t.start, t.end))
+            .collect();
+        let (mut tx, rx) = mpsc::channel(STREAM_BUFFER_SIZE);
+        let server = self.clone();
+        let sender = tokio::spawn(async move {
+            let mut sent = 0;
+            let mut next = Some(Key(Vec::new()));
+            while let Some(start) = next {
+                let (entries, after) = server.range_entries(&start, &spans)?;
+                next = after;
+                for entry in entries {
+                    // The request failed if addr hung up
+                    if tx.send(entry).await.is_err() {
+                        return Ok(sent);
+                    }
+                    sent += 1;
+                }
+            }
+            Ok::<_, Error>(sent)
+        });
+
+        let mut client = self.peers.client(addr).await?;
+        let resp = self.peers.check(addr, client.hand_off_range(rx).await)?;
+        let sent = sender.await.map_err(|e| Error::Other(Box::new(e)))??;
+        let received = resp.into_inner().received;
+        if received != sent {
+            return Err(Error::Rpc(tonic::Status::data_loss(format!(
+                "{} of {} entries stored",
+                received, sent
+            ))));
+        }
+        Ok(sent)
+    }
+
+    // Streams a range from addr into the local store, returning the number
+    // of keys received
+    async fn remote_stream_range(&self, addr: SocketAddr, transfer: &Transfer) -> Result<usize> {
+        let req = proto::StreamRangeRequest {
+            start: transfer.start,
+            end: transfer.end,
+        };
+        let mut client = self.peers.client(addr).await?;
+        let mut stream = self
+            .peers
+            .check(addr, client.stream_range(req).await)?
+            .into_inner();
+        let mut received = 0;
+        while let Some(entry) = self.peers.check(addr, stream.message().await)? {
+            self.apply(Key(entry.key), (entry.siblings, entry.version), None)?;
+            received += 1;
+        }
+        Ok(received)
+    }
+
+    // Returns the replicas responsible for key, split into those that are
+    // available and those the failure detector suspects
+    fn find_replicas(&self, key: &[u8]) -> Result<(Vec<SocketAddr>, Vec<SocketAddr>)> {
+        let replication_factor = self.cluster_config().replication_factor as usize;
+        let membership = self.membership.read().unwrap();
+        let replicas = membership.replicas().of_key(key, replication_factor);
         if replicas.len() < replication_factor {
             return Err(Error::TooFewReplicas);
         }
-        Ok(replicas)
+        Ok(replicas
+            .into_iter()
+            .partition(|addr| membership.is_available(addr)))
+    }
+
+    // Returns the replicas a write to key goes to, split like find_replicas,
+    // and the number of them that must acknowledge it. Nodes joining to take
+    // over the key get it on top of its replicas on the read ring and must
+    // acknowledge it on top of a quorum of those, so a read quorum of the
+    // read ring still sees it.
+    fn find_write_replicas(&self, key: &[u8]) -> Result<(Vec<SocketAddr>, Vec<SocketAddr>, usize)> {
+        let cluster_config = self.cluster_config();
+        let replication_factor = cluster_config.replication_factor as usize;
+        let membership = self.membership.read().unwrap();
+        let mut replicas = membership.replicas().of_key(key, replication_factor);
+        if replicas.len() < replication_factor {
+            return Err(Error::TooFewReplicas);
+        }
+        let joining: Vec<_> = membership
+            .write_replicas()
+            .of_key(key, replication_factor)
+            .into_iter()
+            .filter(|addr| !replicas.contains(addr))
+            .collect();
+        let needed = cluster_config.write_replicas as usize + joining.len();
+        replicas.extend(joining);
+        let (available, suspected) = replicas
+            .into_iter()
+            .partition(|addr| membership.is_available(addr));
+        Ok((available, suspected, needed))
     }
 
     fn check_key(&self, key: &Vec<u8>) -> Result<()> {
@@ -282,8 +2085,453 @@ impl Server {
             Ok(())
         }
     }
+
+    fn check_version(&self, version: Version) -> Result<()> {
+        let skew = Duration::from_millis(self.config.max_clock_skew_ms);
+        if version > hlc::ahead(skew) {
+            Err(Error::InvalidArgument(format!(
+                "version {} too far ahead of the clock",
+                version
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Resolves two copies of a key into the one every replica converges on
+    fn resolve(&self, a: Versioned, b: Versioned) -> Versioned {
+        let mode = self.cluster_config().conflict_resolution();
+        if mode == ConflictResolution::Siblings && !a.0.is_empty() && !b.0.is_empty() {
+            let mut siblings = a.0;
+            siblings.extend(b.0);
+            return (vector_clock::reconcile(siblings), a.1.max(b.1));
+        }
+        // Otherwise the latest write wins, be it a value or a delete. Ties
+        // between writes stamped alike are broken by value.
+        let latest =
+            |(siblings, version): &Versioned| (*version, siblings.first().map(|s| s.value.clone()));
+        if latest(&b) > latest(&a) {
+            b
+        } else {
+            a
+        }
+    }
+
+    // Replaces the existing copy of a key. In Siblings mode the incoming
+    // siblings' clocks are merged with the existing context, so the copies
+    // other replicas still hold resolve to them.
+    fn supersede(&self, existing: &Versioned, incoming: Versioned) -> Versioned {
+        let (siblings, version) = incoming;
+        let siblings = match self.cluster_config().conflict_resolution() {
+            ConflictResolution::Siblings => {
+                let context = vector_clock::context(&existing.0);
+                siblings
+                    .into_iter()
+                    .map(|s| proto::Sibling {
+                        clock: Some(vector_clock::merge(&s.clock.unwrap_or_default(), &context)),
+                        ..s
+                    })
+                    .collect()
+            }
+            ConflictResolution::LastWriteWins => siblings,
+        };
+        (siblings, version)
+    }
+
+    // Scans a page of the store from start on, keeping the entries whose
+    // ring points satisfy within
+    fn scan_points(
+        &self,
+        start: &Key,
+        within: impl Fn(u64) -> bool,
+    ) -> Result<Page<(Key, store::Entry)>> {
+        let entries = self.store.scan(start, SCAN_PAGE_SIZE)?;
+        // The smallest key after the last one
+        let next = entries.last().map(|(key, _)| {
+            let mut next = key.clone();
+            next.0.push(0);
+            next
+        });
+        let membership = self.membership.read().unwrap();
+        let entries = entries
+            .into_iter()
+            .filter(|(key, _)| within(membership.ring().point(&key.0)))
+            .collect();
+        Ok((entries, next))
+    }
+
+    // Reads a page of the entries stored within spans, like scan_points
+    fn range_entries(&self, start: &Key, spans: &[(u64, u64)]) -> Result<Page<proto::RangeEntry>> {
+        let within = |point| spans.iter().any(|(lo, hi)| *lo <= point && point <= *hi);
+        let (entries, next) = self.scan_points(start, within)?;
+        let entries = entries
+            .into_iter()
+            .map(|(Key(key), entry)| {
+                let (siblings, version) = versioned(Some(entry))?;
+                Ok(proto::RangeEntry {
+                    key,
+                    version,
+                    siblings,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok((entries, next))
+    }
+
+    // Returns the digests of the keys stored under the given Merkle leaves
+    // whose points fall within [start, end]. The tree doesn't keep keys, so
+    // this scans the whole store.
+    fn leaf_digests(&self, leaves: &[u32], start: u64, end: u64) -> Result<Vec<proto::KeyDigest>> {
+        let within = merkle::in_leaves(leaves, start, end);
+        let mut digests = Vec::new();
+        let mut next = Some(Key(Vec::new()));
+        while let Some(start) = next {
+            let (entries, after) = self.scan_points(&start, &within)?;
+            digests.extend(
+                entries
+                    .into_iter()
+                    .map(|(Key(key), entry)| proto::KeyDigest {
+                        key,
+                        digest: entry_digest(&entry),
+                    }),
+            );
+            next = after;
+        }
+        Ok(digests)
+    }
+
+    // Returns the copy of key stored locally
+    fn stored(&self, key: &Key) -> Result<Versioned> {
+        versioned(self.store.entry(key)?)
+    }
+
+    // Resolves a copy of key against the stored one and stores the result,
+    // as a tombstone if it has no siblings. Tombstones past the grace period
+    // are purged instead. If expected is set the copy supersedes the stored
+    // one, provided it has the expected version. Returns the previously
+    // stored and resolved copies.
+    fn apply(
+        &self,
+        key: Key,
+        incoming: Versioned,
+        expected: Option<Version>,
+    ) -> Result<(Versioned, Versioned)> {
+        let point = self.membership.read().unwrap().ring().point(&key.0);
+        let mut merkle = self.merkle.lock().unwrap();
+        let stored = self.store.entry(&key)?;
+        let old = stored.as_ref().map(entry_digest);
+        let existing = versioned(stored)?;
+        let resolved = match expected {
+            Some(_) => self.supersede(&existing, incoming),
+            None => self.resolve(existing.clone(), incoming),
+        };
+        let (siblings, version) = &resolved;
+        if siblings.is_empty() && *version < self.tombstone_cutoff() {
+            if let Some(old) = old {
+                self.store.purge(&key)?;
+                merkle.remove(point, &key.0, old);
+            }
+        } else if resolved != existing || expected.is_some() {
+            let digest = if siblings.is_empty() {
+                self.store.delete(&key, *version)?;
+                merkle::digest(None, *version)
+            } else {
+                let value = vector_clock::encode_siblings(siblings.clone());
+                let digest = merkle::digest(Some(&value), *version);
+                let expires = expiry(siblings);
+                self.store
+                    .put(key.clone(), value, *version, expected, expires)?;
+                digest
+            };
+            if let Some(old) = old {
+                merkle.remove(point, &key.0, old);
+            }
+            merkle.insert(point, &key.0, digest);
+        }
+        Ok((existing, resolved))
+    }
+
+    // Tombstones stamped before this are past the grace period
+    fn tombstone_cutoff(&self) -> Version {
+        hlc::before(
+            self.hlc.now(),
+            Duration::from_secs(self.config.tombstone_grace_secs),
+        )
+    }
+
+    // Builds a get response from the siblings of a key. Empty if the key
+    // isn't present.
+    fn get_response(&self, siblings: Vec<proto::Sibling>, version: Version) -> proto::GetResponse {
+        let context = match self.cluster_config().conflict_resolution() {
+            ConflictResolution::Siblings if !siblings.is_empty() => {
+                vector_clock::encode_clock(&vector_clock::context(&siblings))
+            }
+            _ => Vec::new(),
+        };
+        proto::GetResponse {
+            value: siblings.first().map_or(Vec::new(), |s| s.value.clone()),
+            version,
+            siblings,
+            context,
+        }
+    }
+
+    // Builds the response clients get for the resolved copy of a key.
+    // Deleted and expired keys look missing. Expired siblings are left out
+    // but still covered by the context, so the next put supersedes them.
+    fn client_response(&self, resolved: Versioned) -> proto::GetResponse {
+        let (siblings, version) = resolved;
+        let mut resp = self.get_response(siblings, version);
+        resp.siblings
+            .retain(|s| !store::is_expired(expiry(std::slice::from_ref(s))));
+        match resp.siblings.first() {
+            Some(sibling) => resp.value = sibling.value.clone(),
+            None => return self.get_response(Vec::new(), -1),
+        }
+        resp
+    }
+}
+
+// Receives responses until done holds for them or every replica has
+// responded
+async fn gather<T>(
+    responses: &mut Responses<T>,
+    done: impl Fn(&[(SocketAddr, Result<T>)]) -> bool,
+) -> Vec<(SocketAddr, Result<T>)> {
+    let mut results = Vec::new();
+    while !done(&results) {
+        match responses.recv().await {
+            Some(result) => results.push(result),
+            None => break,
+        }
+    }
+    results
+}
+
+// Whether a request needing successes from some of its total replicas
+// has either got them or can no longer get them
+fn decided(successes: usize, responded: usize, total: usize, needed: usize) -> bool {
+    successes >= needed || successes + (total - responded) < needed
+}
+
+fn quorate<T>(results: &[(SocketAddr, Result<T>)], total: usize, needed: usize) -> bool {
+    let successes = results.iter().filter(|(_, r)| r.is_ok()).count();
+    decided(successes, results.len(), total, needed)
 }
 
-fn to_endpoint(addr: &SocketAddr) -> tonic::transport::Endpoint {
-   tonic::transport::Endpoint::from_shared(format!("http://{}", addr)).unwrap()
+// Whether a batch request is decided for every key. Batches map replicas
+// to the indices of the keys sent to them, and totals and needed hold each
+// key's number of replicas and of successes it needs.
+fn batch_quorate<T>(
+    results: &[(SocketAddr, Result<Vec<Result<T>>>)],
+    batches: &HashMap<SocketAddr, Vec<usize>>,
+    totals: &[usize],
+    needed: &[usize],
+) -> bool {
+    let mut successes = vec![0; totals.len()];
+    let mut responded = vec![0; totals.len()];
+    for (addr, result) in results {
+        for (j, i) in batches[addr].iter().enumerate() {
+            responded[*i] += 1;
+            if let Ok(results) = result {
+                if results.get(j).is_some_and(|r| r.is_ok()) {
+                    successes[*i] += 1;
+                }
+            }
+        }
+    }
+    (0..totals.len()).all(|i| decided(successes[i], responded[i], totals[i], needed[i]))
+}
+
+// The put hinted to replicas that miss it. Replicas that miss a
+// conditional put only get it once it's known to have succeeded, with the
+// condition dropped since it was checked by the others.
+fn put_hint(req: &proto::PutRequest, succeeded: bool) -> Option<proto::hint::Mutation> {
+    if succeeded || expected_version(&req.condition).is_none() {
+        Some(proto::hint::Mutation::Put(proto::PutRequest {
+            condition: None,
+            ..req.clone()
+        }))
+    } else {
+        None
+    }
+}
+
+fn get_result(result: Result<proto::GetResponse>) -> proto::BatchGetResult {
+    use proto::batch_get_result::Result as BatchResult;
+    let result = match result {
+        Ok(resp) => BatchResult::Response(resp),
+        Err(e) => BatchResult::Error(batch_error(e)),
+    };
+    proto::BatchGetResult {
+        result: Some(result),
+    }
+}
+
+fn from_get_result(result: proto::BatchGetResult) -> Result<proto::GetResponse> {
+    use proto::batch_get_result::Result as BatchResult;
+    match result.result {
+        Some(BatchResult::Response(resp)) => Ok(resp),
+        Some(BatchResult::Error(e)) => Err(from_batch_error(e)),
+        None => Err(Error::InvalidArgument("missing batch result".to_string())),
+    }
+}
+
+fn put_result(result: Result<proto::PutResponse>) -> proto::BatchPutResult {
+    use proto::batch_put_result::Result as BatchResult;
+    let result = match result {
+        Ok(resp) => BatchResult::Response(resp),
+        Err(e) => BatchResult::Error(batch_error(e)),
+    };
+    proto::BatchPutResult {
+        result: Some(result),
+    }
+}
+
+fn from_put_result(result: proto::BatchPutResult) -> Result<proto::PutResponse> {
+    use proto::batch_put_result::Result as BatchResult;
+    match result.result {
+        Some(BatchResult::Response(resp)) => Ok(resp),
+        Some(BatchResult::Error(e)) => Err(from_batch_error(e)),
+        None => Err(Error::InvalidArgument("missing batch result".to_string())),
+    }
+}
+
+fn batch_error(e: Error) -> proto::BatchError {
+    proto::BatchError {
+        code: e.code() as i32,
+        message: format!("{:?}", e),
+    }
+}
+
+fn from_batch_error(e: proto::BatchError) -> Error {
+    Error::Rpc(tonic::Status::new(tonic::Code::from(e.code), e.message))
+}
+
+// Fails each of len keys with the error a whole batch failed with
+fn batch_failure<T>(e: Error, len: usize) -> Vec<Result<T>> {
+    trace!("batch error: {:?}", e);
+    let message = format!("{:?}", e);
+    (0..len)
+        .map(|_| {
+            Err(Error::Rpc(tonic::Status::new(
+                tonic::Code::Unavailable,
+                message.clone(),
+            )))
+        })
+        .collect()
+}
+
+fn scan_entry(key: Vec<u8>, resp: proto::GetResponse) -> proto::ScanEntry {
+    proto::ScanEntry {
+        key,
+        value: resp.value,
+        version: resp.version,
+        siblings: resp.siblings,
+        context: resp.context,
+    }
+}
+
+// When a key expires: once every sibling has. None if one never expires.
+fn expiry(siblings: &[proto::Sibling]) -> Option<Expiry> {
+    siblings
+        .iter()
+        .map(|s| Some(s.expires_at).filter(|expires_at| *expires_at > 0))
+        .collect::<Option<Vec<_>>>()?
+        .into_iter()
+        .max()
+}
+
+// Decodes a stored entry into a copy of its key
+fn versioned(entry: Option<store::Entry>) -> Result<Versioned> {
+    match entry {
+        Some(store::Entry::Value(value, version, _)) => {
+            Ok((vector_clock::decode_siblings(&value)?, version))
+        }
+        Some(store::Entry::Tombstone(version)) => Ok((Vec::new(), version)),
+        None => Ok((Vec::new(), -1)),
+    }
+}
+
+// Returns the first key a scan covers and the key it ends before, empty if
+// unbounded. A continuation resumes the scan after the last key returned.
+fn scan_bounds(req: &proto::ScanRequest) -> Result<(Vec<u8>, Vec<u8>)> {
+    let (mut start, end) = if req.prefix.is_empty() {
+        (req.start.clone(), req.end.clone())
+    } else if req.start.is_empty() && req.end.is_empty() {
+        (req.prefix.clone(), prefix_end(&req.prefix))
+    } else {
+        return Err(Error::InvalidArgument(
+            "prefix with start or end".to_string(),
+        ));
+    };
+    if !req.continuation.is_empty() {
+        let mut after = req.continuation.clone();
+        after.push(0);
+        start = start.max(after);
+    }
+    Ok((start, end))
+}
+
+// The least key after every key starting with prefix. Empty if there's none.
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            break;
+        }
+    }
+    end
+}
+
+fn scan_limit(req: &proto::ScanRequest) -> usize {
+    match req.limit as usize {
+        0 => DEFAULT_SCAN_LIMIT,
+        limit => limit.min(MAX_SCAN_LIMIT),
+    }
+}
+
+fn expected_version(condition: &Option<proto::put_request::Condition>) -> Option<Version> {
+    condition.as_ref().map(|condition| match condition {
+        proto::put_request::Condition::ExpectedVersion(version) => *version,
+    })
+}
+
+// Whether a replica rejected a conditional put, locally or over rpc
+fn is_version_conflict(e: &Error) -> bool {
+    match e {
+        Error::VersionConflict => true,
+        Error::Rpc(status) => status.code() == tonic::Code::Aborted,
+        _ => false,
+    }
+}
+
+// The digest of an entry as the Merkle tree holds it
+fn entry_digest(entry: &store::Entry) -> u64 {
+    match entry {
+        store::Entry::Value(value, version, _) => merkle::digest(Some(value), *version),
+        store::Entry::Tombstone(version) => merkle::digest(None, *version),
+    }
+}
+
+// Builds a Merkle tree over every entry in store
+fn build_merkle_tree(store: &dyn store::Store, membership: &Membership) -> Result<MerkleTree> {
+    let mut merkle = MerkleTree::new();
+    let mut start = Key(Vec::new());
+    loop {
+        let entries = store.scan(&start, SCAN_PAGE_SIZE)?;
+        let last = match entries.last() {
+            Some((key, _)) => key.clone(),
+            None => break,
+        };
+        for (Key(key), entry) in entries {
+            let point = membership.ring().point(&key);
+            merkle.insert(point, &key, entry_digest(&entry));
+        }
+        // The smallest key after last
+        start = last;
+        start.0.push(0);
+    }
+    Ok(merkle)
 }
@@ -1,9 +1,12 @@
 // GRPC service wrappers
 use super::Server;
-use crate::error::Result;
+use crate::error::{Error, Result};
 use crate::proto;
 use log::trace;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::stream::{Stream, StreamExt};
 use tonic;
 
 pub struct RkvService {
@@ -28,7 +31,8 @@ impl proto::rkv_service_server::RkvService for RkvService {
         request: tonic::Request<proto::PutRequest>,
     ) -> std::result::Result<tonic::Response<proto::PutResponse>, tonic::Status> {
         trace!("put");
-        map_response(self.server.put(request.into_inner()).await)
+        let timeout = client_timeout(&request);
+        map_response(self.server.put(request.into_inner(), timeout).await)
     }
 
     async fn get(
@@ -36,7 +40,8 @@ impl proto::rkv_service_server::RkvService for RkvService {
         request: tonic::Request<proto::GetRequest>,
     ) -> std::result::Result<tonic::Response<proto::GetResponse>, tonic::Status> {
         trace!("get");
-        map_response(self.server.get(request.into_inner()).await)
+        let timeout = client_timeout(&request);
+        map_response(self.server.get(request.into_inner(), timeout).await)
     }
 
     async fn delete(
@@ -44,7 +49,35 @@ impl proto::rkv_service_server::RkvService for RkvService {
         request: tonic::Request<proto::DeleteRequest>,
     ) -> std::result::Result<tonic::Response<proto::DeleteResponse>, tonic::Status> {
         trace!("delete");
-        map_response(self.server.delete(request.into_inner()).await)
+        let timeout = client_timeout(&request);
+        map_response(self.server.delete(request.into_inner(), timeout).await)
+    }
+
+    async fn scan(
+        &self,
+        request: tonic::Request<proto::ScanRequest>,
+    ) -> std::result::Result<tonic::Response<proto::ScanResponse>, tonic::Status> {
+        trace!("scan");
+        let timeout = client_timeout(&request);
+        map_response(self.server.scan(request.into_inner(), timeout).await)
+    }
+
+    async fn batch_get(
+        &self,
+        request: tonic::Request<proto::BatchGetRequest>,
+    ) -> std::result::Result<tonic::Response<proto::BatchGetResponse>, tonic::Status> {
+        trace!("batch_get");
+        let timeout = client_timeout(&request);
+        map_response(self.server.batch_get(request.into_inner(), timeout).await)
+    }
+
+    async fn batch_put(
+        &self,
+        request: tonic::Request<proto::BatchPutRequest>,
+    ) -> std::result::Result<tonic::Response<proto::BatchPutResponse>, tonic::Status> {
+        trace!("batch_put");
+        let timeout = client_timeout(&request);
+        map_response(self.server.batch_put(request.into_inner(), timeout).await)
     }
 
     async fn heartbeat(
@@ -54,6 +87,14 @@ impl proto::rkv_service_server::RkvService for RkvService {
         trace!("heartbeat");
         map_response(self.server.heartbeat(request.into_inner()).await)
     }
+
+    async fn get_metrics(
+        &self,
+        request: tonic::Request<proto::GetMetricsRequest>,
+    ) -> std::result::Result<tonic::Response<proto::GetMetricsResponse>, tonic::Status> {
+        trace!("get_metrics");
+        map_response(self.server.get_metrics(request.into_inner()).await)
+    }
 }
 
 #[tonic::async_trait]
@@ -90,25 +131,52 @@ impl proto::peer_service_server::PeerService for PeerService {
         map_response(self.server.direct_delete(request.into_inner()).await)
     }
 
+    async fn direct_scan(
+        &self,
+        request: tonic::Request<proto::ScanRequest>,
+    ) -> std::result::Result<tonic::Response<proto::ScanResponse>, tonic::Status> {
+        trace!("direct_scan");
+        map_response(self.server.direct_scan(request.into_inner()).await)
+    }
+
+    async fn direct_batch_get(
+        &self,
+        request: tonic::Request<proto::BatchGetRequest>,
+    ) -> std::result::Result<tonic::Response<proto::BatchGetResponse>, tonic::Status> {
+        trace!("direct_batch_get");
+        map_response(self.server.direct_batch_get(request.into_inner()).await)
+    }
+
+    async fn direct_batch_put(
+        &self,
+        request: tonic::Request<proto::BatchPutRequest>,
+    ) -> std::result::Result<tonic::Response<proto::BatchPutResponse>, tonic::Status> {
+        trace!("direct_batch_put");
+        map_response(self.server.direct_batch_put(request.into_inner()).await)
+    }
+
     async fn join_network(
         &self,
         request: tonic::Request<proto::JoinNetworkRequest>,
     ) -> std::result::Result<tonic::Response<proto::JoinNetworkResponse>, tonic::Status> {
-        todo!();
+        trace!("join_network");
+        map_response(self.server.join_network(request.into_inner()).await)
     }
 
     async fn leave_network(
         &self,
         request: tonic::Request<proto::LeaveNetworkRequest>,
     ) -> std::result::Result<tonic::Response<proto::LeaveNetworkResponse>, tonic::Status> {
-        todo!();
+        trace!("leave_network");
+        map_response(self.server.leave_network(request.into_inner()).await)
     }
 
     async fn gossip(
         &self,
         request: tonic::Request<proto::GossipRequest>,
     ) -> std::result::Result<tonic::Response<proto::GossipResponse>, tonic::Status> {
-        todo!();
+        trace!("gossip");
+        map_response(self.server.gossip(request.into_inner()).await)
     }
 
     async fn heartbeat(
@@ -118,10 +186,93 @@ impl proto::peer_service_server::PeerService for PeerService {
         trace!("heartbeat");
         map_response(self.server.heartbeat(request.into_inner()).await)
     }
+
+    async fn merkle_nodes(
+        &self,
+        request: tonic::Request<proto::MerkleNodesRequest>,
+    ) -> std::result::Result<tonic::Response<proto::MerkleNodesResponse>, tonic::Status> {
+        trace!("merkle_nodes");
+        map_response(self.server.merkle_nodes(request.into_inner()).await)
+    }
+
+    async fn merkle_keys(
+        &self,
+        request: tonic::Request<proto::MerkleKeysRequest>,
+    ) -> std::result::Result<tonic::Response<proto::MerkleKeysResponse>, tonic::Status> {
+        trace!("merkle_keys");
+        map_response(self.server.merkle_keys(request.into_inner()).await)
+    }
+
+    type StreamRangeStream = Pin<
+        Box<dyn Stream<Item = std::result::Result<proto::RangeEntry, tonic::Status>> + Send + Sync>,
+    >;
+
+    async fn stream_range(
+        &self,
+        request: tonic::Request<proto::StreamRangeRequest>,
+    ) -> std::result::Result<tonic::Response<Self::StreamRangeStream>, tonic::Status> {
+        trace!("stream_range");
+        let entries = self.server.stream_range(request.into_inner());
+        Ok(tonic::Response::new(Box::pin(
+            entries.map(|entry| entry.map_err(to_status)),
+        )))
+    }
+
+    async fn hand_off_range(
+        &self,
+        request: tonic::Request<tonic::Streaming<proto::RangeEntry>>,
+    ) -> std::result::Result<tonic::Response<proto::HandOffRangeResponse>, tonic::Status> {
+        trace!("hand_off_range");
+        map_response(self.server.hand_off_range(request.into_inner()).await)
+    }
 }
 
 // TODO: Fix response error
 fn map_response<T>(resp: Result<T>) -> std::result::Result<tonic::Response<T>, tonic::Status> {
     resp.map(|result| tonic::Response::new(result))
-        .map_err(|e| tonic::Status::new(tonic::Code::Internal, format!("{:?}", e)))
+        .map_err(to_status)
+}
+
+fn to_status(e: Error) -> tonic::Status {
+    tonic::Status::new(e.code(), format!("{:?}", e))
+}
+
+// The time left before the client's deadline, sent in the grpc-timeout
+// header as an integer and a unit
+fn client_timeout<T>(request: &tonic::Request<T>) -> Option<Duration> {
+    let timeout = request.metadata().get("grpc-timeout")?.to_str().ok()?;
+    parse_timeout(timeout)
+}
+
+fn parse_timeout(timeout: &str) -> Option<Duration> {
+    if timeout.is_empty() || !timeout.is_ascii() {
+        return None;
+    }
+    let (value, unit) = timeout.split_at(timeout.len() - 1);
+    let value: u64 = value.parse().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(value * 60 * 60)),
+        "M" => Some(Duration::from_secs(value * 60)),
+        "S" => Some(Duration::from_secs(value)),
+        "m" => Some(Duration::from_millis(value)),
+        "u" => Some(Duration::from_micros(value)),
+        "n" => Some(Duration::from_nanos(value)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_timeout() {
+        assert_eq!(parse_timeout("250m"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_timeout("2S"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_timeout("1H"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_timeout("100n"), Some(Duration::from_nanos(100)));
+        assert_eq!(parse_timeout(""), None);
+        assert_eq!(parse_timeout("m"), None);
+        assert_eq!(parse_timeout("10x"), None);
+    }
 }
@@ -0,0 +1,224 @@
+use crate::error::{Error, Result};
+use crate::proto::{ClockEntry, Sibling, Siblings, VectorClock};
+use prost::Message;
+use std::cmp::Ordering;
+
+// Causal versioning with vector clocks, as in Dynamo.
+// https://www.allthingsdistributed.com/files/amazon-dynamo-sosp2007.pdf
+//
+// Every coordinated put stamps its value with the clock from the client's
+// causal context, advanced at the coordinator. A value whose clock descends
+// another's supersedes it. Values whose clocks are concurrent are kept side
+// by side as siblings until a put whose context covers them all resolves
+// them.
+//
+// Sibling sets are kept canonical, sorted by clock then value, so replicas
+// holding the same siblings store identical bytes.
+
+// Returns a copy of clock advanced at node. Counters are drawn from a
+// per-node sequence rather than incremented, so writes coordinated by the
+// same node never share a clock.
+pub fn advance(clock: &VectorClock, node: &str, counter: u64) -> VectorClock {
+    let mut clock = clock.clone();
+    match clock.entries.iter_mut().find(|e| e.node == node) {
+        Some(entry) => entry.counter = entry.counter.max(counter),
+        None => {
+            clock.entries.push(ClockEntry {
+                node: node.to_string(),
+                counter,
+            });
+            clock.entries.sort_by(|a, b| a.node.cmp(&b.node));
+        }
+    }
+    clock
+}
+
+// Whether a has seen every write b has
+pub fn descends(a: &VectorClock, b: &VectorClock) -> bool {
+    b.entries.iter().all(|e| counter(a, &e.node) >= e.counter)
+}
+
+// The least clock descending both a and b
+pub fn merge(a: &VectorClock, b: &VectorClock) -> VectorClock {
+    let mut clock = a.clone();
+    for entry in &b.entries {
+        clock = advance(&clock, &entry.node, entry.counter);
+    }
+    clock
+}
+
+// Drops every sibling superseded by another and duplicates, returning the
+// rest in canonical order
+pub fn reconcile(siblings: Vec<Sibling>) -> Vec<Sibling> {
+    let mut siblings: Vec<_> = siblings.into_iter().map(canonical).collect();
+    siblings.sort_by(compare);
+    siblings.dedup_by(|a, b| compare(a, b) == Ordering::Equal);
+    let clocks: Vec<_> = siblings.iter().map(clock).cloned().collect();
+    siblings
+        .into_iter()
+        .filter(|s| {
+            !clocks
+                .iter()
+                .any(|c| c != clock(s) && descends(c, clock(s)))
+        })
+        .collect()
+}
+
+// The clock covering every sibling, returned to clients as the causal
+// context
+pub fn context(siblings: &[Sibling]) -> VectorClock {
+    siblings
+        .iter()
+        .fold(VectorClock::default(), |acc, s| merge(&acc, clock(s)))
+}
+
+pub fn encode_clock(clock: &VectorClock) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(clock.encoded_len());
+    clock.encode(&mut buf).expect("vec has capacity");
+    buf
+}
+
+pub fn decode_clock(buf: &[u8]) -> Result<VectorClock> {
+    VectorClock::decode(buf)
+        .map(canonical_clock)
+        .map_err(|_| Error::InvalidArgument("invalid causal context".to_string()))
+}
+
+pub fn encode_siblings(siblings: Vec<Sibling>) -> Vec<u8> {
+    let siblings = Siblings { siblings };
+    let mut buf = Vec::with_capacity(siblings.encoded_len());
+    siblings.encode(&mut buf).expect("vec has capacity");
+    buf
+}
+
+pub fn decode_siblings(buf: &[u8]) -> Result<Vec<Sibling>> {
+    Siblings::decode(buf)
+        .map(|s| s.siblings)
+        .map_err(|_| Error::Corruption("invalid siblings".to_string()))
+}
+
+fn counter(clock: &VectorClock, node: &str) -> u64 {
+    clock
+        .entries
+        .iter()
+        .find(|e| e.node == node)
+        .map_or(0, |e| e.counter)
+}
+
+fn clock(sibling: &Sibling) -> &VectorClock {
+    sibling
+        .clock
+        .as_ref()
+        .expect("canonical sibling has a clock")
+}
+
+fn canonical(sibling: Sibling) -> Sibling {
+    Sibling {
+        clock: Some(canonical_clock(sibling.clock.unwrap_or_default())),
+        ..sibling
+    }
+}
+
+// Sorts entries by node, dropping zero counters and duplicate nodes
+fn canonical_clock(clock: VectorClock) -> VectorClock {
+    let mut entries: Vec<_> = clock
+        .entries
+        .into_iter()
+        .filter(|e| e.counter > 0)
+        .collect();
+    entries.sort_by(|a, b| a.node.cmp(&b.node).then(b.counter.cmp(&a.counter)));
+    entries.dedup_by(|a, b| a.node == b.node);
+    VectorClock { entries }
+}
+
+fn compare(a: &Sibling, b: &Sibling) -> Ordering {
+    let entries = |s: &Sibling| {
+        clock(s)
+            .entries
+            .iter()
+            .map(|e| (e.node.clone(), e.counter))
+            .collect::<Vec<_>>()
+    };
+    entries(a)
+        .cmp(&entries(b))
+        .then_with(|| a.value.cmp(&b.value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vc(entries: &[(&str, u64)]) -> VectorClock {
+        VectorClock {
+            entries: entries
+                .iter()
+                .map(|(node, counter)| ClockEntry {
+                    node: node.to_string(),
+                    counter: *counter,
+                })
+                .collect(),
+        }
+    }
+
+    fn sibling(value: &str, clock: VectorClock) -> Sibling {
+        Sibling {
+            value: value.as_bytes().to_vec(),
+            clock: Some(clock),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_vector_clock() {
+        let a = advance(&VectorClock::default(), "a", 1);
+        let ab = advance(&a, "b", 5);
+        assert_eq!(ab, vc(&[("a", 1), ("b", 5)]));
+        assert!(descends(&ab, &a));
+        assert!(!descends(&a, &ab));
+        assert!(descends(&a, &VectorClock::default()));
+
+        let ac = advance(&a, "c", 2);
+        assert!(!descends(&ab, &ac) && !descends(&ac, &ab));
+        assert_eq!(merge(&ab, &ac), vc(&[("a", 1), ("b", 5), ("c", 2)]));
+
+        // Counters never go backwards
+        assert_eq!(advance(&ab, "b", 3), ab);
+
+        let buf = encode_clock(&ab);
+        assert_eq!(decode_clock(&buf).unwrap(), ab);
+        assert!(decode_clock(&[0xff]).is_err());
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let a1 = sibling("a1", vc(&[("a", 1)]));
+        let a2 = sibling("a2", vc(&[("a", 2)]));
+        let b1 = sibling("b1", vc(&[("a", 1), ("b", 1)]));
+
+        // Newer writes supersede older ones
+        assert_eq!(reconcile(vec![a1.clone(), a2.clone()]), vec![a2.clone()]);
+        assert_eq!(reconcile(vec![b1.clone(), a1.clone()]), vec![b1.clone()]);
+
+        // Concurrent writes are both kept, in the same order regardless of
+        // arrival
+        let siblings = reconcile(vec![b1.clone(), a2.clone(), a1.clone(), b1.clone()]);
+        assert_eq!(siblings, vec![b1.clone(), a2.clone()]);
+        assert_eq!(reconcile(vec![a2.clone(), b1.clone()]), siblings);
+        assert_eq!(
+            encode_siblings(siblings.clone()),
+            encode_siblings(reconcile(vec![b1.clone(), a2.clone()]))
+        );
+
+        // A write with their context resolves them
+        let ctx = context(&siblings);
+        assert_eq!(ctx, vc(&[("a", 2), ("b", 1)]));
+        let resolved = sibling("c", advance(&ctx, "c", 1));
+        assert_eq!(
+            reconcile(vec![a2, b1, resolved.clone()]),
+            vec![resolved.clone()]
+        );
+
+        let buf = encode_siblings(vec![resolved.clone()]);
+        assert_eq!(decode_siblings(&buf).unwrap(), vec![resolved]);
+    }
+}
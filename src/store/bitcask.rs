@@ -0,0 +1,556 @@
+use super::{check_version, is_expired, Entry, Store};
+use crate::error::{Error, Result};
+use crate::{Expiry, Key, Value, ValueVersion, Version};
+use log::{info, warn};
+use std::collections::{hash_map, BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// A log-structured store modeled after Basho's Bitcask
+// https://riak.com/assets/bitcask-intro.pdf
+//
+// Every mutation is appended to the active data file. An in-memory key
+// directory maps each key to the location of its latest value or to its
+// tombstone. Purging a key appends a record that drops it from the key
+// directory. Data
+// files are rotated once they reach max_file_size and are never modified
+// again. On startup the key directory is rebuilt by scanning every data file
+// in order.
+//
+// Record layout:
+//   crc: u32 | flags: u8 | version: i64 | expires: u64 | key_len: u32 | value_len: u32 | key | value
+// The crc covers every byte following it. expires is 0 for values that
+// never expire.
+pub struct BitcaskStore {
+    inner: Mutex<Inner>,
+}
+
+const DATA_FILE_EXT: &str = "data";
+const DEFAULT_MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+const HEADER_LEN: usize = 29;
+const FLAG_TOMBSTONE: u8 = 1;
+const FLAG_PURGE: u8 = 2;
+
+struct Inner {
+    folder: PathBuf,
+    max_file_size: u64,
+    keydir: BTreeMap<Key, KeydirEntry>,
+    active: ActiveFile,
+    readers: HashMap<u64, File>,
+}
+
+struct ActiveFile {
+    id: u64,
+    file: File,
+    size: u64,
+}
+
+struct KeydirEntry {
+    file_id: u64,
+    value_offset: u64,
+    value_len: u32,
+    version: Version,
+    expires: Option<Expiry>,
+    tombstone: bool,
+}
+
+struct Record {
+    flags: u8,
+    version: Version,
+    expires: Option<Expiry>,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl BitcaskStore {
+    pub fn open<P: AsRef<Path>>(folder: P) -> Result<Self> {
+        Self::open_with_max_file_size(folder, DEFAULT_MAX_FILE_SIZE)
+    }
+
+    pub fn open_with_max_file_size<P: AsRef<Path>>(folder: P, max_file_size: u64) -> Result<Self> {
+        let folder = folder.as_ref().to_path_buf();
+        fs::create_dir_all(&folder)?;
+
+        let mut keydir = BTreeMap::new();
+        let mut readers = HashMap::new();
+        let file_ids = list_data_files(&folder)?;
+        for (i, id) in file_ids.iter().enumerate() {
+            let is_last = i == file_ids.len() - 1;
+            let path = data_file_path(&folder, *id);
+            let end = load_data_file(&path, *id, &mut keydir)?;
+            let len = fs::metadata(&path)?.len();
+            if end < len {
+                if !is_last {
+                    return Err(Error::Corruption(format!(
+                        "invalid record at offset {} in {}",
+                        end,
+                        path.display()
+                    )));
+                }
+                // A crash mid-append leaves a partial record at the tail of
+                // the active file. Drop it.
+                warn!(
+                    "truncating {} from {} to {} bytes",
+                    path.display(),
+                    len,
+                    end
+                );
+                OpenOptions::new().write(true).open(&path)?.set_len(end)?;
+            }
+            readers.insert(*id, File::open(&path)?);
+        }
+
+        let active = match file_ids.last() {
+            Some(id) if fs::metadata(data_file_path(&folder, *id))?.len() < max_file_size => {
+                ActiveFile::open(&folder, *id)?
+            }
+            Some(id) => ActiveFile::open(&folder, id + 1)?,
+            None => ActiveFile::open(&folder, 0)?,
+        };
+        if let hash_map::Entry::Vacant(e) = readers.entry(active.id) {
+            e.insert(File::open(data_file_path(&folder, active.id))?);
+        }
+
+        info!(
+            "opened bitcask store at {} with {} keys in {} files",
+            folder.display(),
+            keydir.len(),
+            readers.len()
+        );
+
+        Ok(Self {
+            inner: Mutex::new(Inner {
+                folder,
+                max_file_size,
+                keydir,
+                active,
+                readers,
+            }),
+        })
+    }
+}
+
+// TODO: Merge immutable data files to reclaim space from stale records
+// TODO: lock().unwrap()??? Handle poisoned locks.
+impl Store for BitcaskStore {
+    fn put(
+        &self,
+        key: Key,
+        val: Value,
+        version: Version,
+        expected: Option<Version>,
+        expires: Option<Expiry>,
+    ) -> Result<Version> {
+        let mut inner = self.inner.lock().unwrap();
+        let current = match inner.keydir.get(&key) {
+            Some(e) if !e.tombstone && !is_expired(e.expires) => e.version,
+            _ => -1,
+        };
+        check_version(current, expected)?;
+        inner.write(Record {
+            flags: 0,
+            version,
+            expires,
+            key: key.0,
+            value: val,
+        })?;
+        Ok(version)
+    }
+
+    fn entry(&self, key: &Key) -> Result<Option<Entry>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.read(key)
+    }
+
+    fn delete(&self, key: &Key, version: Version) -> Result<Option<ValueVersion>> {
+        let mut inner = self.inner.lock().unwrap();
+        let prev = match inner.read(key)? {
+            Some(Entry::Value(value, version, _)) => Some((value, version)),
+            _ => None,
+        };
+        inner.write(Record {
+            flags: FLAG_TOMBSTONE,
+            version,
+            expires: None,
+            key: key.0.clone(),
+            value: Vec::new(),
+        })?;
+        Ok(prev)
+    }
+
+    fn purge(&self, key: &Key) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.keydir.contains_key(key) {
+            inner.write(Record {
+                flags: FLAG_PURGE,
+                version: 0,
+                expires: None,
+                key: key.0.clone(),
+                value: Vec::new(),
+            })?;
+        }
+        Ok(())
+    }
+
+    fn scan(&self, start: &Key, limit: usize) -> Result<Vec<(Key, Entry)>> {
+        let mut inner = self.inner.lock().unwrap();
+        let keys: Vec<Key> = inner
+            .keydir
+            .range(start..)
+            .take(limit)
+            .map(|(k, _)| k.clone())
+            .collect();
+        let mut entries = Vec::with_capacity(keys.len());
+        for key in keys {
+            let entry = inner.read(&key)?.expect("missing keydir entry");
+            entries.push((key, entry));
+        }
+        Ok(entries)
+    }
+
+    fn durable(&self) -> bool {
+        true
+    }
+
+    // Rotated files are synced on rotation, leaving only the active file
+    fn flush(&self) -> Result<()> {
+        let inner = self.inner.lock().unwrap();
+        inner.active.file.sync_data()?;
+        Ok(())
+    }
+}
+
+impl Inner {
+    // Appends a record and applies it to the key directory
+    fn write(&mut self, record: Record) -> Result<()> {
+        let (file_id, value_offset) = self.append(&record)?;
+        apply(&mut self.keydir, record, file_id, value_offset);
+        Ok(())
+    }
+
+    // Appends a record to the active file, returning its id and the offset
+    // of the record's value
+    fn append(&mut self, record: &Record) -> Result<(u64, u64)> {
+        if self.active.size >= self.max_file_size {
+            self.rotate()?;
+        }
+        let buf = record.encode();
+        self.active.file.write_all(&buf)?;
+        let value_offset = self.active.size + (HEADER_LEN + record.key.len()) as u64;
+        self.active.size += buf.len() as u64;
+        Ok((self.active.id, value_offset))
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.active.file.sync_all()?;
+        let id = self.active.id + 1;
+        self.active = ActiveFile::open(&self.folder, id)?;
+        self.readers
+            .insert(id, File::open(data_file_path(&self.folder, id))?);
+        Ok(())
+    }
+
+    fn read(&mut self, key: &Key) -> Result<Option<Entry>> {
+        let (file_id, value_offset, value_len, version, expires) = match self.keydir.get(key) {
+            Some(e) if e.tombstone => return Ok(Some(Entry::Tombstone(e.version))),
+            Some(e) => (e.file_id, e.value_offset, e.value_len, e.version, e.expires),
+            None => return Ok(None),
+        };
+        let file = self
+            .readers
+            .get_mut(&file_id)
+            .expect("missing reader for data file");
+        let mut value = vec![0; value_len as usize];
+        file.seek(SeekFrom::Start(value_offset))?;
+        file.read_exact(&mut value)?;
+        Ok(Some(Entry::Value(value, version, expires)))
+    }
+}
+
+impl ActiveFile {
+    fn open(folder: &Path, id: u64) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(data_file_path(folder, id))?;
+        let size = file.metadata()?.len();
+        Ok(Self { id, file, size })
+    }
+}
+
+impl Record {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.key.len() + self.value.len());
+        buf.extend_from_slice(&[0; 4]);
+        buf.push(self.flags);
+        buf.extend_from_slice(&self.version.to_le_bytes());
+        buf.extend_from_slice(&self.expires.unwrap_or(0).to_le_bytes());
+        buf.extend_from_slice(&(self.key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(self.value.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&self.key);
+        buf.extend_from_slice(&self.value);
+        let crc = crc32fast::hash(&buf[4..]);
+        buf[..4].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    // Reads the next record from the remaining bytes of a file. Returns None
+    // at a clean end of file or if they do not form a complete, valid record.
+    fn decode<R: Read>(r: &mut R, remaining: u64) -> Result<Option<(Record, usize)>> {
+        let mut header = [0; HEADER_LEN];
+        if !read_full(r, &mut header)? {
+            return Ok(None);
+        }
+        let crc = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let flags = header[4];
+        let version = i64::from_le_bytes(header[5..13].try_into().unwrap());
+        let expires = u64::from_le_bytes(header[13..21].try_into().unwrap());
+        let key_len = u32::from_le_bytes(header[21..25].try_into().unwrap()) as usize;
+        let value_len = u32::from_le_bytes(header[25..29].try_into().unwrap()) as usize;
+        // A torn header may claim more bytes than the file holds
+        if (HEADER_LEN + key_len + value_len) as u64 > remaining {
+            return Ok(None);
+        }
+
+        let mut body = vec![0; key_len + value_len];
+        if !read_full(r, &mut body)? {
+            return Ok(None);
+        }
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header[4..]);
+        hasher.update(&body);
+        if hasher.finalize() != crc {
+            return Ok(None);
+        }
+
+        let value = body.split_off(key_len);
+        let record = Record {
+            flags,
+            version,
+            expires: Some(expires).filter(|expires| *expires > 0),
+            key: body,
+            value,
+        };
+        Ok(Some((record, HEADER_LEN + key_len + value_len)))
+    }
+}
+
+// Replays a data file into the key directory, returning the offset just past
+// the last valid record
+fn load_data_file(path: &Path, id: u64, keydir: &mut BTreeMap<Key, KeydirEntry>) -> Result<u64> {
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut offset = 0;
+    while let Some((record, len)) = Record::decode(&mut reader, file_len - offset)? {
+        let value_offset = offset + (HEADER_LEN + record.key.len()) as u64;
+        apply(keydir, record, id, value_offset);
+        offset += len as u64;
+    }
+    Ok(offset)
+}
+
+// Updates the key directory with a record written at value_offset in file_id
+fn apply(keydir: &mut BTreeMap<Key, KeydirEntry>, record: Record, file_id: u64, value_offset: u64) {
+    if record.flags & FLAG_PURGE != 0 {
+        keydir.remove(&Key(record.key));
+        return;
+    }
+    keydir.insert(
+        Key(record.key),
+        KeydirEntry {
+            file_id,
+            value_offset,
+            value_len: record.value.len() as u32,
+            version: record.version,
+            expires: record.expires,
+            tombstone: record.flags & FLAG_TOMBSTONE != 0,
+        },
+    );
+}
+
+fn list_data_files(folder: &Path) -> Result<Vec<u64>> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(folder)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(DATA_FILE_EXT) {
+            continue;
+        }
+        if let Some(id) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok())
+        {
+            ids.push(id);
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+fn data_file_path(folder: &Path, id: u64) -> PathBuf {
+    folder.join(format!("{:010}.{}", id, DATA_FILE_EXT))
+}
+
+// Fills buf, returning false if the reader hit end of file first
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> Result<bool> {
+    match r.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(s: &str) -> Key {
+        Key(s.as_bytes().to_vec())
+    }
+
+    fn val(s: &str) -> Value {
+        s.as_bytes().to_vec()
+    }
+
+    #[test]
+    fn test_bitcask_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BitcaskStore::open(dir.path()).unwrap();
+
+        assert_eq!(store.get(&key("k")).unwrap(), None);
+        store.put(key("k"), val("v0"), 0, None, None).unwrap();
+        store.put(key("k"), val("v1"), 0, None, None).unwrap();
+        assert_eq!(store.get(&key("k")).unwrap(), Some((val("v1"), 0)));
+
+        store.put(key("j"), val("v"), 0, None, None).unwrap();
+        store.put(key("l"), val("v"), 0, None, None).unwrap();
+        assert_eq!(
+            store.scan(&key("k"), 10).unwrap(),
+            vec![
+                (key("k"), Entry::Value(val("v1"), 0, None)),
+                (key("l"), Entry::Value(val("v"), 0, None))
+            ]
+        );
+        assert_eq!(
+            store.scan(&key("a"), 1).unwrap(),
+            vec![(key("j"), Entry::Value(val("v"), 0, None))]
+        );
+
+        assert_eq!(store.delete(&key("k"), 1).unwrap(), Some((val("v1"), 0)));
+        assert_eq!(store.get(&key("k")).unwrap(), None);
+        assert_eq!(store.delete(&key("k"), 2).unwrap(), None);
+        assert_eq!(
+            store.scan(&key("k"), 10).unwrap(),
+            vec![
+                (key("k"), Entry::Tombstone(2)),
+                (key("l"), Entry::Value(val("v"), 0, None))
+            ]
+        );
+        store.purge(&key("k")).unwrap();
+        assert_eq!(store.entry(&key("k")).unwrap(), None);
+
+        // Conditional puts
+        assert!(store.put(key("l"), val("v2"), 3, Some(-1), None).is_err());
+        assert_eq!(store.put(key("l"), val("v2"), 3, Some(0), None).unwrap(), 3);
+        assert!(store.put(key("l"), val("v3"), 4, Some(0), None).is_err());
+        assert_eq!(store.get(&key("l")).unwrap(), Some((val("v2"), 3)));
+        assert_eq!(store.put(key("k"), val("v"), 5, Some(-1), None).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_bitcask_store_recovery() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = BitcaskStore::open_with_max_file_size(dir.path(), 64).unwrap();
+            for i in 0..10 {
+                store
+                    .put(
+                        key(&format!("k{}", i)),
+                        val(&format!("v{}", i)),
+                        0,
+                        None,
+                        None,
+                    )
+                    .unwrap();
+            }
+            store.delete(&key("k3"), 3).unwrap();
+            store.put(key("k4"), val("v4'"), 4, None, None).unwrap();
+            store.delete(&key("k5"), 5).unwrap();
+            store.purge(&key("k5")).unwrap();
+            store
+                .put(key("k6"), val("v6'"), 6, None, Some(u64::MAX))
+                .unwrap();
+            store.put(key("k7"), val("v7'"), 7, None, Some(1)).unwrap();
+        }
+        assert!(list_data_files(dir.path()).unwrap().len() > 1);
+
+        let store = BitcaskStore::open_with_max_file_size(dir.path(), 64).unwrap();
+        assert_eq!(store.get(&key("k0")).unwrap(), Some((val("v0"), 0)));
+        assert_eq!(store.entry(&key("k3")).unwrap(), Some(Entry::Tombstone(3)));
+        assert_eq!(store.get(&key("k4")).unwrap(), Some((val("v4'"), 4)));
+        assert_eq!(store.entry(&key("k5")).unwrap(), None);
+        assert_eq!(store.get(&key("k6")).unwrap(), Some((val("v6'"), 6)));
+        assert_eq!(
+            store.entry(&key("k7")).unwrap(),
+            Some(Entry::Value(val("v7'"), 7, Some(1)))
+        );
+        assert_eq!(store.get(&key("k7")).unwrap(), None);
+        assert_eq!(store.get(&key("k9")).unwrap(), Some((val("v9"), 0)));
+    }
+
+    #[test]
+    fn test_bitcask_store_truncated_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = BitcaskStore::open(dir.path()).unwrap();
+            store.put(key("k0"), val("v0"), 0, None, None).unwrap();
+            store.put(key("k1"), val("v1"), 0, None, None).unwrap();
+        }
+        let path = data_file_path(dir.path(), 0);
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        let store = BitcaskStore::open(dir.path()).unwrap();
+        assert_eq!(store.get(&key("k0")).unwrap(), Some((val("v0"), 0)));
+        assert_eq!(store.get(&key("k1")).unwrap(), None);
+
+        // Appends after recovery land after the last valid record
+        store.put(key("k1"), val("v1"), 0, None, None).unwrap();
+        drop(store);
+        let store = BitcaskStore::open(dir.path()).unwrap();
+        assert_eq!(store.get(&key("k1")).unwrap(), Some((val("v1"), 0)));
+    }
+
+    #[test]
+    fn test_bitcask_store_corrupt_tail_lengths() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = BitcaskStore::open(dir.path()).unwrap();
+            store.put(key("k0"), val("v0"), 0, None, None).unwrap();
+        }
+        // A header claiming lengths far past the end of the file
+        let path = data_file_path(dir.path(), 0);
+        let len = fs::metadata(&path).unwrap().len();
+        let mut header = [0; HEADER_LEN];
+        header[21..29].copy_from_slice(&[0xff; 8]);
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&header)
+            .unwrap();
+
+        let store = BitcaskStore::open(dir.path()).unwrap();
+        assert_eq!(store.get(&key("k0")).unwrap(), Some((val("v0"), 0)));
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+    }
+}
@@ -0,0 +1,99 @@
+use crate::error::{Error, Result};
+use std::convert::TryInto;
+
+// A bloom filter over key hashes. Probes are derived from a single 64 bit
+// hash by double hashing, as in LevelDB.
+pub(super) struct Bloom {
+    k: u32,
+    bits: Vec<u64>,
+}
+
+impl Bloom {
+    pub fn new(hashes: &[u64], bits_per_key: usize) -> Self {
+        // ln(2) * bits_per_key minimizes the false positive rate
+        let k = ((bits_per_key as f64 * 0.69) as u32).clamp(1, 30);
+        let words = (hashes.len() * bits_per_key).div_ceil(64).max(1);
+        let mut bloom = Self {
+            k,
+            bits: vec![0; words],
+        };
+        for hash in hashes {
+            for bit in bloom.probes(*hash) {
+                bloom.bits[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        bloom
+    }
+
+    pub fn may_contain(&self, hash: u64) -> bool {
+        self.probes(hash)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    fn probes(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let nbits = self.bits.len() as u64 * 64;
+        let delta = hash.rotate_right(17) | 1;
+        (0..self.k as u64).map(move |i| (hash.wrapping_add(i.wrapping_mul(delta)) % nbits) as usize)
+    }
+
+    // Layout: k: u32 | words: u32 | bits: [u64]
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.k.to_le_bytes());
+        buf.extend_from_slice(&(self.bits.len() as u32).to_le_bytes());
+        for word in &self.bits {
+            buf.extend_from_slice(&word.to_le_bytes());
+        }
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let invalid = || Error::Corruption("invalid bloom filter".to_string());
+        if buf.len() < 8 {
+            return Err(invalid());
+        }
+        let k = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+        let words = u32::from_le_bytes(buf[4..8].try_into().unwrap()) as usize;
+        if k == 0 || words == 0 || buf.len() != 8 + words * 8 {
+            return Err(invalid());
+        }
+        let bits = buf[8..]
+            .chunks_exact(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        Ok(Self { k, bits })
+    }
+}
+
+// 64 bit FNV-1a. Stable across builds, unlike std's DefaultHasher, since
+// filters are persisted.
+pub(super) fn hash(key: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in key {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom() {
+        let keys: Vec<_> = (0..1000).map(|i| format!("k{}", i)).collect();
+        let hashes: Vec<_> = keys.iter().map(|k| hash(k.as_bytes())).collect();
+        let bloom = Bloom::new(&hashes, 10);
+
+        let mut buf = Vec::new();
+        bloom.encode(&mut buf);
+        let bloom = Bloom::decode(&buf).unwrap();
+
+        for h in &hashes {
+            assert!(bloom.may_contain(*h));
+        }
+        let false_positives = (0..1000)
+            .filter(|i| bloom.may_contain(hash(format!("x{}", i).as_bytes())))
+            .count();
+        assert!(false_positives < 50, "{} false positives", false_positives);
+    }
+}
@@ -0,0 +1,853 @@
+use super::{check_version, Store};
+use crate::error::{Error, Result};
+use crate::{Expiry, Key, Value, ValueVersion, Version};
+use log::{error, info, warn};
+use sstable::{Entry, Table, TableWriter};
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+mod bloom;
+mod sstable;
+
+// A log-structured merge tree inspired by LevelDB
+// https://github.com/google/leveldb/blob/master/doc/impl.md
+//
+// Writes go to a sorted in-memory memtable. Once the memtable reaches
+// memtable_size it is flushed to an immutable SSTable in level 0. A
+// background thread compacts level 0 into level 1 once it holds
+// level0_compaction_trigger tables, and level N into level N+1 once level N
+// outgrows its size limit. Tables within levels >= 1 never overlap.
+//
+// The live tables of each level are recorded in a manifest that is replaced
+// atomically after every flush and compaction. Tables missing from the
+// manifest are left over from an interrupted compaction and are deleted on
+// open.
+//
+// The memtable is not logged. Wrap the store in a WalStore for durability.
+pub struct LsmStore {
+    shared: Arc<Shared>,
+    // Held across each mutation, so conditional puts check and write
+    // atomically
+    writer: Mutex<()>,
+    compactor: Option<thread::JoinHandle<()>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    // Memtable bytes that trigger a flush to level 0
+    pub memtable_size: usize,
+    // Target size of tables written by compactions
+    pub table_size: u64,
+    // Approximate bytes between sparse index entries
+    pub block_size: u64,
+    pub bloom_bits_per_key: usize,
+    // Level 0 tables that trigger a compaction into level 1
+    pub level0_compaction_trigger: usize,
+    // Size limit of level 1. Each following level is level_size_multiplier
+    // times larger.
+    pub level_size_base: u64,
+    pub level_size_multiplier: u64,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        Self {
+            memtable_size: 4 * 1024 * 1024,
+            table_size: 2 * 1024 * 1024,
+            block_size: 4 * 1024,
+            bloom_bits_per_key: 10,
+            level0_compaction_trigger: 4,
+            level_size_base: 10 * 1024 * 1024,
+            level_size_multiplier: 10,
+        }
+    }
+}
+
+const NUM_LEVELS: usize = 7;
+const TABLE_FILE_EXT: &str = "sst";
+const MANIFEST_FILE_NAME: &str = "MANIFEST";
+const COMPACTION_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+// Version of the tombstones written by purge. Unlike those written by
+// delete, they mark keys as absent and are dropped by compactions into the
+// bottommost level.
+const PURGED: Version = i64::MIN;
+
+struct Shared {
+    folder: PathBuf,
+    options: LsmOptions,
+    state: Mutex<State>,
+    // Signaled when a compaction may be needed or the store is closing
+    compaction_cond: Condvar,
+}
+
+struct State {
+    memtable: BTreeMap<Vec<u8>, Entry>,
+    memtable_size: usize,
+    // Level 0 is ordered newest first. Other levels are ordered by key.
+    levels: Vec<Vec<Arc<Table>>>,
+    next_table_id: u64,
+    closed: bool,
+}
+
+struct Compaction {
+    level: usize,
+    inputs: Vec<Arc<Table>>,
+    // Purged keys can be dropped when no deeper level may hold them
+    bottommost: bool,
+}
+
+impl LsmStore {
+    pub fn open<P: AsRef<Path>>(folder: P) -> Result<Self> {
+        Self::open_with_options(folder, LsmOptions::default())
+    }
+
+    pub fn open_with_options<P: AsRef<Path>>(folder: P, options: LsmOptions) -> Result<Self> {
+        let folder = folder.as_ref().to_path_buf();
+        fs::create_dir_all(&folder)?;
+
+        let manifest = read_manifest(&folder)?;
+        let mut levels = vec![Vec::new(); NUM_LEVELS];
+        let mut next_table_id = 0;
+        for (level, id) in &manifest {
+            let table = Table::open(&table_path(&folder, *id), *id)?;
+            levels[*level].push(Arc::new(table));
+            next_table_id = next_table_id.max(id + 1);
+        }
+        levels[0].sort_by_key(|t| Reverse(t.id));
+        for level in &mut levels[1..] {
+            level.sort_by(|a, b| a.first_key.cmp(&b.first_key));
+        }
+
+        for entry in fs::read_dir(&folder)? {
+            let path = entry?.path();
+            let orphaned = match path.extension().and_then(|ext| ext.to_str()) {
+                Some(TABLE_FILE_EXT) => match table_id(&path) {
+                    Some(id) => {
+                        next_table_id = next_table_id.max(id + 1);
+                        !manifest.iter().any(|(_, live)| *live == id)
+                    }
+                    None => false,
+                },
+                Some("tmp") => true,
+                _ => false,
+            };
+            if orphaned {
+                warn!("removing orphaned table {}", path.display());
+                fs::remove_file(&path)?;
+            }
+        }
+
+        info!(
+            "opened lsm store at {} with {} tables",
+            folder.display(),
+            manifest.len()
+        );
+
+        let shared = Arc::new(Shared {
+            folder,
+            options,
+            state: Mutex::new(State {
+                memtable: BTreeMap::new(),
+                memtable_size: 0,
+                levels,
+                next_table_id,
+                closed: false,
+            }),
+            compaction_cond: Condvar::new(),
+        });
+        let compactor = {
+            let shared = shared.clone();
+            thread::spawn(move || shared.run_compactions())
+        };
+        shared.compaction_cond.notify_one();
+
+        Ok(Self {
+            shared,
+            writer: Mutex::new(()),
+            compactor: Some(compactor),
+        })
+    }
+
+    fn write(&self, key: Vec<u8>, entry: Entry) -> Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        state.memtable_size += entry.encoded_len(&key);
+        state.memtable.insert(key, entry);
+        if state.memtable_size >= self.shared.options.memtable_size {
+            self.shared.flush_memtable(&mut state)?;
+        }
+        Ok(())
+    }
+
+    fn read(&self, key: &[u8]) -> Result<Option<Entry>> {
+        // Tables are immutable, so they're searched without holding the lock
+        let tables: Vec<Arc<Table>> = {
+            let state = self.shared.state.lock().unwrap();
+            if let Some(entry) = state.memtable.get(key) {
+                return Ok(Some(entry.clone()));
+            }
+            let mut tables = state.levels[0].clone();
+            for level in &state.levels[1..] {
+                let i = level.partition_point(|t| &t.last_key[..] < key);
+                if let Some(table) = level.get(i) {
+                    tables.push(table.clone());
+                }
+            }
+            tables
+        };
+        for table in tables {
+            if let Some(entry) = table.get(key)? {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    // Returns up to limit entries with keys >= start by merging the
+    // memtable with every table that may hold such keys
+    fn range(&self, start: &[u8], limit: usize) -> Result<Vec<(Key, super::Entry)>> {
+        // Sources are ordered newest first: the memtable, each level 0 table
+        // and then each deeper level as a whole. Tables are opened under the
+        // lock since compactions delete them once they're replaced.
+        type Source = Box<dyn Iterator<Item = Result<(Vec<u8>, Entry)>>>;
+        let mut sources: Vec<std::iter::Peekable<Source>> = Vec::new();
+        {
+            let state = self.shared.state.lock().unwrap();
+            let memtable: Vec<_> = state
+                .memtable
+                .range(start.to_vec()..)
+                .map(|(key, entry)| Ok((key.clone(), entry.clone())))
+                .collect();
+            let memtable: Source = Box::new(memtable.into_iter());
+            sources.push(memtable.peekable());
+            for table in &state.levels[0] {
+                let iter: Source = Box::new(table.iter_from(start)?);
+                sources.push(iter.peekable());
+            }
+            for level in &state.levels[1..] {
+                let mut iters = Vec::new();
+                for table in level.iter().filter(|t| &t.last_key[..] >= start) {
+                    iters.push(table.iter_from(start)?);
+                }
+                let iter: Source = Box::new(iters.into_iter().flatten());
+                sources.push(iter.peekable());
+            }
+        }
+
+        let mut entries = Vec::new();
+        while entries.len() < limit {
+            let (key, entry) = match merge_next(&mut sources)? {
+                Some(next) => next,
+                None => break,
+            };
+            if key[..] < *start {
+                continue;
+            }
+            if let Some(entry) = stored(entry) {
+                entries.push((Key(key), entry));
+            }
+        }
+        Ok(entries)
+    }
+}
+
+impl Drop for LsmStore {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.compaction_cond.notify_one();
+        if let Some(compactor) = self.compactor.take() {
+            let _ = compactor.join();
+        }
+    }
+}
+
+// TODO: lock().unwrap()??? Handle poisoned locks.
+impl Store for LsmStore {
+    fn put(
+        &self,
+        key: Key,
+        val: Value,
+        version: Version,
+        expected: Option<Version>,
+        expires: Option<Expiry>,
+    ) -> Result<Version> {
+        let _writer = self.writer.lock().unwrap();
+        if expected.is_some() {
+            let current = self.get(&key)?.map_or(-1, |(_, version)| version);
+            check_version(current, expected)?;
+        }
+        self.write(
+            key.0,
+            Entry {
+                version,
+                expires,
+                value: Some(val),
+            },
+        )?;
+        Ok(version)
+    }
+
+    fn entry(&self, key: &Key) -> Result<Option<super::Entry>> {
+        Ok(self.read(&key.0)?.and_then(stored))
+    }
+
+    fn delete(&self, key: &Key, version: Version) -> Result<Option<ValueVersion>> {
+        let _writer = self.writer.lock().unwrap();
+        let prev = self.get(key)?;
+        self.write(
+            key.0.clone(),
+            Entry {
+                version,
+                expires: None,
+                value: None,
+            },
+        )?;
+        Ok(prev)
+    }
+
+    fn purge(&self, key: &Key) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        self.write(
+            key.0.clone(),
+            Entry {
+                version: PURGED,
+                expires: None,
+                value: None,
+            },
+        )
+    }
+
+    fn scan(&self, start: &Key, limit: usize) -> Result<Vec<(Key, super::Entry)>> {
+        self.range(&start.0, limit)
+    }
+
+    fn durable(&self) -> bool {
+        true
+    }
+
+    fn flush(&self) -> Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        self.shared.flush_memtable(&mut state)
+    }
+}
+
+impl Shared {
+    // Writes the memtable to a new level 0 table
+    fn flush_memtable(&self, state: &mut State) -> Result<()> {
+        if state.memtable.is_empty() {
+            return Ok(());
+        }
+        let id = state.next_table_id;
+        let mut writer = TableWriter::create(
+            &table_path(&self.folder, id),
+            id,
+            self.options.block_size,
+            self.options.bloom_bits_per_key,
+        )?;
+        for (key, entry) in &state.memtable {
+            writer.add(key, entry)?;
+        }
+        let table = writer.finish()?;
+        state.next_table_id += 1;
+        state.levels[0].insert(0, Arc::new(table));
+        write_manifest(&self.folder, &state.levels)?;
+        state.memtable.clear();
+        state.memtable_size = 0;
+        self.compaction_cond.notify_one();
+        Ok(())
+    }
+
+    fn run_compactions(&self) {
+        loop {
+            let compaction = {
+                let mut state = self.state.lock().unwrap();
+                loop {
+                    if state.closed {
+                        return;
+                    }
+                    if let Some(compaction) = self.pick_compaction(&state) {
+                        break compaction;
+                    }
+                    state = self.compaction_cond.wait(state).unwrap();
+                }
+            };
+            if let Err(e) = self.compact(compaction) {
+                error!("compaction failed: {}", e);
+                let state = self.state.lock().unwrap();
+                let _ = self
+                    .compaction_cond
+                    .wait_timeout(state, COMPACTION_RETRY_INTERVAL);
+            }
+        }
+    }
+
+    fn pick_compaction(&self, state: &State) -> Option<Compaction> {
+        let level = if state.levels[0].len() >= self.options.level0_compaction_trigger {
+            0
+        } else {
+            (1..NUM_LEVELS - 1).find(|level| {
+                let size: u64 = state.levels[*level].iter().map(|t| t.size).sum();
+                size > self.level_size_limit(*level)
+            })?
+        };
+
+        let mut inputs = match level {
+            // Level 0 tables may overlap, so they're compacted together
+            0 => state.levels[0].clone(),
+            // TODO: Rotate through the key space instead of always picking
+            // the first table
+            _ => vec![state.levels[level][0].clone()],
+        };
+        let first_key = inputs.iter().map(|t| &t.first_key).min().unwrap().clone();
+        let last_key = inputs.iter().map(|t| &t.last_key).max().unwrap().clone();
+        inputs.extend(
+            state.levels[level + 1]
+                .iter()
+                .filter(|t| t.overlaps(&first_key, &last_key))
+                .cloned(),
+        );
+        // The next level's tables may reach past the range they were picked
+        // by, and every key of the inputs must be absent from deeper levels
+        let first_key = inputs.iter().map(|t| &t.first_key).min().unwrap().clone();
+        let last_key = inputs.iter().map(|t| &t.last_key).max().unwrap().clone();
+        let bottommost = state.levels[level + 2..]
+            .iter()
+            .flatten()
+            .all(|t| !t.overlaps(&first_key, &last_key));
+
+        Some(Compaction {
+            level,
+            inputs,
+            bottommost,
+        })
+    }
+
+    fn level_size_limit(&self, level: usize) -> u64 {
+        let mut limit = self.options.level_size_base;
+        for _ in 1..level {
+            limit = limit.saturating_mul(self.options.level_size_multiplier);
+        }
+        limit
+    }
+
+    fn compact(&self, compaction: Compaction) -> Result<()> {
+        let output_level = compaction.level + 1;
+        info!(
+            "compacting {} tables from level {} into level {}",
+            compaction.inputs.len(),
+            compaction.level,
+            output_level
+        );
+
+        let outputs = self.merge(&compaction)?;
+        {
+            let mut state = self.state.lock().unwrap();
+            let is_input = |t: &Arc<Table>| compaction.inputs.iter().any(|i| i.id == t.id);
+            state.levels[compaction.level].retain(|t| !is_input(t));
+            state.levels[output_level].retain(|t| !is_input(t));
+            state.levels[output_level].extend(outputs.into_iter().map(Arc::new));
+            state.levels[output_level].sort_by(|a, b| a.first_key.cmp(&b.first_key));
+            write_manifest(&self.folder, &state.levels)?;
+        }
+        for table in &compaction.inputs {
+            fs::remove_file(&table.path)?;
+        }
+        Ok(())
+    }
+
+    // Merges the compaction inputs into new tables of roughly table_size,
+    // keeping only the newest entry for each key. Partial outputs are removed
+    // on failure.
+    fn merge(&self, compaction: &Compaction) -> Result<Vec<Table>> {
+        let mut outputs = Vec::new();
+        let mut writer = None;
+        let result = self.merge_into(compaction, &mut outputs, &mut writer);
+        if let Err(e) = result {
+            if let Some(writer) = writer {
+                writer.abandon();
+            }
+            for table in outputs {
+                let _ = fs::remove_file(&table.path);
+            }
+            return Err(e);
+        }
+        Ok(outputs)
+    }
+
+    fn merge_into(
+        &self,
+        compaction: &Compaction,
+        outputs: &mut Vec<Table>,
+        writer: &mut Option<TableWriter>,
+    ) -> Result<()> {
+        // Inputs are ordered newest first
+        let mut sources = Vec::new();
+        for table in &compaction.inputs {
+            sources.push(table.iter()?.peekable());
+        }
+        while let Some((key, entry)) = merge_next(&mut sources)? {
+            if entry.version == PURGED && entry.value.is_none() && compaction.bottommost {
+                continue;
+            }
+            if writer.is_none() {
+                *writer = Some(self.create_table()?);
+            }
+            let w = writer.as_mut().unwrap();
+            w.add(&key, &entry)?;
+            if w.size() >= self.options.table_size {
+                outputs.push(writer.take().unwrap().finish()?);
+            }
+        }
+        if let Some(w) = writer.take() {
+            outputs.push(w.finish()?);
+        }
+        Ok(())
+    }
+
+    fn create_table(&self) -> Result<TableWriter> {
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            state.next_table_id += 1;
+            state.next_table_id - 1
+        };
+        TableWriter::create(
+            &table_path(&self.folder, id),
+            id,
+            self.options.block_size,
+            self.options.bloom_bits_per_key,
+        )
+    }
+}
+
+// Converts an entry of the memtable or a table into the one the Store
+// returns. None for purged keys.
+fn stored(entry: Entry) -> Option<super::Entry> {
+    match entry {
+        Entry {
+            version: PURGED,
+            value: None,
+            ..
+        } => None,
+        Entry {
+            version,
+            value: None,
+            ..
+        } => Some(super::Entry::Tombstone(version)),
+        Entry {
+            version,
+            expires,
+            value: Some(value),
+        } => Some(super::Entry::Value(value, version, expires)),
+    }
+}
+
+// Returns the smallest key across sources along with its newest entry,
+// skipping older entries for the same key. Sources are ordered newest first.
+fn merge_next<I>(sources: &mut [std::iter::Peekable<I>]) -> Result<Option<(Vec<u8>, Entry)>>
+where
+    I: Iterator<Item = Result<(Vec<u8>, Entry)>>,
+{
+    let mut min: Option<(usize, Vec<u8>)> = None;
+    for (i, source) in sources.iter_mut().enumerate() {
+        match source.peek() {
+            Some(Ok((key, _))) if min.as_ref().is_none_or(|(_, min_key)| key < min_key) => {
+                min = Some((i, key.clone()));
+            }
+            Some(Err(_)) => return Err(source.next().unwrap().unwrap_err()),
+            Some(Ok(_)) | None => {}
+        }
+    }
+    let (newest, key) = match min {
+        Some(min) => min,
+        None => return Ok(None),
+    };
+    let (_, entry) = sources[newest].next().unwrap()?;
+    for source in sources.iter_mut() {
+        if let Some(Ok((k, _))) = source.peek() {
+            if *k == key {
+                source.next();
+            }
+        }
+    }
+    Ok(Some((key, entry)))
+}
+
+// Each manifest line is "<level> <table id>"
+fn read_manifest(folder: &Path) -> Result<Vec<(usize, u64)>> {
+    let contents = match fs::read_to_string(folder.join(MANIFEST_FILE_NAME)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    contents
+        .lines()
+        .map(|line| {
+            let mut parts = line.split_whitespace();
+            let level = parts.next().and_then(|level| level.parse::<usize>().ok());
+            let id = parts.next().and_then(|id| id.parse::<u64>().ok());
+            match (level, id) {
+                (Some(level), Some(id)) if level < NUM_LEVELS => Ok((level, id)),
+                _ => Err(Error::Corruption(format!(
+                    "invalid manifest line: {}",
+                    line
+                ))),
+            }
+        })
+        .collect()
+}
+
+fn write_manifest(folder: &Path, levels: &[Vec<Arc<Table>>]) -> Result<()> {
+    let mut contents = String::new();
+    for (level, tables) in levels.iter().enumerate() {
+        for table in tables {
+            contents.push_str(&format!("{} {}\n", level, table.id));
+        }
+    }
+    let tmp_path = folder.join(format!("{}.tmp", MANIFEST_FILE_NAME));
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, folder.join(MANIFEST_FILE_NAME))?;
+    Ok(())
+}
+
+fn table_path(folder: &Path, id: u64) -> PathBuf {
+    folder.join(format!("{:010}.{}", id, TABLE_FILE_EXT))
+}
+
+fn table_id(path: &Path) -> Option<u64> {
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Entry;
+
+    fn key(s: &str) -> Key {
+        Key(s.as_bytes().to_vec())
+    }
+
+    fn val(s: &str) -> Value {
+        s.as_bytes().to_vec()
+    }
+
+    fn small_options() -> LsmOptions {
+        LsmOptions {
+            memtable_size: 256,
+            table_size: 512,
+            block_size: 64,
+            bloom_bits_per_key: 10,
+            level0_compaction_trigger: 2,
+            level_size_base: 1024,
+            level_size_multiplier: 2,
+        }
+    }
+
+    // Waits for the background compactor to go idle
+    fn wait_for_compactions(store: &LsmStore) {
+        loop {
+            {
+                let state = store.shared.state.lock().unwrap();
+                if store.shared.pick_compaction(&state).is_none() {
+                    return;
+                }
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_lsm_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LsmStore::open(dir.path()).unwrap();
+
+        assert_eq!(store.get(&key("k")).unwrap(), None);
+        store.put(key("k"), val("v0"), 0, None, None).unwrap();
+        store.put(key("k"), val("v1"), 0, None, None).unwrap();
+        assert_eq!(store.get(&key("k")).unwrap(), Some((val("v1"), 0)));
+
+        store.flush().unwrap();
+        assert_eq!(store.get(&key("k")).unwrap(), Some((val("v1"), 0)));
+
+        assert_eq!(store.delete(&key("k"), 1).unwrap(), Some((val("v1"), 0)));
+        assert_eq!(store.get(&key("k")).unwrap(), None);
+        assert_eq!(store.delete(&key("k"), 2).unwrap(), None);
+        store.flush().unwrap();
+        assert_eq!(store.entry(&key("k")).unwrap(), Some(Entry::Tombstone(2)));
+
+        store.purge(&key("k")).unwrap();
+        assert_eq!(store.entry(&key("k")).unwrap(), None);
+        store.flush().unwrap();
+        assert_eq!(store.entry(&key("k")).unwrap(), None);
+
+        // Conditional puts
+        assert!(store.put(key("k"), val("v2"), 3, Some(2), None).is_err());
+        assert_eq!(
+            store.put(key("k"), val("v2"), 3, Some(-1), None).unwrap(),
+            3
+        );
+        store.flush().unwrap();
+        assert!(store.put(key("k"), val("v3"), 4, Some(-1), None).is_err());
+        assert_eq!(store.put(key("k"), val("v3"), 4, Some(3), None).unwrap(), 4);
+        assert_eq!(store.get(&key("k")).unwrap(), Some((val("v3"), 4)));
+    }
+
+    #[test]
+    fn test_lsm_store_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = LsmStore::open_with_options(dir.path(), small_options()).unwrap();
+            for round in 0..5 {
+                for i in 0..100 {
+                    store
+                        .put(
+                            key(&format!("k{:03}", i)),
+                            val(&format!("v{}.{}", i, round)),
+                            0,
+                            None,
+                            None,
+                        )
+                        .unwrap();
+                }
+            }
+            for i in (0..100).step_by(3) {
+                store.delete(&key(&format!("k{:03}", i)), 1).unwrap();
+                store.purge(&key(&format!("k{:03}", i))).unwrap();
+            }
+            store.flush().unwrap();
+            wait_for_compactions(&store);
+
+            let state = store.shared.state.lock().unwrap();
+            assert!(state.levels[0].len() < 2);
+            assert!(state.levels[1..].iter().any(|level| !level.is_empty()));
+        }
+
+        let store = LsmStore::open_with_options(dir.path(), small_options()).unwrap();
+        for i in 0..100 {
+            let expected = if i % 3 == 0 {
+                None
+            } else {
+                Some((val(&format!("v{}.4", i)), 0))
+            };
+            assert_eq!(store.get(&key(&format!("k{:03}", i))).unwrap(), expected);
+        }
+
+        // Superseded versions and purged keys are dropped from the bottom
+        // level, leaving one entry per live key
+        wait_for_compactions(&store);
+        let state = store.shared.state.lock().unwrap();
+        let entries: usize = state
+            .levels
+            .iter()
+            .flatten()
+            .map(|t| t.iter().unwrap().count())
+            .sum();
+        assert!(entries < 5 * 100);
+    }
+
+    #[test]
+    fn test_lsm_store_compaction_keeps_deeper_keys_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = LsmOptions {
+            // Only level 1 is over its limit
+            level_size_base: 1,
+            level_size_multiplier: 1 << 40,
+            ..small_options()
+        };
+        let write_table = |id, entries: &[(&str, Option<&str>)]| {
+            let mut writer = TableWriter::create(&table_path(dir.path(), id), id, 64, 10).unwrap();
+            for (key, value) in entries {
+                let entry = sstable::Entry {
+                    version: if value.is_some() { 0 } else { PURGED },
+                    expires: None,
+                    value: value.map(val),
+                };
+                writer.add(key.as_bytes(), &entry).unwrap();
+            }
+            writer.finish().unwrap();
+        };
+        // A narrow level 1 table above a wide level 2 one, which holds a
+        // purged key still live at level 3
+        write_table(1, &[("k1", None)]);
+        write_table(2, &[("k0", Some("v0")), ("k1", Some("v1")), ("k9", None)]);
+        write_table(3, &[("k9", Some("v9"))]);
+        fs::write(dir.path().join(MANIFEST_FILE_NAME), "1 1\n2 2\n3 3\n").unwrap();
+
+        let store = LsmStore::open_with_options(dir.path(), options).unwrap();
+        wait_for_compactions(&store);
+        assert!(store.shared.state.lock().unwrap().levels[1].is_empty());
+        assert_eq!(store.get(&key("k0")).unwrap(), Some((val("v0"), 0)));
+        assert_eq!(store.get(&key("k1")).unwrap(), None);
+        assert_eq!(store.get(&key("k9")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_lsm_store_scan() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LsmStore::open_with_options(dir.path(), small_options()).unwrap();
+        for round in 0..3 {
+            for i in 0..50 {
+                store
+                    .put(
+                        key(&format!("k{:02}", i)),
+                        val(&format!("v{}", round)),
+                        0,
+                        None,
+                        None,
+                    )
+                    .unwrap();
+            }
+        }
+        for i in (0..50).step_by(2) {
+            store.delete(&key(&format!("k{:02}", i)), 1).unwrap();
+        }
+        // Leaves entries spread across the memtable and several levels
+        store.put(key("k49"), val("v3"), 0, None, None).unwrap();
+
+        let mut scanned = Vec::new();
+        let mut start = key("");
+        loop {
+            let page = store.scan(&start, 7).unwrap();
+            if page.is_empty() {
+                break;
+            }
+            let mut next = page.last().unwrap().0.clone();
+            next.0.push(0);
+            start = next;
+            scanned.extend(page);
+        }
+        let expected: Vec<_> = (0..50)
+            .map(|i| {
+                let entry = match i {
+                    49 => Entry::Value(val("v3"), 0, None),
+                    _ if i % 2 == 0 => Entry::Tombstone(1),
+                    _ => Entry::Value(val("v2"), 0, None),
+                };
+                (key(&format!("k{:02}", i)), entry)
+            })
+            .collect();
+        assert_eq!(scanned, expected);
+    }
+
+    #[test]
+    fn test_lsm_store_removes_orphaned_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = LsmStore::open(dir.path()).unwrap();
+            store.put(key("k"), val("v"), 0, None, None).unwrap();
+            store.flush().unwrap();
+        }
+        let orphan = table_path(dir.path(), 42);
+        fs::write(&orphan, b"partial").unwrap();
+
+        let store = LsmStore::open(dir.path()).unwrap();
+        assert!(!orphan.exists());
+        assert_eq!(store.get(&key("k")).unwrap(), Some((val("v"), 0)));
+    }
+}
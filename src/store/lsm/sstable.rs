@@ -0,0 +1,383 @@
+use super::bloom::{self, Bloom};
+use crate::error::{Error, Result};
+use crate::{Expiry, Value, Version};
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// Sorted string table file layout:
+//   data:   entry*
+//   index:  count: u32 | (key_len: u32 | key | offset: u64)* | last_key_len: u32 | last_key
+//   bloom:  see Bloom::encode
+//   footer: index_offset: u64 | bloom_offset: u64 | magic: u64
+//
+// Entry layout:
+//   flags: u8 | version: i64 | expires: u64 | key_len: u32 | value_len: u32 | key | value
+//
+// expires is 0 for values that never expire.
+//
+// The index is sparse: it holds the first key of every block of roughly
+// block_size bytes. Tables are written to a temporary file and renamed into
+// place once complete, so a table is never observed partially written.
+const MAGIC: u64 = 0x726b_765f_7373_7401;
+const FOOTER_LEN: u64 = 24;
+const ENTRY_HEADER_LEN: usize = 25;
+const FLAG_TOMBSTONE: u8 = 1;
+
+// A value or tombstone with its version
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Entry {
+    pub version: Version,
+    pub expires: Option<Expiry>,
+    // None for a tombstone
+    pub value: Option<Value>,
+}
+
+impl Entry {
+    pub fn encoded_len(&self, key: &[u8]) -> usize {
+        ENTRY_HEADER_LEN + key.len() + self.value.as_ref().map_or(0, |v| v.len())
+    }
+}
+
+pub(super) struct Table {
+    pub id: u64,
+    pub path: PathBuf,
+    pub first_key: Vec<u8>,
+    pub last_key: Vec<u8>,
+    pub size: u64,
+    data_end: u64,
+    index: Vec<(Vec<u8>, u64)>,
+    bloom: Bloom,
+    file: Mutex<File>,
+}
+
+impl Table {
+    pub fn open(path: &Path, id: u64) -> Result<Self> {
+        let invalid = |msg: &str| Error::Corruption(format!("{}: {}", path.display(), msg));
+
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        if size < FOOTER_LEN {
+            return Err(invalid("missing footer"));
+        }
+        let mut footer = [0; FOOTER_LEN as usize];
+        file.seek(SeekFrom::Start(size - FOOTER_LEN))?;
+        file.read_exact(&mut footer)?;
+        let index_offset = u64::from_le_bytes(footer[0..8].try_into().unwrap());
+        let bloom_offset = u64::from_le_bytes(footer[8..16].try_into().unwrap());
+        let magic = u64::from_le_bytes(footer[16..24].try_into().unwrap());
+        if magic != MAGIC || index_offset > bloom_offset || bloom_offset > size - FOOTER_LEN {
+            return Err(invalid("invalid footer"));
+        }
+
+        let mut meta = vec![0; (size - FOOTER_LEN - index_offset) as usize];
+        file.seek(SeekFrom::Start(index_offset))?;
+        file.read_exact(&mut meta)?;
+        let (index_buf, bloom_buf) = meta.split_at((bloom_offset - index_offset) as usize);
+
+        let mut r = index_buf;
+        let count = read_u32(&mut r).ok_or_else(|| invalid("invalid index"))?;
+        let mut index = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let key = read_bytes(&mut r).ok_or_else(|| invalid("invalid index"))?;
+            let offset = read_u64(&mut r).ok_or_else(|| invalid("invalid index"))?;
+            index.push((key, offset));
+        }
+        let last_key = read_bytes(&mut r).ok_or_else(|| invalid("invalid index"))?;
+        let first_key = match index.first() {
+            Some((key, _)) => key.clone(),
+            None => return Err(invalid("empty index")),
+        };
+        let bloom = Bloom::decode(bloom_buf)?;
+
+        Ok(Self {
+            id,
+            path: path.to_path_buf(),
+            first_key,
+            last_key,
+            size,
+            data_end: index_offset,
+            index,
+            bloom,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        if key < &self.first_key[..] || key > &self.last_key[..] {
+            return Ok(None);
+        }
+        if !self.bloom.may_contain(bloom::hash(key)) {
+            return Ok(None);
+        }
+        // The block that may hold key starts at the last index key <= key
+        let block = match self.index.binary_search_by(|(k, _)| k[..].cmp(key)) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        };
+        let start = self.index[block].1;
+        let end = self
+            .index
+            .get(block + 1)
+            .map_or(self.data_end, |(_, offset)| *offset);
+
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(start))?;
+        let mut r = BufReader::new(&mut *file).take(end - start);
+        while let Some((k, entry)) = read_entry(&mut r)? {
+            if k[..] == *key {
+                return Ok(Some(entry));
+            }
+            if k[..] > *key {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    // Iterates every entry in key order
+    pub fn iter(&self) -> Result<TableIter> {
+        let file = File::open(&self.path)?;
+        Ok(TableIter {
+            reader: BufReader::new(file).take(self.data_end),
+        })
+    }
+
+    // Iterates in key order from the start of the block that may hold key.
+    // Entries before key may be yielded and must be skipped by the caller.
+    pub fn iter_from(&self, key: &[u8]) -> Result<TableIter> {
+        let block = self.index.partition_point(|(k, _)| &k[..] <= key);
+        let start = self.index[block.saturating_sub(1)].1;
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(start))?;
+        Ok(TableIter {
+            reader: BufReader::new(file).take(self.data_end - start),
+        })
+    }
+
+    pub fn overlaps(&self, first_key: &[u8], last_key: &[u8]) -> bool {
+        &self.first_key[..] <= last_key && &self.last_key[..] >= first_key
+    }
+}
+
+pub(super) struct TableIter {
+    reader: std::io::Take<BufReader<File>>,
+}
+
+impl Iterator for TableIter {
+    type Item = Result<(Vec<u8>, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        read_entry(&mut self.reader).transpose()
+    }
+}
+
+pub(super) struct TableWriter {
+    id: u64,
+    path: PathBuf,
+    tmp_path: PathBuf,
+    writer: BufWriter<File>,
+    block_size: u64,
+    bloom_bits_per_key: usize,
+    offset: u64,
+    block_start: Option<u64>,
+    index: Vec<(Vec<u8>, u64)>,
+    hashes: Vec<u64>,
+    last_key: Vec<u8>,
+}
+
+impl TableWriter {
+    pub fn create(
+        path: &Path,
+        id: u64,
+        block_size: u64,
+        bloom_bits_per_key: usize,
+    ) -> Result<Self> {
+        let tmp_path = path.with_extension("tmp");
+        Ok(Self {
+            id,
+            path: path.to_path_buf(),
+            writer: BufWriter::new(File::create(&tmp_path)?),
+            tmp_path,
+            block_size,
+            bloom_bits_per_key,
+            offset: 0,
+            block_start: None,
+            index: Vec::new(),
+            hashes: Vec::new(),
+            last_key: Vec::new(),
+        })
+    }
+
+    // Keys must be added in strictly increasing order
+    pub fn add(&mut self, key: &[u8], entry: &Entry) -> Result<()> {
+        debug_assert!(self.index.is_empty() || key > &self.last_key[..]);
+        let new_block = match self.block_start {
+            Some(start) => self.offset - start >= self.block_size,
+            None => true,
+        };
+        if new_block {
+            self.block_start = Some(self.offset);
+            self.index.push((key.to_vec(), self.offset));
+        }
+
+        let (flags, value): (u8, &[u8]) = match &entry.value {
+            Some(value) => (0, value),
+            None => (FLAG_TOMBSTONE, &[]),
+        };
+        self.writer.write_all(&[flags])?;
+        self.writer.write_all(&entry.version.to_le_bytes())?;
+        self.writer
+            .write_all(&entry.expires.unwrap_or(0).to_le_bytes())?;
+        self.writer.write_all(&(key.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&(value.len() as u32).to_le_bytes())?;
+        self.writer.write_all(key)?;
+        self.writer.write_all(value)?;
+        self.offset += entry.encoded_len(key) as u64;
+
+        self.hashes.push(bloom::hash(key));
+        self.last_key = key.to_vec();
+        Ok(())
+    }
+
+    // Bytes written so far
+    pub fn size(&self) -> u64 {
+        self.offset
+    }
+
+    // Writes the index, bloom filter and footer, syncs the table and moves
+    // it into place
+    pub fn finish(mut self) -> Result<Table> {
+        let index_offset = self.offset;
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(self.index.len() as u32).to_le_bytes());
+        for (key, offset) in &self.index {
+            buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            buf.extend_from_slice(key);
+            buf.extend_from_slice(&offset.to_le_bytes());
+        }
+        buf.extend_from_slice(&(self.last_key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&self.last_key);
+        let bloom_offset = index_offset + buf.len() as u64;
+        Bloom::new(&self.hashes, self.bloom_bits_per_key).encode(&mut buf);
+        buf.extend_from_slice(&index_offset.to_le_bytes());
+        buf.extend_from_slice(&bloom_offset.to_le_bytes());
+        buf.extend_from_slice(&MAGIC.to_le_bytes());
+        self.writer.write_all(&buf)?;
+
+        let file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&self.tmp_path, &self.path)?;
+        Table::open(&self.path, self.id)
+    }
+
+    pub fn abandon(self) {
+        let _ = fs::remove_file(&self.tmp_path);
+    }
+}
+
+fn read_entry<R: Read>(r: &mut R) -> Result<Option<(Vec<u8>, Entry)>> {
+    let mut header = [0; ENTRY_HEADER_LEN];
+    // Distinguish a clean end of data from a truncated entry
+    match r.read(&mut header[..1])? {
+        0 => return Ok(None),
+        _ => r.read_exact(&mut header[1..])?,
+    }
+    let flags = header[0];
+    let version = i64::from_le_bytes(header[1..9].try_into().unwrap());
+    let expires = u64::from_le_bytes(header[9..17].try_into().unwrap());
+    let key_len = u32::from_le_bytes(header[17..21].try_into().unwrap()) as usize;
+    let value_len = u32::from_le_bytes(header[21..25].try_into().unwrap()) as usize;
+    let mut key = vec![0; key_len];
+    r.read_exact(&mut key)?;
+    let mut value = vec![0; value_len];
+    r.read_exact(&mut value)?;
+    let value = if flags & FLAG_TOMBSTONE != 0 {
+        None
+    } else {
+        Some(value)
+    };
+    let expires = Some(expires).filter(|expires| *expires > 0);
+    Ok(Some((
+        key,
+        Entry {
+            version,
+            expires,
+            value,
+        },
+    )))
+}
+
+fn read_u32(r: &mut &[u8]) -> Option<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf).ok()?;
+    Some(u32::from_le_bytes(buf))
+}
+
+fn read_u64(r: &mut &[u8]) -> Option<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf).ok()?;
+    Some(u64::from_le_bytes(buf))
+}
+
+fn read_bytes(r: &mut &[u8]) -> Option<Vec<u8>> {
+    let len = read_u32(r)? as usize;
+    if r.len() < len {
+        return None;
+    }
+    let (bytes, rest) = r.split_at(len);
+    *r = rest;
+    Some(bytes.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sstable() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.sst");
+        let mut writer = TableWriter::create(&path, 1, 64, 10).unwrap();
+        let entries: Vec<_> = (0..100)
+            .map(|i| {
+                let key = format!("k{:03}", i).into_bytes();
+                let value = if i % 10 == 0 {
+                    None
+                } else {
+                    Some(format!("v{}", i).into_bytes())
+                };
+                // Some values expire
+                let expires = Some(i as u64 % 3).filter(|e| *e > 0);
+                (
+                    key,
+                    Entry {
+                        version: i,
+                        expires,
+                        value,
+                    },
+                )
+            })
+            .collect();
+        for (key, entry) in &entries {
+            writer.add(key, entry).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let table = Table::open(&path, 1).unwrap();
+        assert_eq!(table.first_key, b"k000".to_vec());
+        assert_eq!(table.last_key, b"k099".to_vec());
+        assert!(table.index.len() > 1);
+        for (key, entry) in &entries {
+            assert_eq!(table.get(key).unwrap().as_ref(), Some(entry));
+        }
+        assert_eq!(table.get(b"k").unwrap(), None);
+        assert_eq!(table.get(b"k0505").unwrap(), None);
+        assert_eq!(table.get(b"z").unwrap(), None);
+
+        let scanned: Vec<_> = table.iter().unwrap().map(|e| e.unwrap()).collect();
+        assert_eq!(scanned, entries);
+    }
+}
@@ -1,36 +1,63 @@
-use super::Store;
+use super::{check_version, is_expired, Entry, Store};
 use crate::error::Result;
-use crate::{Key, Value, ValueVersion, Version};
-use std::collections::HashMap;
+use crate::{Expiry, Key, Value, ValueVersion, Version};
+use std::collections::BTreeMap;
 use std::sync::{Arc, Mutex};
 
 pub struct MemStore {
-    entries: Arc<Mutex<HashMap<Key, ValueVersion>>>,
+    entries: Arc<Mutex<BTreeMap<Key, Entry>>>,
 }
 
 impl MemStore {
     pub fn new() -> Self {
         Self {
-            entries: Arc::new(Mutex::new(HashMap::new())),
+            entries: Arc::new(Mutex::new(BTreeMap::new())),
         }
     }
 }
 
-// TODO: Fix versions
 // TODO: lock().unwrap()??? Handle poisoned locks.
 impl Store for MemStore {
-    fn put(&self, key: Key, val: Value) -> Result<Version> {
+    fn put(
+        &self,
+        key: Key,
+        val: Value,
+        version: Version,
+        expected: Option<Version>,
+        expires: Option<Expiry>,
+    ) -> Result<Version> {
         let mut entries = self.entries.lock().unwrap();
-        entries.insert(key, (val, 0));
-        Ok(0)
+        let current = match entries.get(&key) {
+            Some(Entry::Value(_, version, expires)) if !is_expired(*expires) => *version,
+            _ => -1,
+        };
+        check_version(current, expected)?;
+        entries.insert(key, Entry::Value(val, version, expires));
+        Ok(version)
     }
-    fn get(&self, key: &Key) -> Result<Option<ValueVersion>> {
+    fn entry(&self, key: &Key) -> Result<Option<Entry>> {
         let entries = self.entries.lock().unwrap();
-        Ok(entries.get(key).map(|(k, v)| (k.clone(), *v)))
+        Ok(entries.get(key).cloned())
     }
-    fn delete(&self, key: &Key) -> Result<Option<ValueVersion>> {
+    fn delete(&self, key: &Key, version: Version) -> Result<Option<ValueVersion>> {
         let mut entries = self.entries.lock().unwrap();
-        Ok(entries.remove(key))
+        match entries.insert(key.clone(), Entry::Tombstone(version)) {
+            Some(Entry::Value(value, version, _)) => Ok(Some((value, version))),
+            _ => Ok(None),
+        }
+    }
+    fn purge(&self, key: &Key) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(key);
+        Ok(())
+    }
+    fn scan(&self, start: &Key, limit: usize) -> Result<Vec<(Key, Entry)>> {
+        let entries = self.entries.lock().unwrap();
+        Ok(entries
+            .range(start..)
+            .take(limit)
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
     }
 }
 
@@ -40,9 +67,15 @@ mod tests {
 
     #[test]
     fn test_mem_store() {
-        let mut store = MemStore::new();
+        let store = MemStore::new();
         let version = store
-            .put(Key("k".as_bytes().to_vec()), "v".as_bytes().to_vec())
+            .put(
+                Key("k".as_bytes().to_vec()),
+                "v".as_bytes().to_vec(),
+                0,
+                None,
+                None,
+            )
             .unwrap();
         assert_eq!(version, 0);
         let (val, version) = store
@@ -50,5 +83,111 @@ mod tests {
             .unwrap()
             .expect("missing value for key");
         assert_eq!((val, version), ("v".as_bytes().to_vec(), 0));
+        let entries = store.scan(&Key(Vec::new()), 10).unwrap();
+        assert_eq!(
+            entries,
+            vec![(
+                Key("k".as_bytes().to_vec()),
+                Entry::Value("v".as_bytes().to_vec(), 0, None)
+            )]
+        );
+
+        let key = Key("k".as_bytes().to_vec());
+        assert_eq!(
+            store.delete(&key, 1).unwrap(),
+            Some(("v".as_bytes().to_vec(), 0))
+        );
+        assert_eq!(store.get(&key).unwrap(), None);
+        assert_eq!(store.entry(&key).unwrap(), Some(Entry::Tombstone(1)));
+        store.purge(&key).unwrap();
+        assert_eq!(store.entry(&key).unwrap(), None);
+        assert!(store.scan(&Key(Vec::new()), 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_mem_store_expiry() {
+        let store = MemStore::new();
+        let key = Key("k".as_bytes().to_vec());
+        let val = "v".as_bytes().to_vec();
+        store
+            .put(key.clone(), val.clone(), 1, None, Some(u64::MAX))
+            .unwrap();
+        assert_eq!(store.get(&key).unwrap(), Some((val.clone(), 1)));
+
+        // Expired values look absent but are still stored
+        store
+            .put(key.clone(), val.clone(), 2, None, Some(1))
+            .unwrap();
+        assert_eq!(store.get(&key).unwrap(), None);
+        assert_eq!(
+            store.entry(&key).unwrap(),
+            Some(Entry::Value(val.clone(), 2, Some(1)))
+        );
+        assert!(store
+            .put(key.clone(), val.clone(), 3, Some(2), None)
+            .is_err());
+        assert_eq!(store.put(key.clone(), val, 3, Some(-1), None).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_mem_store_scan_range() {
+        let store = MemStore::new();
+        for k in &["a", "b", "c", "d"] {
+            store
+                .put(
+                    Key(k.as_bytes().to_vec()),
+                    k.as_bytes().to_vec(),
+                    0,
+                    None,
+                    None,
+                )
+                .unwrap();
+        }
+        let keys = |start: &str, end: Option<&str>, limit| {
+            let end = end.map(|end| Key(end.as_bytes().to_vec()));
+            store
+                .scan_range(&Key(start.as_bytes().to_vec()), end.as_ref(), limit)
+                .unwrap()
+                .into_iter()
+                .map(|(k, _)| String::from_utf8(k.0).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys("b", None, 10), vec!["b", "c", "d"]);
+        assert_eq!(keys("b", Some("d"), 10), vec!["b", "c"]);
+        assert_eq!(keys("a", Some("d"), 2), vec!["a", "b"]);
+        assert!(keys("d", Some("d"), 10).is_empty());
+    }
+
+    #[test]
+    fn test_mem_store_conditional_put() {
+        let store = MemStore::new();
+        let key = Key("k".as_bytes().to_vec());
+        let val = "v".as_bytes().to_vec();
+        assert!(store
+            .put(key.clone(), val.clone(), 1, Some(0), None)
+            .is_err());
+        assert_eq!(
+            store
+                .put(key.clone(), val.clone(), 1, Some(-1), None)
+                .unwrap(),
+            1
+        );
+        assert!(store
+            .put(key.clone(), val.clone(), 2, Some(-1), None)
+            .is_err());
+        assert_eq!(
+            store
+                .put(key.clone(), val.clone(), 2, Some(1), None)
+                .unwrap(),
+            2
+        );
+        assert_eq!(store.get(&key).unwrap(), Some((val.clone(), 2)));
+
+        // Deleted keys are absent
+        store.delete(&key, 3).unwrap();
+        assert!(store
+            .put(key.clone(), val.clone(), 4, Some(3), None)
+            .is_err());
+        assert_eq!(store.put(key.clone(), val, 4, Some(-1), None).unwrap(), 4);
     }
 }
@@ -1,11 +1,147 @@
-use crate::error::Result;
-use crate::{Key, Value, ValueVersion, Version};
+use crate::error::{Error, Result};
+use crate::{Expiry, Key, Value, ValueVersion, Version};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+mod bitcask;
+mod lsm;
 mod mem;
+mod wal;
+pub use bitcask::BitcaskStore;
+pub use lsm::{LsmOptions, LsmStore};
 pub use mem::MemStore;
+pub use wal::{SyncPolicy, WalStore};
+
+// The value stored for a key along with when it expires, if ever, or the
+// tombstone left by deleting it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    Value(Value, Version, Option<Expiry>),
+    Tombstone(Version),
+}
 
 pub trait Store: Send + Sync {
-    fn put(&self, key: Key, val: Value) -> Result<Version>;
-    fn get(&self, key: &Key) -> Result<Option<ValueVersion>>;
-    fn delete(&self, key: &Key) -> Result<Option<ValueVersion>>;
+    // Stores val under key at the given version, returning it. If expected
+    // is set, fails with VersionConflict unless the key's value has that
+    // version, or is absent, deleted or expired for -1.
+    fn put(
+        &self,
+        key: Key,
+        val: Value,
+        version: Version,
+        expected: Option<Version>,
+        expires: Option<Expiry>,
+    ) -> Result<Version>;
+
+    // Returns the value or tombstone stored for key, expired or not
+    fn entry(&self, key: &Key) -> Result<Option<Entry>>;
+
+    // Replaces the value of key with a tombstone at the given version,
+    // returning the previous value
+    fn delete(&self, key: &Key, version: Version) -> Result<Option<ValueVersion>>;
+
+    // Removes key outright, tombstone included
+    fn purge(&self, key: &Key) -> Result<()>;
+
+    // Returns up to limit entries, tombstones included, with keys >= start
+    // in key order
+    fn scan(&self, start: &Key, limit: usize) -> Result<Vec<(Key, Entry)>>;
+
+    // Returns up to limit entries, tombstones included, with keys >= start
+    // and < end if set, in key order
+    fn scan_range(
+        &self,
+        start: &Key,
+        end: Option<&Key>,
+        limit: usize,
+    ) -> Result<Vec<(Key, Entry)>> {
+        let mut entries = self.scan(start, limit)?;
+        if let Some(end) = end {
+            entries.retain(|(key, _)| key < end);
+        }
+        Ok(entries)
+    }
+
+    // Returns the value of key. None if absent, deleted or expired.
+    fn get(&self, key: &Key) -> Result<Option<ValueVersion>> {
+        match self.entry(key)? {
+            Some(Entry::Value(value, version, expires)) if !is_expired(expires) => {
+                Ok(Some((value, version)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // Whether flush persists every applied mutation
+    fn durable(&self) -> bool {
+        false
+    }
+
+    // Persists every applied mutation if the store is durable
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Whether a value expiring at expires has expired
+pub fn is_expired(expires: Option<Expiry>) -> bool {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time before unix epoch")
+        .as_millis() as Expiry;
+    expires.is_some_and(|expires| expires <= now)
+}
+
+// Fails with VersionConflict unless the current version of a key, -1 if
+// it's absent, deleted or expired, is the one a put expects
+fn check_version(current: Version, expected: Option<Version>) -> Result<()> {
+    match expected {
+        Some(expected) if expected != current => Err(Error::VersionConflict),
+        _ => Ok(()),
+    }
+}
+
+// Storage engine backing a node's Store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    Mem,
+    Bitcask,
+    Lsm,
+}
+
+impl FromStr for Engine {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mem" => Ok(Engine::Mem),
+            "bitcask" => Ok(Engine::Bitcask),
+            "lsm" => Ok(Engine::Lsm),
+            _ => Err(Error::InvalidArgument(format!("unknown engine: {}", s))),
+        }
+    }
+}
+
+// Opens a store of the given engine type rooted at folder. Every mutation is
+// recorded in a write-ahead log synced according to policy.
+pub fn open<P: AsRef<Path>>(
+    engine: Engine,
+    folder: P,
+    policy: SyncPolicy,
+) -> Result<Box<dyn Store>> {
+    let folder = folder.as_ref();
+    match engine {
+        Engine::Mem => Ok(Box::new(WalStore::open(MemStore::new(), folder, policy)?)),
+        Engine::Bitcask => Ok(Box::new(WalStore::open(
+            BitcaskStore::open(folder)?,
+            folder,
+            policy,
+        )?)),
+        Engine::Lsm => Ok(Box::new(WalStore::open(
+            LsmStore::open(folder)?,
+            folder,
+            policy,
+        )?)),
+    }
 }
@@ -0,0 +1,494 @@
+use super::{check_version, Entry, Store};
+use crate::error::{Error, Result};
+use crate::{Expiry, Key, Value, ValueVersion, Version};
+use log::{info, warn};
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+// When the write-ahead log is fsync'ed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    // Before every mutation is acknowledged
+    Always,
+    // Periodically in the background. Mutations acknowledged since the last
+    // sync may be lost on crash.
+    Batched(Duration),
+    // Never. Left to the OS.
+    Never,
+}
+
+// Parses "always", "never" or "batched:<interval in ms>"
+impl FromStr for SyncPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "always" => return Ok(SyncPolicy::Always),
+            "never" => return Ok(SyncPolicy::Never),
+            _ => {}
+        }
+        let millis = s
+            .strip_prefix("batched:")
+            .and_then(|millis| millis.parse::<u64>().ok())
+            .filter(|millis| *millis > 0)
+            .ok_or_else(|| Error::InvalidArgument(format!("invalid sync policy: {}", s)))?;
+        Ok(SyncPolicy::Batched(Duration::from_millis(millis)))
+    }
+}
+
+// A Store wrapper that records every mutation in a write-ahead log before
+// applying it to the inner store. The log is replayed into the inner store on
+// open.
+//
+// If the inner store is durable, the log is checkpointed once it grows past
+// checkpoint_size: the inner store is flushed and the log truncated.
+// Otherwise the log is the only durable copy of the data and is never
+// truncated.
+pub struct WalStore<S> {
+    inner: S,
+    wal: Mutex<Wal>,
+    checkpoint_size: u64,
+}
+
+const WAL_FILE_NAME: &str = "wal.log";
+const DEFAULT_CHECKPOINT_SIZE: u64 = 64 * 1024 * 1024;
+
+impl<S: Store> WalStore<S> {
+    pub fn open<P: AsRef<Path>>(inner: S, folder: P, policy: SyncPolicy) -> Result<Self> {
+        Self::open_with_checkpoint_size(inner, folder, policy, DEFAULT_CHECKPOINT_SIZE)
+    }
+
+    pub fn open_with_checkpoint_size<P: AsRef<Path>>(
+        inner: S,
+        folder: P,
+        policy: SyncPolicy,
+        checkpoint_size: u64,
+    ) -> Result<Self> {
+        std::fs::create_dir_all(folder.as_ref())?;
+        let path = folder.as_ref().join(WAL_FILE_NAME);
+        let (mut wal, mutations) = Wal::open(&path, policy)?;
+        info!(
+            "replaying {} mutations from {}",
+            mutations.len(),
+            path.display()
+        );
+        for mutation in mutations {
+            match mutation {
+                Mutation::Put(key, val, version, expires) => {
+                    inner.put(key, val, version, None, expires)?;
+                }
+                Mutation::Delete(key, version) => {
+                    inner.delete(&key, version)?;
+                }
+                Mutation::Purge(key) => {
+                    inner.purge(&key)?;
+                }
+            }
+        }
+        if inner.durable() {
+            inner.flush()?;
+            wal.reset()?;
+        }
+        Ok(Self {
+            inner,
+            wal: Mutex::new(wal),
+            checkpoint_size,
+        })
+    }
+
+    fn checkpoint(&self, wal: &mut Wal) -> Result<()> {
+        if self.inner.durable() && wal.size >= self.checkpoint_size {
+            self.inner.flush()?;
+            wal.reset()?;
+        }
+        Ok(())
+    }
+}
+
+// The log lock is held while applying each mutation so that log order always
+// matches apply order.
+// TODO: lock().unwrap()??? Handle poisoned locks.
+impl<S: Store> Store for WalStore<S> {
+    fn put(
+        &self,
+        key: Key,
+        val: Value,
+        version: Version,
+        expected: Option<Version>,
+        expires: Option<Expiry>,
+    ) -> Result<Version> {
+        let mut wal = self.wal.lock().unwrap();
+        // Checked before logging. The log lock keeps the key from changing
+        // in between.
+        if expected.is_some() {
+            let current = self.inner.get(&key)?.map_or(-1, |(_, version)| version);
+            check_version(current, expected)?;
+        }
+        wal.append(OP_PUT, version, expires, &key.0, &val)?;
+        let version = self.inner.put(key, val, version, None, expires)?;
+        self.checkpoint(&mut wal)?;
+        Ok(version)
+    }
+
+    fn entry(&self, key: &Key) -> Result<Option<Entry>> {
+        self.inner.entry(key)
+    }
+
+    fn delete(&self, key: &Key, version: Version) -> Result<Option<ValueVersion>> {
+        let mut wal = self.wal.lock().unwrap();
+        wal.append(OP_DELETE, version, None, &key.0, &[])?;
+        let prev = self.inner.delete(key, version)?;
+        self.checkpoint(&mut wal)?;
+        Ok(prev)
+    }
+
+    fn purge(&self, key: &Key) -> Result<()> {
+        let mut wal = self.wal.lock().unwrap();
+        wal.append(OP_PURGE, 0, None, &key.0, &[])?;
+        self.inner.purge(key)?;
+        self.checkpoint(&mut wal)
+    }
+
+    fn scan(&self, start: &Key, limit: usize) -> Result<Vec<(Key, Entry)>> {
+        self.inner.scan(start, limit)
+    }
+
+    fn durable(&self) -> bool {
+        true
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.wal.lock().unwrap().sync()
+    }
+}
+
+enum Mutation {
+    Put(Key, Value, Version, Option<Expiry>),
+    Delete(Key, Version),
+    Purge(Key),
+}
+
+// Record layout:
+//   crc: u32 | op: u8 | version: i64 | expires: u64 | key_len: u32 | value_len: u32 | key | value
+// The crc covers every byte following it. expires is 0 for values that
+// never expire.
+const HEADER_LEN: usize = 29;
+const OP_PUT: u8 = 0;
+const OP_DELETE: u8 = 1;
+const OP_PURGE: u8 = 2;
+
+struct Wal {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    policy: SyncPolicy,
+    // Set when there are appends the background syncer has yet to fsync
+    dirty: Arc<AtomicBool>,
+}
+
+impl Wal {
+    // Opens the log at path, returning it along with every mutation it holds
+    fn open(path: &Path, policy: SyncPolicy) -> Result<(Self, Vec<Mutation>)> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+
+        let mut mutations = Vec::new();
+        let mut reader = BufReader::new(file.try_clone()?);
+        let mut size = 0;
+        let len = file.metadata()?.len();
+        while let Some((mutation, record_len)) = decode(&mut reader, len - size)? {
+            mutations.push(mutation);
+            size += record_len as u64;
+        }
+        if size < len {
+            // A crash mid-append leaves a partial record at the tail. Drop it.
+            warn!(
+                "truncating {} from {} to {} bytes",
+                path.display(),
+                len,
+                size
+            );
+            file.set_len(size)?;
+        }
+
+        let dirty = Arc::new(AtomicBool::new(false));
+        if let SyncPolicy::Batched(interval) = policy {
+            spawn_syncer(file.try_clone()?, Arc::downgrade(&dirty), interval);
+        }
+
+        let wal = Self {
+            path: path.to_path_buf(),
+            file,
+            size,
+            policy,
+            dirty,
+        };
+        Ok((wal, mutations))
+    }
+
+    fn append(
+        &mut self,
+        op: u8,
+        version: Version,
+        expires: Option<Expiry>,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<()> {
+        let buf = encode(op, version, expires, key, value);
+        self.file.write_all(&buf)?;
+        self.size += buf.len() as u64;
+        match self.policy {
+            SyncPolicy::Always => self.file.sync_data()?,
+            SyncPolicy::Batched(_) => self.dirty.store(true, Ordering::Release),
+            SyncPolicy::Never => {}
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.dirty.store(false, Ordering::Release);
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    // Discards every record. Only safe once all logged mutations are durable
+    // elsewhere.
+    fn reset(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.dirty.store(false, Ordering::Release);
+        self.size = 0;
+        info!("reset {}", self.path.display());
+        Ok(())
+    }
+}
+
+// Fsyncs the log every interval while it has unsynced appends. Exits once
+// the log is dropped.
+fn spawn_syncer(file: File, dirty: Weak<AtomicBool>, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let dirty = match dirty.upgrade() {
+            Some(dirty) => dirty,
+            None => return,
+        };
+        if dirty.swap(false, Ordering::AcqRel) {
+            if let Err(e) = file.sync_data() {
+                warn!("failed to sync write-ahead log: {}", e);
+                dirty.store(true, Ordering::Release);
+            }
+        }
+    });
+}
+
+fn encode(op: u8, version: Version, expires: Option<Expiry>, key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_LEN + key.len() + value.len());
+    buf.extend_from_slice(&[0; 4]);
+    buf.push(op);
+    buf.extend_from_slice(&version.to_le_bytes());
+    buf.extend_from_slice(&expires.unwrap_or(0).to_le_bytes());
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(value);
+    let crc = crc32fast::hash(&buf[4..]);
+    buf[..4].copy_from_slice(&crc.to_le_bytes());
+    buf
+}
+
+// Reads the next record from the remaining bytes of a file. Returns None at
+// a clean end of file or if they do not form a complete, valid record.
+fn decode<R: Read>(r: &mut R, remaining: u64) -> Result<Option<(Mutation, usize)>> {
+    let mut header = [0; HEADER_LEN];
+    if !read_full(r, &mut header)? {
+        return Ok(None);
+    }
+    let crc = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let op = header[4];
+    let version = i64::from_le_bytes(header[5..13].try_into().unwrap());
+    let expires = u64::from_le_bytes(header[13..21].try_into().unwrap());
+    let key_len = u32::from_le_bytes(header[21..25].try_into().unwrap()) as usize;
+    let value_len = u32::from_le_bytes(header[25..29].try_into().unwrap()) as usize;
+    // A torn header may claim more bytes than the file holds
+    if (HEADER_LEN + key_len + value_len) as u64 > remaining {
+        return Ok(None);
+    }
+
+    let mut body = vec![0; key_len + value_len];
+    if !read_full(r, &mut body)? {
+        return Ok(None);
+    }
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[4..]);
+    hasher.update(&body);
+    if hasher.finalize() != crc {
+        return Ok(None);
+    }
+
+    let value = body.split_off(key_len);
+    let mutation = match op {
+        OP_PUT => {
+            let expires = Some(expires).filter(|expires| *expires > 0);
+            Mutation::Put(Key(body), value, version, expires)
+        }
+        OP_DELETE => Mutation::Delete(Key(body), version),
+        OP_PURGE => Mutation::Purge(Key(body)),
+        _ => return Ok(None),
+    };
+    Ok(Some((mutation, HEADER_LEN + key_len + value_len)))
+}
+
+// Fills buf, returning false if the reader hit end of file first
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> Result<bool> {
+    match r.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{BitcaskStore, MemStore};
+
+    fn key(s: &str) -> Key {
+        Key(s.as_bytes().to_vec())
+    }
+
+    fn val(s: &str) -> Value {
+        s.as_bytes().to_vec()
+    }
+
+    #[test]
+    fn test_sync_policy_from_str() {
+        assert_eq!("always".parse::<SyncPolicy>().unwrap(), SyncPolicy::Always);
+        assert_eq!("never".parse::<SyncPolicy>().unwrap(), SyncPolicy::Never);
+        assert_eq!(
+            "batched:50".parse::<SyncPolicy>().unwrap(),
+            SyncPolicy::Batched(Duration::from_millis(50))
+        );
+        assert!("batched:0".parse::<SyncPolicy>().is_err());
+        assert!("batched".parse::<SyncPolicy>().is_err());
+        assert!("sometimes".parse::<SyncPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_wal_store_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        for policy in &[
+            SyncPolicy::Always,
+            SyncPolicy::Batched(Duration::from_millis(1)),
+            SyncPolicy::Never,
+        ] {
+            {
+                let store = WalStore::open(MemStore::new(), dir.path(), *policy).unwrap();
+                store.put(key("k0"), val("v0"), 0, None, None).unwrap();
+                store.put(key("k1"), val("v1"), 7, None, None).unwrap();
+                store.put(key("k2"), val("v2"), 0, None, None).unwrap();
+                store.delete(&key("k0"), 8).unwrap();
+                store.delete(&key("k2"), 9).unwrap();
+                store.purge(&key("k2")).unwrap();
+                store.put(key("k3"), val("v3"), 0, None, Some(1)).unwrap();
+                // Rejected puts aren't logged
+                assert!(store.put(key("k1"), val("v3"), 10, Some(0), None).is_err());
+            }
+            let store = WalStore::open(MemStore::new(), dir.path(), *policy).unwrap();
+            assert_eq!(store.entry(&key("k0")).unwrap(), Some(Entry::Tombstone(8)));
+            assert_eq!(store.get(&key("k1")).unwrap(), Some((val("v1"), 7)));
+            assert_eq!(store.entry(&key("k2")).unwrap(), None);
+            assert_eq!(
+                store.entry(&key("k3")).unwrap(),
+                Some(Entry::Value(val("v3"), 0, Some(1)))
+            );
+        }
+    }
+
+    #[test]
+    fn test_wal_store_truncated_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = WalStore::open(MemStore::new(), dir.path(), SyncPolicy::Always).unwrap();
+            store.put(key("k0"), val("v0"), 0, None, None).unwrap();
+            store.put(key("k1"), val("v1"), 0, None, None).unwrap();
+        }
+        let path = dir.path().join(WAL_FILE_NAME);
+        let len = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+
+        let store = WalStore::open(MemStore::new(), dir.path(), SyncPolicy::Always).unwrap();
+        assert_eq!(store.get(&key("k0")).unwrap(), Some((val("v0"), 0)));
+        assert_eq!(store.get(&key("k1")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_wal_store_corrupt_tail_lengths() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = WalStore::open(MemStore::new(), dir.path(), SyncPolicy::Always).unwrap();
+            store.put(key("k0"), val("v0"), 0, None, None).unwrap();
+        }
+        // A header claiming lengths far past the end of the file
+        let path = dir.path().join(WAL_FILE_NAME);
+        let len = std::fs::metadata(&path).unwrap().len();
+        let mut header = [0; HEADER_LEN];
+        header[21..29].copy_from_slice(&[0xff; 8]);
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&header)
+            .unwrap();
+
+        let store = WalStore::open(MemStore::new(), dir.path(), SyncPolicy::Always).unwrap();
+        assert_eq!(store.get(&key("k0")).unwrap(), Some((val("v0"), 0)));
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+    }
+
+    #[test]
+    fn test_wal_store_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(WAL_FILE_NAME);
+        {
+            let inner = BitcaskStore::open(dir.path()).unwrap();
+            let store =
+                WalStore::open_with_checkpoint_size(inner, dir.path(), SyncPolicy::Always, 64)
+                    .unwrap();
+            for i in 0..10 {
+                store
+                    .put(
+                        key(&format!("k{}", i)),
+                        val(&format!("v{}", i)),
+                        0,
+                        None,
+                        None,
+                    )
+                    .unwrap();
+                assert!(std::fs::metadata(&path).unwrap().len() < 64);
+            }
+        }
+        let inner = BitcaskStore::open(dir.path()).unwrap();
+        let store = WalStore::open(inner, dir.path(), SyncPolicy::Always).unwrap();
+        for i in 0..10 {
+            assert_eq!(
+                store.get(&key(&format!("k{}", i))).unwrap(),
+                Some((val(&format!("v{}", i)), 0))
+            );
+        }
+    }
+}
@@ -20,16 +20,390 @@ async fn test_rkv() {
             key: "k0".as_bytes().to_vec(),
             value: "v0".as_bytes().to_vec(),
             version: -1,
+            ..Default::default()
         })
         .await
         .unwrap();
+    let version = resp.into_inner().version;
+    assert!(version > 0);
 
     let resp = client
         .get(GetRequest {
             key: "k0".as_bytes().to_vec(),
         })
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(resp.value, "v0".as_bytes().to_vec());
+    assert_eq!(resp.version, version);
+}
+
+#[tokio::test]
+async fn test_rkv_client_version() {
+    let mut client = RkvServiceClient::connect("http://127.0.0.1:8080")
+        .await
+        .unwrap();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap();
+    let key = format!("cv{}", now.as_nanos()).into_bytes();
+
+    // A client version ahead of the coordinator's clock. Kept short of the
+    // TTL test's slack, as the cluster's clocks catch up with it.
+    let version = ((now.as_millis() as i64) + 250) << 16;
+    let resp = client
+        .put(PutRequest {
+            key: key.clone(),
+            value: "v0".as_bytes().to_vec(),
+            version,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(resp.into_inner().version, version);
+
+    // The next put stamped by the coordinator supersedes it
+    let context = client
+        .get(GetRequest { key: key.clone() })
+        .await
+        .unwrap()
+        .into_inner()
+        .context;
+    let next = client
+        .put(PutRequest {
+            key: key.clone(),
+            value: "v1".as_bytes().to_vec(),
+            context,
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner()
+        .version;
+    assert!(next > version);
+    let resp = client.get(GetRequest { key }).await.unwrap().into_inner();
+    assert_eq!(resp.value, "v1".as_bytes().to_vec());
+    assert_eq!(resp.version, next);
+}
+
+#[tokio::test]
+async fn test_rkv_client_version_too_far_ahead() {
+    let mut client = RkvServiceClient::connect("http://127.0.0.1:8080")
+        .await
+        .unwrap();
+
+    let status = client
+        .put(PutRequest {
+            key: "cv_max".as_bytes().to_vec(),
+            value: "v0".as_bytes().to_vec(),
+            version: i64::MAX,
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert!(status.message().contains("too far ahead of the clock"));
+
+    // As is a conditional put expecting such a version, though it would fail
+    let status = client
+        .put(PutRequest {
+            key: "cv_max".as_bytes().to_vec(),
+            value: "v0".as_bytes().to_vec(),
+            version: -1,
+            condition: Some(put_request::Condition::ExpectedVersion(i64::MAX)),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert!(status.message().contains("too far ahead of the clock"));
+
+    // The clock didn't move to the rejected versions
+    let version = client
+        .put(PutRequest {
+            key: "cv_max".as_bytes().to_vec(),
+            value: "v1".as_bytes().to_vec(),
+            version: -1,
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner()
+        .version;
+    assert!(version > 0 && version < i64::MAX / 2);
+}
+
+#[tokio::test]
+async fn test_rkv_siblings() {
+    let mut a = RkvServiceClient::connect("http://127.0.0.1:8080")
         .await
         .unwrap();
+    let mut b = RkvServiceClient::connect("http://127.0.0.1:8078")
+        .await
+        .unwrap();
+    // Blind writes are concurrent with whatever earlier runs left behind
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let key = format!("k{}", nanos).into_bytes();
+
+    // Blind writes through different coordinators are concurrent
+    a.put(PutRequest {
+        key: key.clone(),
+        value: "v0".as_bytes().to_vec(),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    b.put(PutRequest {
+        key: key.clone(),
+        value: "v1".as_bytes().to_vec(),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let resp = a
+        .get(GetRequest { key: key.clone() })
+        .await
+        .unwrap()
+        .into_inner();
+    let mut values: Vec<_> = resp.siblings.iter().map(|s| s.value.clone()).collect();
+    values.sort();
+    assert_eq!(
+        values,
+        vec!["v0".as_bytes().to_vec(), "v1".as_bytes().to_vec()]
+    );
+
+    // A write with their context resolves them
+    b.put(PutRequest {
+        key: key.clone(),
+        value: "v2".as_bytes().to_vec(),
+        context: resp.context,
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let resp = a.get(GetRequest { key }).await.unwrap().into_inner();
+    assert_eq!(resp.siblings.len(), 1);
+    assert_eq!(resp.value, "v2".as_bytes().to_vec());
+}
 
-    assert_eq!(resp.into_inner().value, "v0".as_bytes().to_vec());
+#[tokio::test]
+async fn test_rkv_conditional_put() {
+    let mut client = RkvServiceClient::connect("http://127.0.0.1:8079")
+        .await
+        .unwrap();
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let key = format!("cas{}", nanos).into_bytes();
+    let put = |value: &str, expected_version| PutRequest {
+        key: key.clone(),
+        value: value.as_bytes().to_vec(),
+        condition: Some(put_request::Condition::ExpectedVersion(expected_version)),
+        ..Default::default()
+    };
+
+    // -1 expects the key to be absent
+    let version = client
+        .put(put("v0", -1))
+        .await
+        .unwrap()
+        .into_inner()
+        .version;
+    let status = client.put(put("v1", -1)).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Aborted);
+
+    let status = client.put(put("v1", version - 1)).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Aborted);
+    let next = client
+        .put(put("v1", version))
+        .await
+        .unwrap()
+        .into_inner()
+        .version;
+    assert!(next > version);
+
+    let resp = client
+        .get(GetRequest { key: key.clone() })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(resp.siblings.len(), 1);
+    assert_eq!(resp.value, "v1".as_bytes().to_vec());
+    assert_eq!(resp.version, next);
+}
+
+#[tokio::test]
+async fn test_rkv_scan() {
+    let mut client = RkvServiceClient::connect("http://127.0.0.1:8080")
+        .await
+        .unwrap();
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let prefix = format!("scan{}/", nanos);
+    let mut keys = Vec::new();
+    for i in 0..25 {
+        let key = format!("{}{:02}", prefix, i).into_bytes();
+        client
+            .put(PutRequest {
+                key: key.clone(),
+                value: key.clone(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        keys.push(key);
+    }
+    client
+        .delete(DeleteRequest {
+            key: keys.remove(3),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+    // Pages through the prefix in key order, skipping deleted keys
+    let mut scanned = Vec::new();
+    let mut continuation = Vec::new();
+    loop {
+        let resp = client
+            .scan(ScanRequest {
+                prefix: prefix.clone().into_bytes(),
+                limit: 10,
+                continuation,
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(resp.entries.len() <= 10);
+        for entry in resp.entries {
+            assert_eq!(entry.value, entry.key);
+            scanned.push(entry.key);
+        }
+        if resp.continuation.is_empty() {
+            break;
+        }
+        continuation = resp.continuation;
+    }
+    assert_eq!(scanned, keys);
+
+    let resp = client
+        .scan(ScanRequest {
+            start: keys[5].clone(),
+            end: keys[8].clone(),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    let scanned: Vec<_> = resp.entries.into_iter().map(|e| e.key).collect();
+    assert_eq!(scanned, keys[5..8].to_vec());
+    assert!(resp.continuation.is_empty());
+}
+
+#[tokio::test]
+async fn test_rkv_ttl() {
+    let mut client = RkvServiceClient::connect("http://127.0.0.1:8080")
+        .await
+        .unwrap();
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let key = format!("ttl{}", nanos).into_bytes();
+
+    client
+        .put(PutRequest {
+            key: key.clone(),
+            value: "v0".as_bytes().to_vec(),
+            ttl_secs: 1,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    let resp = client
+        .get(GetRequest { key: key.clone() })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(resp.value, "v0".as_bytes().to_vec());
+
+    tokio::time::delay_for(std::time::Duration::from_millis(1500)).await;
+    let resp = client
+        .get(GetRequest { key: key.clone() })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(resp.version, -1);
+    assert!(resp.value.is_empty());
+}
+
+#[tokio::test]
+async fn test_rkv_batch() {
+    let mut client = RkvServiceClient::connect("http://127.0.0.1:8078")
+        .await
+        .unwrap();
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let keys: Vec<_> = (0..10)
+        .map(|i| format!("batch{}/{}", nanos, i).into_bytes())
+        .collect();
+
+    // Each put succeeds or fails on its own
+    let mut puts: Vec<_> = keys
+        .iter()
+        .map(|key| PutRequest {
+            key: key.clone(),
+            value: key.clone(),
+            ..Default::default()
+        })
+        .collect();
+    puts.push(PutRequest::default());
+    let results = client
+        .batch_put(BatchPutRequest { puts })
+        .await
+        .unwrap()
+        .into_inner()
+        .results;
+    assert_eq!(results.len(), keys.len() + 1);
+    for result in &results[..keys.len()] {
+        match &result.result {
+            Some(batch_put_result::Result::Response(resp)) => assert!(resp.version > 0),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+    match &results[keys.len()].result {
+        Some(batch_put_result::Result::Error(e)) => {
+            assert_eq!(e.code, tonic::Code::Internal as i32)
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    // Results come back in request order
+    let mut get_keys = keys.clone();
+    get_keys.push(format!("batch{}/missing", nanos).into_bytes());
+    let results = client
+        .batch_get(BatchGetRequest { keys: get_keys })
+        .await
+        .unwrap()
+        .into_inner()
+        .results;
+    assert_eq!(results.len(), keys.len() + 1);
+    for (key, result) in keys.iter().zip(&results) {
+        match &result.result {
+            Some(batch_get_result::Result::Response(resp)) => assert_eq!(&resp.value, key),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+    match &results[keys.len()].result {
+        Some(batch_get_result::Result::Response(resp)) => assert_eq!(resp.version, -1),
+        other => panic!("unexpected result {:?}", other),
+    }
 }